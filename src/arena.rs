@@ -1,8 +1,11 @@
+use serde::{Deserialize, Serialize};
+
 use crate::shapes::Shape;
 
 // Arena is a pattern to simplify self-referencing structs (see the Group shape)
 // https://dev.to/deciduously/no-more-tears-no-more-knots-arena-allocated-trees-in-rust-44k6
 
+#[derive(Serialize, Deserialize)]
 pub struct Arena {
     pub objects: Vec<Option<Shape>>,
 }
@@ -38,6 +41,14 @@ impl Arena {
         &self.objects[id].as_ref().unwrap()
     }
 
+    // Intersections only carry a reference to the shape, so the id is found by identity
+    pub fn id_of(&self, object: &Shape) -> Option<usize> {
+        self.objects.iter().position(|o| match o {
+            Some(o) => std::ptr::eq(o, object),
+            None => false,
+        })
+    }
+
     pub fn apply_changes(&mut self, id: usize, c: impl Fn(&mut Shape)) {
         match self.objects[id].as_mut() {
             Some(object) => {
@@ -62,4 +73,14 @@ mod tests {
         });
         assert_eq!(0.42, arena.get(id).material().ambient);
     }
+
+    #[test]
+    fn id_of_object() {
+        let mut arena = Arena::new();
+        let id1 = arena.add(sphere!());
+        let id2 = arena.add(sphere!());
+        assert_eq!(Some(id1), arena.id_of(arena.get(id1)));
+        assert_eq!(Some(id2), arena.id_of(arena.get(id2)));
+        assert_eq!(None, arena.id_of(&sphere!()));
+    }
 }
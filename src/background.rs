@@ -0,0 +1,338 @@
+use std::{f64::consts::PI, fs, io};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    color::{Color, BLACK},
+    sampling,
+    sky::Sky,
+    tuple::Tuple,
+};
+
+// directions averaged for the ambient light of the background
+const AMBIENT_SAMPLES: usize = 16;
+
+// What rays that miss every object see
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Background {
+    Solid(Color),
+    // blended on the y component of the direction, bottom straight down and top straight up
+    Gradient { bottom: Color, top: Color },
+    Environment(EnvironmentMap),
+    // also lights the scene with its sun
+    Sky(Sky),
+}
+
+impl Default for Background {
+    fn default() -> Self {
+        Background::Solid(BLACK)
+    }
+}
+
+impl Background {
+    pub fn color_in(&self, direction: Tuple) -> Color {
+        match self {
+            Background::Solid(color) => *color,
+            Background::Gradient { bottom, top } => {
+                let t = (direction.normalize().y + 1.) / 2.;
+                *bottom + (*top - *bottom) * t
+            }
+            Background::Environment(map) => map.color_in(direction),
+            Background::Sky(sky) => sky.color_in(direction),
+        }
+    }
+
+    // Cosine weighted average over the hemisphere around the normal, using a
+    // fixed spiral of directions so that the result is noise free
+    pub fn ambient_light(&self, normalv: Tuple) -> Color {
+        if let Background::Solid(color) = self {
+            return *color;
+        }
+        let (tangent, bitangent) = sampling::orthonormal_basis(normalv);
+        let golden_angle = PI * (3. - 5f64.sqrt());
+        (0..AMBIENT_SAMPLES).fold(BLACK, |acc, i| {
+            let u = (i as f64 + 0.5) / AMBIENT_SAMPLES as f64;
+            let (r, phi) = (u.sqrt(), golden_angle * i as f64);
+            let direction =
+                tangent * (r * phi.cos()) + bitangent * (r * phi.sin()) + normalv * (1. - u).sqrt();
+            acc + self.color_in(direction)
+        }) / AMBIENT_SAMPLES as f64
+    }
+}
+
+// Latitude/longitude image around the scene: the center of the image is +z,
+// the top row is straight up
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EnvironmentMap {
+    pub width: usize,
+    pub height: usize,
+    pixels: Vec<Color>,
+}
+
+impl EnvironmentMap {
+    pub fn new(width: usize, height: usize, pixels: Vec<Color>) -> Self {
+        assert_eq!(width * height, pixels.len(), "pixels do not match the size");
+        Self {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    pub fn pixel_at(&self, x: usize, y: usize) -> Color {
+        self.pixels[y * self.width + x]
+    }
+
+    pub fn color_in(&self, direction: Tuple) -> Color {
+        let (x, y) = self.pixel_in(direction);
+        self.pixel_at(x, y)
+    }
+
+    pub fn pixel_in(&self, direction: Tuple) -> (usize, usize) {
+        let d = direction.normalize();
+        let u = 0.5 + d.x.atan2(d.z) / (2. * PI);
+        let v = d.y.clamp(-1., 1.).acos() / PI;
+        let x = ((u * self.width as f64) as usize).min(self.width - 1);
+        let y = ((v * self.height as f64) as usize).min(self.height - 1);
+        (x, y)
+    }
+
+    // Radiance .hdr (RGBE) file, flat or run-length encoded
+    pub fn load_hdr(path: &str) -> io::Result<Self> {
+        let bytes = fs::read(path)?;
+        Self::parse_hdr(&bytes)
+    }
+
+    pub fn parse_hdr(bytes: &[u8]) -> io::Result<Self> {
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message);
+
+        let mut lines: Vec<String> = Vec::new();
+        let mut pos = 0;
+        // header lines, then an empty line and the resolution line
+        loop {
+            let end = bytes[pos..]
+                .iter()
+                .position(|b| *b == b'\n')
+                .ok_or_else(|| invalid("truncated header"))?;
+            let line = String::from_utf8_lossy(&bytes[pos..pos + end])
+                .trim()
+                .to_string();
+            pos += end + 1;
+            let done = matches!(lines.last(), Some(l) if l.is_empty());
+            lines.push(line);
+            if done {
+                break;
+            }
+        }
+        if !lines[0].starts_with("#?") {
+            return Err(invalid("not a Radiance HDR file"));
+        }
+        if lines
+            .iter()
+            .any(|l| l.starts_with("FORMAT=") && l != "FORMAT=32-bit_rle_rgbe")
+        {
+            return Err(invalid("unsupported pixel format"));
+        }
+        let resolution = lines.last().unwrap().split_whitespace().collect::<Vec<_>>();
+        let (height, width) = match resolution[..] {
+            ["-Y", h, "+X", w] => (
+                h.parse::<usize>().map_err(|_| invalid("invalid height"))?,
+                w.parse::<usize>().map_err(|_| invalid("invalid width"))?,
+            ),
+            _ => return Err(invalid("unsupported resolution line")),
+        };
+        if width == 0 || height == 0 {
+            return Err(invalid("empty image"));
+        }
+        // fewest bytes a scanline can take: run-length encoded scanlines have 4 bytes
+        // of header and at least one run of up to 128 pixels (2 bytes) per channel,
+        // the others 4 bytes per pixel
+        let scanline_bytes = if (8..0x8000).contains(&width) {
+            4 + 8 * width.div_ceil(128)
+        } else {
+            width
+                .checked_mul(4)
+                .ok_or_else(|| invalid("image too large"))?
+        };
+        let data_bytes = match (
+            width.checked_mul(height),
+            scanline_bytes.checked_mul(height),
+        ) {
+            (Some(_), Some(data_bytes)) => data_bytes,
+            _ => return Err(invalid("image too large")),
+        };
+        if data_bytes > bytes.len() - pos {
+            return Err(invalid("truncated pixel data"));
+        }
+
+        let mut pixels = Vec::with_capacity(width * height);
+        let mut data = bytes[pos..].iter().copied();
+        let mut next = || data.next().ok_or_else(|| invalid("truncated pixel data"));
+        let mut scanline = vec![[0u8; 4]; width];
+        for _ in 0..height {
+            let first = [next()?, next()?, next()?, next()?];
+            let encoded_width = ((first[2] as usize) << 8) | first[3] as usize;
+            if first[0] == 2 && first[1] == 2 && (8..0x8000).contains(&width) {
+                if encoded_width != width {
+                    return Err(invalid("scanline width mismatch"));
+                }
+                // each channel is run-length encoded on its own
+                for channel in 0..4 {
+                    let mut x = 0;
+                    while x < width {
+                        let count = next()? as usize;
+                        let (run, count) = if count > 128 {
+                            (true, count - 128)
+                        } else {
+                            (false, count)
+                        };
+                        if count == 0 || x + count > width {
+                            return Err(invalid("bad run length"));
+                        }
+                        if run {
+                            let value = next()?;
+                            for pixel in &mut scanline[x..x + count] {
+                                pixel[channel] = value;
+                            }
+                        } else {
+                            for pixel in &mut scanline[x..x + count] {
+                                pixel[channel] = next()?;
+                            }
+                        }
+                        x += count;
+                    }
+                }
+            } else {
+                scanline[0] = first;
+                for pixel in scanline.iter_mut().skip(1) {
+                    *pixel = [next()?, next()?, next()?, next()?];
+                }
+            }
+            pixels.extend(scanline.iter().map(|p| rgbe_to_color(*p)));
+        }
+        Ok(Self::new(width, height, pixels))
+    }
+}
+
+fn rgbe_to_color([r, g, b, e]: [u8; 4]) -> Color {
+    if e == 0 {
+        return BLACK;
+    }
+    let f = 2f64.powi(e as i32 - 136);
+    Color::new(r as f64 * f, g as f64 * f, b as f64 * f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        color,
+        color::{BLUE, GREEN, RED, WHITE},
+        vector,
+    };
+
+    #[test]
+    fn solid_and_gradient() {
+        let solid = Background::Solid(RED);
+        assert_eq!(RED, solid.color_in(vector!(0, 1, 0)));
+        assert_eq!(RED, solid.ambient_light(vector!(0, 1, 0)));
+
+        let gradient = Background::Gradient {
+            bottom: BLACK,
+            top: WHITE,
+        };
+        assert_eq!(WHITE, gradient.color_in(vector!(0, 1, 0)));
+        assert_eq!(BLACK, gradient.color_in(vector!(0, -2, 0)));
+        assert_eq!(color!(0.5, 0.5, 0.5), gradient.color_in(vector!(1, 0, 0)));
+    }
+
+    #[test]
+    fn ambient_light_averages_the_hemisphere() {
+        let gradient = Background::Gradient {
+            bottom: BLACK,
+            top: WHITE,
+        };
+        // the cosine weighted mean of y over the upper hemisphere is 2/3
+        let up = gradient.ambient_light(vector!(0, 1, 0));
+        assert!((up.r - 5. / 6.).abs() < 0.01);
+        let sideways = gradient.ambient_light(vector!(1, 0, 0));
+        assert!((sideways.r - 0.5).abs() < 0.02);
+    }
+
+    #[test]
+    fn environment_map_lookup() {
+        let map = EnvironmentMap::new(4, 2, vec![RED, GREEN, BLUE, WHITE, BLACK, RED, GREEN, BLUE]);
+        assert_eq!((2, 0), map.pixel_in(vector!(0, 0.5, 1)));
+        assert_eq!(BLUE, map.color_in(vector!(0, 0.5, 1)));
+        assert_eq!((0, 1), map.pixel_in(vector!(-0.1, -0.5, -1)));
+        assert_eq!((3, 0), map.pixel_in(vector!(1, 1, 0)));
+        assert_eq!((1, 1), map.pixel_in(vector!(-1, -1, 0)));
+    }
+
+    fn hdr_header(width: usize, height: usize) -> Vec<u8> {
+        format!(
+            "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n",
+            height, width
+        )
+        .into_bytes()
+    }
+
+    #[test]
+    fn parse_flat_hdr() {
+        let mut bytes = hdr_header(2, 1);
+        bytes.extend(&[128, 64, 0, 129, 0, 0, 0, 0]);
+        let map = EnvironmentMap::parse_hdr(&bytes).unwrap();
+        assert_eq!((2, 1), (map.width, map.height));
+        assert_eq!(color!(1, 0.5, 0), map.pixel_at(0, 0));
+        assert_eq!(BLACK, map.pixel_at(1, 0));
+    }
+
+    #[test]
+    fn parse_run_length_encoded_hdr() {
+        let mut bytes = hdr_header(8, 1);
+        bytes.extend(&[2, 2, 0, 8]);
+        // red: a run of 8, green: 8 literals, blue: two runs, exponent: a run
+        bytes.extend(&[128 + 8, 128]);
+        bytes.extend(&[8, 0, 16, 32, 48, 64, 80, 96, 112]);
+        bytes.extend(&[128 + 4, 0, 128 + 4, 128]);
+        bytes.extend(&[128 + 8, 129]);
+        let map = EnvironmentMap::parse_hdr(&bytes).unwrap();
+        assert_eq!(color!(1, 0, 0), map.pixel_at(0, 0));
+        assert_eq!(color!(1, 0.75, 1), map.pixel_at(6, 0));
+    }
+
+    #[test]
+    fn parse_hdr_errors() {
+        let error = |bytes: &[u8]| EnvironmentMap::parse_hdr(bytes).unwrap_err().to_string();
+        assert_eq!("not a Radiance HDR file", error(b"P6\n\n-Y 1 +X 1\n"));
+        assert_eq!("truncated header", error(b"#?RADIANCE\n"));
+        assert_eq!("truncated pixel data", error(&hdr_header(2, 2)));
+        let mut bytes = hdr_header(2, 2);
+        bytes.extend(&[0, 0, 0, 0]);
+        assert_eq!("truncated pixel data", error(&bytes));
+        // sizes the data can't hold are rejected before anything is allocated
+        assert_eq!(
+            "truncated pixel data",
+            error(b"#?RADIANCE\n\n-Y 100000000 +X 100000000\n\x02\x02")
+        );
+        assert_eq!(
+            "image too large",
+            error(format!("#?RADIANCE\n\n-Y {} +X {}\n", usize::MAX, usize::MAX).as_bytes())
+        );
+        assert_eq!(
+            "unsupported resolution line",
+            error(b"#?RADIANCE\n\n+Y 1 +X 1\n")
+        );
+    }
+
+    #[test]
+    fn load_hdr_file() {
+        let path = std::env::temp_dir().join("rust_tracer_background_test.hdr");
+        let mut bytes = hdr_header(1, 1);
+        bytes.extend(&[128, 128, 128, 129]);
+        fs::write(&path, bytes).unwrap();
+        let map = EnvironmentMap::load_hdr(path.to_str().unwrap()).unwrap();
+        assert_eq!(WHITE, map.pixel_at(0, 0));
+        assert!(EnvironmentMap::load_hdr("/nonexistent/sky.hdr").is_err());
+    }
+}
@@ -0,0 +1,12 @@
+use std::{io, net::TcpListener};
+
+use rust_tracer::distributed;
+
+// tile_worker            serves a single coordinator over stdin/stdout
+// tile_worker <address>  accepts coordinators on a TCP address, e.g. 127.0.0.1:7878
+fn main() -> io::Result<()> {
+    match std::env::args().nth(1) {
+        Some(address) => distributed::serve_tcp(TcpListener::bind(address)?),
+        None => distributed::serve(io::stdin(), io::stdout()),
+    }
+}
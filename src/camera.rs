@@ -3,16 +3,105 @@ use std::time::Instant;
 use indicatif::HumanDuration;
 use indicatif::ProgressBar;
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 
 use crate::{
     canvas::Canvas,
+    color,
     color::Color,
     matrix::{Matrix, IDENTITY_MATRIX},
     point, ray,
     ray::Ray,
-    world::World,
+    stats,
+    stats::{CostMap, RenderStats},
+    tuple::Tuple,
+    vector,
+    world::{RayHit, World},
+    EPSILON,
 };
 
+// Sensor (or film gate) dimensions in millimeters
+#[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
+pub struct Sensor {
+    pub width: f64,
+    pub height: f64,
+}
+
+impl Sensor {
+    pub const FULL_FRAME: Sensor = Sensor {
+        width: 36.,
+        height: 24.,
+    };
+    pub const APS_C: Sensor = Sensor {
+        width: 23.6,
+        height: 15.6,
+    };
+    pub const SUPER_35: Sensor = Sensor {
+        width: 24.89,
+        height: 18.66,
+    };
+    pub const MICRO_FOUR_THIRDS: Sensor = Sensor {
+        width: 17.3,
+        height: 13.,
+    };
+
+    pub fn new(width: impl Into<f64>, height: impl Into<f64>) -> Self {
+        Self {
+            width: width.into(),
+            height: height.into(),
+        }
+    }
+}
+
+// How the sensor is mapped to the image when their aspect ratios differ
+#[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
+pub enum AspectFit {
+    // sensor width matches the image width
+    Horizontal,
+    // sensor height matches the image height
+    Vertical,
+    // the sensor is cropped so the image is completely covered
+    Fill,
+    // the whole sensor is visible, the image extends beyond it
+    Overscan,
+}
+
+#[derive(Debug, PartialEq, Copy, Clone, Default, Serialize, Deserialize)]
+pub struct Lens {
+    // 0 disables it, 1 is the natural cos^4 falloff
+    pub vignetting: f64,
+    // Brown-Conrady radial distortion coefficients (positive = pincushion, negative = barrel)
+    pub k1: f64,
+    pub k2: f64,
+}
+
+#[derive(Debug, Copy, Clone, Builder)]
+#[builder(default)]
+pub struct PhysicalCamera {
+    pub hsize: usize,
+    pub vsize: usize,
+    // millimeters
+    #[builder(setter(into))]
+    pub focal_length: f64,
+    pub sensor: Sensor,
+    pub fit: AspectFit,
+    pub lens: Lens,
+}
+
+impl Default for PhysicalCamera {
+    fn default() -> Self {
+        Self {
+            hsize: 640,
+            vsize: 480,
+            focal_length: 50.,
+            sensor: Sensor::FULL_FRAME,
+            fit: AspectFit::Fill,
+            lens: Lens::default(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct Camera {
     pub hsize: usize,
     pub vsize: usize,
@@ -20,6 +109,7 @@ pub struct Camera {
     half_width: f64,
     half_height: f64,
     pub pixel_size: f64,
+    pub lens: Lens,
     transform: Matrix,
 }
 
@@ -32,6 +122,40 @@ impl Camera {
         } else {
             (half_view * aspect, half_view)
         };
+        Self {
+            field_of_view,
+            ..Self::with_half_extents(hsize, vsize, half_width, half_height)
+        }
+    }
+
+    pub fn with_focal_length(
+        hsize: usize,
+        vsize: usize,
+        focal_length: f64,
+        sensor: Sensor,
+        fit: AspectFit,
+    ) -> Self {
+        let aspect = hsize as f64 / vsize as f64;
+        let sensor_aspect = sensor.width / sensor.height;
+        let fit_horizontally = match fit {
+            AspectFit::Horizontal => true,
+            AspectFit::Vertical => false,
+            AspectFit::Fill => aspect >= sensor_aspect,
+            AspectFit::Overscan => aspect < sensor_aspect,
+        };
+        // the canvas is at z = -1, so the extents are the sensor's divided by the focal length
+        let (half_width, half_height) = if fit_horizontally {
+            let half_width = sensor.width / 2. / focal_length;
+            (half_width, half_width / aspect)
+        } else {
+            let half_height = sensor.height / 2. / focal_length;
+            (half_height * aspect, half_height)
+        };
+        Self::with_half_extents(hsize, vsize, half_width, half_height)
+    }
+
+    fn with_half_extents(hsize: usize, vsize: usize, half_width: f64, half_height: f64) -> Self {
+        let field_of_view = 2. * half_width.max(half_height).atan();
         let pixel_size = (half_width * 2.) / hsize as f64;
         Self {
             hsize,
@@ -40,6 +164,7 @@ impl Camera {
             half_width,
             half_height,
             pixel_size,
+            lens: Lens::default(),
             transform: IDENTITY_MATRIX,
         }
     }
@@ -48,6 +173,26 @@ impl Camera {
         self.transform = transform;
     }
 
+    pub fn transform(&self) -> &Matrix {
+        &self.transform
+    }
+
+    pub fn look_at(&mut self, from: Tuple, to: Tuple, up: Tuple) {
+        self.transform = Matrix::view_transform(from, to, up);
+    }
+
+    pub fn position(&self) -> Tuple {
+        self.transform.inverse().unwrap() * point!(0, 0, 0)
+    }
+
+    pub fn direction(&self) -> Tuple {
+        (self.transform.inverse().unwrap() * vector!(0, 0, -1)).normalize()
+    }
+
+    pub fn up(&self) -> Tuple {
+        (self.transform.inverse().unwrap() * vector!(0, 1, 0)).normalize()
+    }
+
     pub fn ray_for_pixel(&self, px: usize, py: usize) -> Ray {
         self.ray_for_pixel_with_offset(px, py, 0.5, 0.5)
     }
@@ -59,8 +204,20 @@ impl Camera {
 
         // untransformed coordinates of the pixel in world space
         // camera looks toward -z, so +x is to the left
-        let world_x = self.half_width - xoffset;
-        let world_y = self.half_height - yoffset;
+        let mut world_x = self.half_width - xoffset;
+        let mut world_y = self.half_height - yoffset;
+
+        // lens distortion, using the coordinates on the canvas (z = -1) as normalized coordinates.
+        // The model maps scene points to the image, so rays go the inverse way: a pixel
+        // pushed outward by the lens sees what is closer to the center
+        if self.lens.k1 != 0. || self.lens.k2 != 0. {
+            let r2 = world_x * world_x + world_y * world_y;
+            let factor = 1. + self.lens.k1 * r2 + self.lens.k2 * r2 * r2;
+            if factor > 0. {
+                world_x /= factor;
+                world_y /= factor;
+            }
+        }
 
         // using the camera matrix, transform the canvas point and the origin,
         // and then compute the ray's direction
@@ -72,7 +229,21 @@ impl Camera {
         ray!(origin, direction)
     }
 
+    // What object is under pixel (px, py)?
+    pub fn pick<'a>(&self, world: &'a World, px: usize, py: usize) -> Option<RayHit<'a>> {
+        world.closest_hit(&self.ray_for_pixel(px, py))
+    }
+
     pub fn render(&self, world: &World, antialiasing: bool) -> Canvas {
+        self.render_with_stats(world, antialiasing).0
+    }
+
+    // Also returns the aggregated ray/test counters and the per-pixel cost
+    pub fn render_with_stats(
+        &self,
+        world: &World,
+        antialiasing: bool,
+    ) -> (Canvas, RenderStats, CostMap) {
         let start = Instant::now();
         let progress_bar = if self.vsize > 50 {
             println!("Rendering...");
@@ -86,7 +257,11 @@ impl Camera {
             .flat_map(|y| {
                 let row = (0..self.hsize)
                     .into_iter()
-                    .map(|x| (x, y, self.color_at(world, x, y, antialiasing)))
+                    .map(|x| {
+                        stats::take();
+                        let color = self.color_at(world, x, y, antialiasing);
+                        (x, y, color, stats::take())
+                    })
                     .collect::<Vec<_>>();
                 if let Some(pb) = &progress_bar {
                     pb.inc(1);
@@ -96,20 +271,76 @@ impl Camera {
             .collect::<Vec<_>>();
 
         let mut image = Canvas::new(self.hsize, self.vsize);
-        pixels
-            .iter()
-            .for_each(|(x, y, color)| image.write_pixel(*x, *y, *color));
+        let mut costs = CostMap::new(self.hsize, self.vsize);
+        let mut total = RenderStats::default();
+        for (x, y, color, pixel_stats) in pixels {
+            image.write_pixel(x, y, color);
+            costs.write_cost(x, y, pixel_stats.cost());
+            total = total + pixel_stats;
+        }
 
         if let Some(pb) = &progress_bar {
             pb.finish();
             println!("Completed in {}", HumanDuration(start.elapsed()));
+            println!("{}", total);
+        }
+        (image, total, costs)
+    }
+
+    // Grayscale ambient occlusion pass: white where the surface is open, and for
+    // rays that miss. Uses the world's settings, or the defaults when it has none
+    pub fn render_ambient_occlusion(&self, world: &World) -> Canvas {
+        let occlusion = world.ambient_occlusion.unwrap_or_default();
+        let pixels = (0..self.vsize)
+            .into_par_iter()
+            .flat_map(|y| {
+                (0..self.hsize)
+                    .map(|x| {
+                        let visibility = match self.pick(world, x, y) {
+                            Some(hit) => world.ambient_visibility(
+                                hit.point + hit.geometric_normalv * EPSILON,
+                                hit.geometric_normalv,
+                                occlusion,
+                            ),
+                            None => 1.,
+                        };
+                        (x, y, color!(visibility, visibility, visibility))
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        let mut image = Canvas::new(self.hsize, self.vsize);
+        for (x, y, color) in pixels {
+            image.write_pixel(x, y, color);
         }
         image
     }
 
+    // Pixels of a rectangular region of the image, row by row
+    pub fn render_region(
+        &self,
+        world: &World,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        antialiasing: bool,
+    ) -> Vec<Color> {
+        (y..y + height)
+            .into_par_iter()
+            .flat_map(|py| {
+                (x..x + width)
+                    .map(|px| self.color_at(world, px, py, antialiasing))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
     fn color_at(&self, world: &World, x: usize, y: usize, antialiasing: bool) -> Color {
+        stats::record(|s| s.primary_rays += if antialiasing { 5 } else { 1 });
         let color_center = world.color_at(&self.ray_for_pixel(x, y));
-        if antialiasing {
+        let color = if antialiasing {
             let mut color_sum = color_center;
             for &(ox, oy) in &[(0.20, 0.20), (0.80, 0.20), (0.20, 0.80), (0.80, 0.80)] {
                 color_sum =
@@ -118,7 +349,28 @@ impl Camera {
             color_sum / 5.
         } else {
             color_center
+        };
+        color * self.vignetting_at(x, y)
+    }
+
+    fn vignetting_at(&self, px: usize, py: usize) -> f64 {
+        if self.lens.vignetting == 0. {
+            return 1.;
         }
+        let x = self.half_width - (px as f64 + 0.5) * self.pixel_size;
+        let y = self.half_height - (py as f64 + 0.5) * self.pixel_size;
+        // cos of the angle between the pixel's ray and the optical axis
+        let cos2 = 1. / (1. + x * x + y * y);
+        1. - self.lens.vignetting * (1. - cos2 * cos2)
+    }
+}
+
+impl From<PhysicalCamera> for Camera {
+    fn from(p: PhysicalCamera) -> Self {
+        let mut camera =
+            Camera::with_focal_length(p.hsize, p.vsize, p.focal_length, p.sensor, p.fit);
+        camera.lens = p.lens;
+        camera
     }
 }
 
@@ -128,7 +380,15 @@ mod tests {
 
     use std::f64::consts::PI;
 
-    use crate::{approx_eq, color, vector};
+    use crate::{
+        approx_eq,
+        color::{BLACK, WHITE},
+        gradient_pattern,
+        light::PointLight,
+        material::Material,
+        occlusion::AmbientOcclusion,
+        plane, vector,
+    };
 
     #[test]
     fn ctor() {
@@ -183,4 +443,176 @@ mod tests {
         let image = c.render(&w, false);
         assert_eq!(color!(0.38066, 0.47583, 0.2855), image.pixel_at(5, 5));
     }
+
+    #[test]
+    fn render_ambient_occlusion_pass() {
+        let mut w = World::new(PointLight::new(point!(0, 10, 0), WHITE), vec![plane!()]);
+        let mut ceiling = plane!();
+        ceiling.set_transform(Matrix::translation(0, 2, 0));
+        w.add_object(ceiling);
+        let mut c = Camera::new(3, 3, PI / 2.);
+        c.set_transform(Matrix::view_transform(
+            point!(0, 1, 0),
+            point!(0, 0, 0),
+            vector!(0, 0, 1),
+        ));
+        // the ceiling is out of reach with the default radius
+        let image = c.render_ambient_occlusion(&w);
+        assert_eq!(WHITE, image.pixel_at(1, 1));
+        w.ambient_occlusion = Some(AmbientOcclusion {
+            radius: 1000.,
+            samples: 8,
+        });
+        let image = c.render_ambient_occlusion(&w);
+        assert_eq!(BLACK, image.pixel_at(1, 1));
+    }
+
+    #[test]
+    fn ambient_occlusion_pass_ignores_bumps() {
+        let mut ramp = gradient_pattern!(BLACK, WHITE);
+        ramp.set_transform(Matrix::scaling(0.1, 1, 1));
+        let mut floor = plane!();
+        floor.set_material(Material {
+            bump: Some(ramp),
+            ..Material::default()
+        });
+        let mut w = World::new(PointLight::new(point!(0, 10, 0), WHITE), vec![floor]);
+        w.ambient_occlusion = Some(AmbientOcclusion {
+            radius: 1000.,
+            samples: 64,
+        });
+        let mut c = Camera::new(3, 3, PI / 2.);
+        c.set_transform(Matrix::view_transform(
+            point!(0.05, 1, 0),
+            point!(0.05, 0, 0),
+            vector!(0, 0, 1),
+        ));
+        let image = c.render_ambient_occlusion(&w);
+        assert_eq!(WHITE, image.pixel_at(1, 1));
+    }
+
+    #[test]
+    fn render_with_stats_counts_rays_and_costs() {
+        let w = World::default();
+        let mut c = Camera::new(11, 11, PI / 2.);
+        c.set_transform(Matrix::view_transform(
+            point!(0, 0, -5),
+            point!(0, 0, 0),
+            vector!(0, 1, 0),
+        ));
+        let (image, stats, costs) = c.render_with_stats(&w, false);
+        assert_eq!(color!(0.38066, 0.47583, 0.2855), image.pixel_at(5, 5));
+        assert_eq!(121, stats.primary_rays);
+        assert!(stats.shadow_rays > 0);
+        assert_eq!(0, stats.reflection_rays);
+        assert_eq!(0, stats.max_depth);
+        // two spheres tested by the primary ray and again by the shadow ray
+        assert_eq!(4, costs.cost_at(5, 5));
+        // a ray that misses everything still tests both spheres
+        assert_eq!(2, costs.cost_at(0, 0));
+    }
+
+    #[test]
+    fn render_region_matches_render() {
+        let w = World::default();
+        let mut c = Camera::new(11, 11, PI / 2.);
+        c.look_at(point!(0, 0, -5), point!(0, 0, 0), vector!(0, 1, 0));
+        let image = c.render(&w, false);
+        let region = c.render_region(&w, 4, 3, 3, 2, false);
+        assert_eq!(6, region.len());
+        assert_eq!(image.pixel_at(4, 3), region[0]);
+        assert_eq!(image.pixel_at(6, 3), region[2]);
+        assert_eq!(image.pixel_at(5, 4), region[4]);
+    }
+
+    #[test]
+    fn focal_length_and_sensor_width() {
+        let c = Camera::with_focal_length(360, 240, 50., Sensor::FULL_FRAME, AspectFit::Fill);
+        assert!(approx_eq(2. * (0.36f64).atan(), c.field_of_view));
+        assert!(approx_eq(0.72 / 360., c.pixel_size));
+    }
+
+    #[test]
+    fn aspect_fit_modes() {
+        // a square image on a 3:2 sensor
+        let sensor = Sensor::new(36, 24);
+        let horizontal = Camera::with_focal_length(100, 100, 12., sensor, AspectFit::Horizontal);
+        let vertical = Camera::with_focal_length(100, 100, 12., sensor, AspectFit::Vertical);
+        let fill = Camera::with_focal_length(100, 100, 12., sensor, AspectFit::Fill);
+        let overscan = Camera::with_focal_length(100, 100, 12., sensor, AspectFit::Overscan);
+        assert!(approx_eq(1.5, horizontal.half_width));
+        assert!(approx_eq(1.5, horizontal.half_height));
+        assert!(approx_eq(1., vertical.half_width));
+        assert!(approx_eq(1., vertical.half_height));
+        assert!(approx_eq(vertical.half_width, fill.half_width));
+        assert!(approx_eq(horizontal.half_width, overscan.half_width));
+    }
+
+    #[test]
+    fn physical_camera_builder() {
+        let p = PhysicalCameraBuilder::default()
+            .hsize(200)
+            .vsize(100)
+            .focal_length(35)
+            .sensor(Sensor::APS_C)
+            .build()
+            .unwrap();
+        assert_eq!(AspectFit::Fill, p.fit);
+        let c = Camera::from(p);
+        assert_eq!(200, c.hsize);
+        assert_eq!(100, c.vsize);
+        assert!(approx_eq(2. * (23.6 / 2. / 35f64).atan(), c.field_of_view));
+    }
+
+    #[test]
+    fn look_at_and_accessors() {
+        let mut c = Camera::new(11, 11, PI / 2.);
+        c.look_at(point!(1, 2, -5), point!(1, 2, 0), vector!(0, 1, 0));
+        assert_eq!(
+            &Matrix::view_transform(point!(1, 2, -5), point!(1, 2, 0), vector!(0, 1, 0)),
+            c.transform()
+        );
+        assert_eq!(point!(1, 2, -5), c.position());
+        assert_eq!(vector!(0, 0, 1), c.direction());
+        assert_eq!(vector!(0, 1, 0), c.up());
+    }
+
+    #[test]
+    fn radial_distortion_moves_corner_rays() {
+        let mut c = Camera::new(201, 101, PI / 2.);
+        let undistorted = c.ray_for_pixel(0, 0).direction;
+        c.lens.k1 = 0.1;
+        let pincushion = c.ray_for_pixel(0, 0).direction;
+        c.lens.k1 = -0.1;
+        let barrel = c.ray_for_pixel(0, 0).direction;
+        // pincushion stretches the edges, so the corner pixel sees closer to the center
+        assert!(pincushion.x < undistorted.x);
+        assert!(barrel.x > undistorted.x);
+        // the center is not affected
+        assert_eq!(vector!(0, 0, -1), c.ray_for_pixel(100, 50).direction);
+    }
+
+    #[test]
+    fn vignetting_darkens_corners() {
+        let mut c = Camera::new(201, 101, PI / 2.);
+        assert_eq!(1., c.vignetting_at(0, 0));
+        c.lens.vignetting = 1.;
+        assert!(approx_eq(1., c.vignetting_at(100, 50)));
+        assert!(c.vignetting_at(0, 0) < 0.5);
+    }
+
+    #[test]
+    fn pick_object_under_pixel() {
+        let w = World::default();
+        let mut c = Camera::new(11, 11, PI / 2.);
+        c.set_transform(Matrix::view_transform(
+            point!(0, 0, -5),
+            point!(0, 0, 0),
+            vector!(0, 1, 0),
+        ));
+        let hit = c.pick(&w, 5, 5).unwrap();
+        assert_eq!(w.object_ids[0], hit.object_id);
+        assert_eq!(point!(0, 0, -1), hit.point);
+        assert!(c.pick(&w, 0, 0).is_none());
+    }
 }
@@ -1,8 +1,11 @@
 use std::fs::File;
-use std::io::{BufWriter, Result};
+use std::io::{BufWriter, Error, ErrorKind, Result};
+
+use serde::{Deserialize, Serialize};
 
 use crate::color::*;
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Canvas {
     pub width: usize,
     pub height: usize,
@@ -52,6 +55,36 @@ impl Canvas {
         png_writer.write_image_data(&self.to_u8_rgb())?;
         Ok(())
     }
+
+    // 8 or 16 bit PNG in any color type; alpha is ignored
+    pub fn load(path: &str) -> Result<Self> {
+        let mut decoder = png::Decoder::new(File::open(path)?);
+        decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+        let (info, mut reader) = decoder.read_info()?;
+        let mut bytes = vec![0u8; reader.output_buffer_size()];
+        reader.next_frame(&mut bytes)?;
+        let channels = match reader.output_color_type().0 {
+            png::ColorType::Grayscale => 1,
+            png::ColorType::GrayscaleAlpha => 2,
+            png::ColorType::RGB => 3,
+            png::ColorType::RGBA => 4,
+            png::ColorType::Indexed => {
+                return Err(Error::new(ErrorKind::InvalidData, "unexpanded palette"))
+            }
+        };
+        let (width, height) = (info.width as usize, info.height as usize);
+        let mut canvas = Self::new(width, height);
+        for (i, pixel) in bytes.chunks(channels).take(width * height).enumerate() {
+            let value = |c: usize| pixel[c] as f64 / 255.;
+            let color = if channels < 3 {
+                Color::new(value(0), value(0), value(0))
+            } else {
+                Color::new(value(0), value(1), value(2))
+            };
+            canvas.write_pixel(i % width, i / width, color);
+        }
+        Ok(canvas)
+    }
 }
 
 #[cfg(test)]
@@ -110,4 +143,18 @@ mod tests {
         // c.save("/tmp/save_test.png")?;
         Ok(())
     }
+
+    #[test]
+    fn load_saved_png() -> Result<()> {
+        let mut c = Canvas::new(3, 2);
+        c.write_pixel(0, 0, RED);
+        c.write_pixel(2, 1, WHITE);
+        let path = std::env::temp_dir().join("rust_tracer_canvas_test.png");
+        let path = path.to_str().unwrap();
+        c.save(path)?;
+        let loaded = Canvas::load(path)?;
+        assert_eq!(c, loaded);
+        assert!(Canvas::load("/nonexistent/image.png").is_err());
+        Ok(())
+    }
 }
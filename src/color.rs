@@ -1,5 +1,6 @@
 use core::ops::{Add, Div, Mul, Sub};
 use pad::PadStr;
+use serde::{Deserialize, Serialize};
 use std::str::FromStr;
 
 use crate::approx_eq;
@@ -42,7 +43,7 @@ pub const YELLOW: Color = Color {
     b: 0.,
 };
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub struct Color {
     pub r: f64,
     pub g: f64,
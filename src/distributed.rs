@@ -0,0 +1,633 @@
+use std::{
+    collections::VecDeque,
+    io::{self, BufReader, BufWriter, Read, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    process::{Child, Command, Stdio},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use bincode::Options;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{camera::Camera, canvas::Canvas, color::Color, world::World};
+
+// Tile-job protocol: the coordinator sends the scene once per connection and then
+// one tile at a time. The worker answers each tile with its pixels, row by row.
+// Messages are bincode encoded, so the same protocol works over TCP or stdin/stdout.
+
+// Largest message accepted from a peer, so that a corrupt length can't exhaust memory
+pub const MAX_MESSAGE_SIZE: u64 = 512 << 20;
+
+#[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
+pub struct Tile {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+pub fn split_tiles(width: usize, height: usize, tile_size: usize) -> Vec<Tile> {
+    assert!(tile_size > 0);
+    let mut tiles = vec![];
+    for y in (0..height).step_by(tile_size) {
+        for x in (0..width).step_by(tile_size) {
+            tiles.push(Tile {
+                x,
+                y,
+                width: tile_size.min(width - x),
+                height: tile_size.min(height - y),
+            });
+        }
+    }
+    tiles
+}
+
+#[derive(Serialize, Deserialize)]
+enum Request {
+    // bincode encoded (World, Camera, antialiasing)
+    Scene(Vec<u8>),
+    Tile(Tile),
+}
+
+#[derive(Serialize, Deserialize)]
+struct TileResult {
+    tile: Tile,
+    pixels: Vec<Color>,
+}
+
+fn encoding() -> impl Options {
+    bincode::options().with_limit(MAX_MESSAGE_SIZE)
+}
+
+fn encode<T: Serialize>(message: &T) -> io::Result<Vec<u8>> {
+    encoding().serialize(message).map_err(|e| to_io_error(*e))
+}
+
+fn send<T: Serialize>(writer: &mut impl Write, message: &T) -> io::Result<()> {
+    encoding()
+        .serialize_into(&mut *writer, message)
+        .map_err(|e| to_io_error(*e))?;
+    writer.flush()
+}
+
+fn receive<T: DeserializeOwned>(reader: &mut impl Read) -> io::Result<T> {
+    receive_limited(reader, MAX_MESSAGE_SIZE)
+}
+
+fn receive_limited<T: DeserializeOwned>(reader: &mut impl Read, limit: u64) -> io::Result<T> {
+    encoding()
+        .with_limit(limit)
+        .deserialize_from(reader)
+        .map_err(|e| to_io_error(*e))
+}
+
+fn to_io_error(e: bincode::ErrorKind) -> io::Error {
+    match e {
+        bincode::ErrorKind::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::InvalidData, e),
+    }
+}
+
+// Worker side: serves tiles until the coordinator closes the connection
+pub fn serve(reader: impl Read, writer: impl Write) -> io::Result<()> {
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    let mut scene: Option<(World, Camera, bool)> = None;
+    loop {
+        let request = match receive(&mut reader) {
+            Ok(request) => request,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        match request {
+            Request::Scene(bytes) => {
+                scene = Some(
+                    encoding()
+                        .deserialize(&bytes)
+                        .map_err(|e| to_io_error(*e))?,
+                );
+            }
+            Request::Tile(tile) => {
+                let (world, camera, antialiasing) = scene.as_ref().ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "tile requested before the scene",
+                    )
+                })?;
+                let pixels = camera.render_region(
+                    world,
+                    tile.x,
+                    tile.y,
+                    tile.width,
+                    tile.height,
+                    *antialiasing,
+                );
+                send(&mut writer, &TileResult { tile, pixels })?;
+            }
+        }
+    }
+}
+
+// Serves every coordinator that connects, each on its own thread
+pub fn serve_tcp(listener: TcpListener) -> io::Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let reader = stream.try_clone()?;
+        thread::spawn(move || {
+            if let Err(e) = serve(reader, stream) {
+                eprintln!("Worker connection failed: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+// Coordinator side of a connection to a worker
+pub struct Connection {
+    reader: BufReader<Box<dyn Read + Send>>,
+    writer: BufWriter<Box<dyn Write + Send>>,
+    child: Option<Child>,
+    // where set_timeout applies
+    stream: Option<TcpStream>,
+    pipe_timeout: Option<Arc<Mutex<Option<Duration>>>>,
+}
+
+impl Connection {
+    pub fn new(reader: impl Read + Send + 'static, writer: impl Write + Send + 'static) -> Self {
+        Self {
+            reader: BufReader::new(Box::new(reader)),
+            writer: BufWriter::new(Box::new(writer)),
+            child: None,
+            stream: None,
+            pipe_timeout: None,
+        }
+    }
+
+    pub fn tcp(address: impl ToSocketAddrs) -> io::Result<Self> {
+        let stream = TcpStream::connect(address)?;
+        let mut connection = Self::new(stream.try_clone()?, stream.try_clone()?);
+        connection.stream = Some(stream);
+        Ok(connection)
+    }
+
+    // Spawns a worker process that speaks the protocol over its stdin/stdout.
+    // Only reads can time out: pipes have no timeouts of their own, so its output is
+    // read on another thread
+    pub fn spawn(command: &mut Command) -> io::Result<Self> {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = TimeoutReader::new(child.stdout.take().unwrap());
+        let timeout = Arc::clone(&stdout.timeout);
+        let mut connection = Self::new(stdout, stdin);
+        connection.child = Some(child);
+        connection.pipe_timeout = Some(timeout);
+        Ok(connection)
+    }
+
+    // How long a read or write may block before failing with TimedOut or WouldBlock.
+    // None waits forever. Has no effect on connections made with new
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        if let Some(stream) = &self.stream {
+            stream.set_read_timeout(timeout)?;
+            stream.set_write_timeout(timeout)?;
+        }
+        if let Some(pipe_timeout) = &self.pipe_timeout {
+            *pipe_timeout.lock().unwrap() = timeout;
+        }
+        Ok(())
+    }
+
+    fn send_raw(&mut self, message: &[u8]) -> io::Result<()> {
+        self.writer.write_all(message)?;
+        self.writer.flush()
+    }
+
+    fn render_tile(&mut self, tile: Tile) -> io::Result<Vec<Color>> {
+        send(&mut self.writer, &Request::Tile(tile))?;
+        let result: TileResult = receive(&mut self.reader)?;
+        if result.tile != tile || result.pixels.len() != tile.width * tile.height {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "worker returned an unexpected tile",
+            ));
+        }
+        Ok(result.pixels)
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if let Some(child) = &mut self.child {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+// Reads on a thread of its own, so that waiting for data can time out
+struct TimeoutReader {
+    chunks: mpsc::Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    position: usize,
+    timeout: Arc<Mutex<Option<Duration>>>,
+}
+
+impl TimeoutReader {
+    fn new(mut reader: impl Read + Send + 'static) -> Self {
+        let (sender, chunks) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buffer = vec![0; 64 * 1024];
+            loop {
+                let chunk = match reader.read(&mut buffer) {
+                    Ok(0) => return,
+                    Ok(n) => Ok(buffer[..n].to_vec()),
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => Err(e),
+                };
+                let failed = chunk.is_err();
+                if sender.send(chunk).is_err() || failed {
+                    return;
+                }
+            }
+        });
+        Self {
+            chunks,
+            chunk: vec![],
+            position: 0,
+            timeout: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+impl Read for TimeoutReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.chunk.len() {
+            let timeout = *self.timeout.lock().unwrap();
+            let chunk = match timeout {
+                Some(timeout) => match self.chunks.recv_timeout(timeout) {
+                    Ok(chunk) => chunk,
+                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "worker did not answer in time",
+                        ))
+                    }
+                    // end of the output
+                    Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(0),
+                },
+                None => match self.chunks.recv() {
+                    Ok(chunk) => chunk,
+                    Err(_) => return Ok(0),
+                },
+            };
+            self.chunk = chunk?;
+            self.position = 0;
+        }
+        let n = buf.len().min(self.chunk.len() - self.position);
+        buf[..n].copy_from_slice(&self.chunk[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+type Connector = dyn Fn() -> io::Result<Connection> + Send + Sync;
+
+pub struct Coordinator {
+    pub tile_size: usize,
+    // how many times a worker is reconnected (e.g. its process respawned) after failing
+    pub max_retries: usize,
+    // longest wait on a worker before its tile counts as failed. None waits forever
+    pub timeout: Option<Duration>,
+    workers: Vec<Arc<Connector>>,
+}
+
+struct Job {
+    scene_message: Vec<u8>,
+    queue: Mutex<VecDeque<Tile>>,
+    remaining: AtomicUsize,
+    canvas: Mutex<Canvas>,
+}
+
+impl Coordinator {
+    pub fn new(tile_size: usize) -> Self {
+        Self {
+            tile_size,
+            max_retries: 3,
+            timeout: Some(Duration::from_secs(300)),
+            workers: vec![],
+        }
+    }
+
+    // The connector is called again to replace the connection when the worker dies
+    pub fn add_worker(
+        &mut self,
+        connector: impl Fn() -> io::Result<Connection> + Send + Sync + 'static,
+    ) {
+        self.workers.push(Arc::new(connector));
+    }
+
+    pub fn render(&self, world: &World, camera: &Camera, antialiasing: bool) -> io::Result<Canvas> {
+        let scene = encode(&(world, camera, antialiasing))?;
+        let tiles = split_tiles(camera.hsize, camera.vsize, self.tile_size);
+        let job = Arc::new(Job {
+            scene_message: encode(&Request::Scene(scene))?,
+            remaining: AtomicUsize::new(tiles.len()),
+            queue: Mutex::new(tiles.into_iter().collect()),
+            canvas: Mutex::new(Canvas::new(camera.hsize, camera.vsize)),
+        });
+
+        let handles = self
+            .workers
+            .iter()
+            .map(|connector| {
+                let connector = Arc::clone(connector);
+                let job = Arc::clone(&job);
+                let (max_retries, timeout) = (self.max_retries, self.timeout);
+                thread::spawn(move || Self::run_worker(&*connector, &job, max_retries, timeout))
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let remaining = job.remaining.load(Ordering::SeqCst);
+        if remaining > 0 {
+            return Err(io::Error::other(format!(
+                "all workers failed, {} tiles were not rendered",
+                remaining
+            )));
+        }
+        let canvas = std::mem::replace(&mut *job.canvas.lock().unwrap(), Canvas::new(0, 0));
+        Ok(canvas)
+    }
+
+    // A worker that fails or times out on a tile is dropped (its process killed) and
+    // reconnected, until it has failed more than max_retries times
+    fn run_worker(connector: &Connector, job: &Job, max_retries: usize, timeout: Option<Duration>) {
+        let mut connection: Option<Connection> = None;
+        let mut failures = 0;
+        while job.remaining.load(Ordering::SeqCst) > 0 {
+            let tile = match job.queue.lock().unwrap().pop_front() {
+                Some(tile) => tile,
+                None => {
+                    // other workers hold the last tiles, wait in case one of them fails
+                    thread::sleep(Duration::from_millis(10));
+                    continue;
+                }
+            };
+            match Self::render_tile(connector, &mut connection, job, tile, timeout) {
+                Ok(pixels) => {
+                    let mut canvas = job.canvas.lock().unwrap();
+                    for (i, color) in pixels.into_iter().enumerate() {
+                        canvas.write_pixel(tile.x + i % tile.width, tile.y + i / tile.width, color);
+                    }
+                    job.remaining.fetch_sub(1, Ordering::SeqCst);
+                }
+                Err(e) => {
+                    job.queue.lock().unwrap().push_back(tile);
+                    connection = None;
+                    failures += 1;
+                    if failures > max_retries {
+                        eprintln!("Giving up on worker after {} failures: {}", failures, e);
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    fn render_tile(
+        connector: &Connector,
+        connection: &mut Option<Connection>,
+        job: &Job,
+        tile: Tile,
+        timeout: Option<Duration>,
+    ) -> io::Result<Vec<Color>> {
+        if connection.is_none() {
+            let mut c = connector()?;
+            c.set_timeout(timeout)?;
+            c.send_raw(&job.scene_message)?;
+            *connection = Some(c);
+        }
+        connection.as_mut().unwrap().render_tile(tile)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{f64::consts::PI, net::SocketAddr};
+
+    use crate::{point, vector};
+
+    fn start_worker() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || serve_tcp(listener));
+        address
+    }
+
+    // accepts a single connection and drops it after receiving the first tile
+    fn start_crashing_worker() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let _: Request = receive(&mut stream).unwrap();
+            let _: Request = receive(&mut stream).unwrap();
+        });
+        address
+    }
+
+    // accepts a single connection and never answers
+    fn start_hung_worker() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            thread::sleep(Duration::from_secs(10));
+            drop(stream);
+        });
+        address
+    }
+
+    fn test_camera() -> Camera {
+        let mut camera = Camera::new(11, 9, PI / 2.);
+        camera.look_at(point!(0, 0, -5), point!(0, 0, 0), vector!(0, 1, 0));
+        camera
+    }
+
+    fn assert_same_image(expected: &Canvas, actual: &Canvas) {
+        assert_eq!(expected.width, actual.width);
+        assert_eq!(expected.height, actual.height);
+        for y in 0..expected.height {
+            for x in 0..expected.width {
+                assert_eq!(
+                    expected.pixel_at(x, y),
+                    actual.pixel_at(x, y),
+                    "{}, {}",
+                    x,
+                    y
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn tiles_cover_the_image() {
+        let tiles = split_tiles(10, 5, 4);
+        assert_eq!(6, tiles.len());
+        assert_eq!(
+            Tile {
+                x: 0,
+                y: 0,
+                width: 4,
+                height: 4
+            },
+            tiles[0]
+        );
+        assert_eq!(
+            Tile {
+                x: 8,
+                y: 4,
+                width: 2,
+                height: 1
+            },
+            tiles[5]
+        );
+        let area: usize = tiles.iter().map(|t| t.width * t.height).sum();
+        assert_eq!(50, area);
+    }
+
+    #[test]
+    fn render_with_several_workers() {
+        let world = World::default();
+        let camera = test_camera();
+
+        let mut coordinator = Coordinator::new(4);
+        for _ in 0..3 {
+            let address = start_worker();
+            coordinator.add_worker(move || Connection::tcp(address));
+        }
+        let image = coordinator.render(&world, &camera, false).unwrap();
+        assert_same_image(&camera.render(&world, false), &image);
+    }
+
+    #[test]
+    fn tiles_are_retried_when_a_worker_dies() {
+        let world = World::default();
+        let camera = test_camera();
+
+        let crashing = start_crashing_worker();
+        let healthy = start_worker();
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let mut coordinator = Coordinator::new(3);
+        {
+            let attempts = Arc::clone(&attempts);
+            coordinator.add_worker(move || {
+                if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                    Connection::tcp(crashing)
+                } else {
+                    Connection::tcp(healthy)
+                }
+            });
+        }
+        let image = coordinator.render(&world, &camera, false).unwrap();
+        assert_eq!(2, attempts.load(Ordering::SeqCst));
+        assert_same_image(&camera.render(&world, false), &image);
+    }
+
+    #[test]
+    fn hung_workers_time_out_and_are_retried() {
+        let world = World::default();
+        let camera = test_camera();
+
+        let hung = start_hung_worker();
+        let healthy = start_worker();
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let mut coordinator = Coordinator::new(4);
+        coordinator.timeout = Some(Duration::from_millis(200));
+        {
+            let attempts = Arc::clone(&attempts);
+            coordinator.add_worker(move || {
+                if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                    Connection::tcp(hung)
+                } else {
+                    Connection::tcp(healthy)
+                }
+            });
+        }
+        let image = coordinator.render(&world, &camera, false).unwrap();
+        assert_eq!(2, attempts.load(Ordering::SeqCst));
+        assert_same_image(&camera.render(&world, false), &image);
+    }
+
+    #[test]
+    fn pipe_reads_time_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+        let mut reader = TimeoutReader::new(stream);
+        *reader.timeout.lock().unwrap() = Some(Duration::from_millis(50));
+        let mut buf = [0; 4];
+        assert_eq!(
+            io::ErrorKind::TimedOut,
+            reader.read(&mut buf).unwrap_err().kind()
+        );
+        peer.write_all(b"tile").unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(b"tile", &buf);
+        drop(peer);
+        assert_eq!(0, reader.read(&mut buf).unwrap());
+    }
+
+    #[test]
+    fn oversized_messages_are_rejected() {
+        // a scene claiming u64::MAX bytes, followed by endless data
+        let mut bytes = (&[0u8, 253][..]).chain(&[0xff; 8][..]).chain(io::repeat(0));
+        let result: io::Result<Request> = receive_limited(&mut bytes, 1024);
+        assert_eq!(io::ErrorKind::InvalidData, result.err().unwrap().kind());
+    }
+
+    #[test]
+    fn serve_over_stdin_and_stdout() {
+        let world = World::default();
+        let camera = test_camera();
+        let tile = Tile {
+            x: 2,
+            y: 3,
+            width: 4,
+            height: 2,
+        };
+        // what the coordinator writes to the worker's stdin
+        let mut input = vec![];
+        let scene = encode(&(&world, &camera, false)).unwrap();
+        send(&mut input, &Request::Scene(scene)).unwrap();
+        send(&mut input, &Request::Tile(tile)).unwrap();
+
+        let mut output = vec![];
+        serve(&input[..], &mut output).unwrap();
+        let result: TileResult = receive(&mut &output[..]).unwrap();
+        assert_eq!(tile, result.tile);
+        let expected = camera.render_region(&world, 2, 3, 4, 2, false);
+        assert_eq!(expected, result.pixels);
+    }
+
+    #[test]
+    fn render_fails_when_all_workers_fail() {
+        let mut coordinator = Coordinator::new(4);
+        coordinator.max_retries = 1;
+        coordinator.add_worker(|| Err(io::Error::from(io::ErrorKind::ConnectionRefused)));
+        let result = coordinator.render(&World::default(), &test_camera(), false);
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,280 @@
+use std::f64::consts::PI;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    background::{Background, EnvironmentMap},
+    color::{Color, BLACK},
+    intersection::PreparedComputations,
+    light::PointLight,
+    tuple::Tuple,
+    vector,
+    world::World,
+};
+
+// far enough for the shadow rays to leave the scene
+const ENVIRONMENT_DISTANCE: f64 = 1e6;
+
+// Environment map lighting the scene, importance sampled: pixels are picked
+// with a probability proportional to their luminance times the solid angle they cover,
+// first the row from the marginal distribution, then the column within it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EnvironmentLight {
+    pub map: EnvironmentMap,
+    // cumulative distributions, each ending with 1
+    marginal_cdf: Vec<f64>,
+    conditional_cdfs: Vec<Vec<f64>>,
+    // probability of each pixel
+    pixel_probabilities: Vec<f64>,
+}
+
+impl EnvironmentLight {
+    pub fn new(map: EnvironmentMap) -> Self {
+        let (width, height) = (map.width, map.height);
+        let weights = (0..height)
+            .map(|y| {
+                let sin_theta = ((y as f64 + 0.5) / height as f64 * PI).sin();
+                (0..width)
+                    .map(|x| luminance(map.pixel_at(x, y)) * sin_theta)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let row_sums = weights
+            .iter()
+            .map(|row| row.iter().sum())
+            .collect::<Vec<f64>>();
+        let total: f64 = row_sums.iter().sum();
+
+        let pixel_probabilities = weights
+            .iter()
+            .flatten()
+            .map(|w| if total > 0. { w / total } else { 0. })
+            .collect();
+        Self {
+            marginal_cdf: cdf(&row_sums),
+            conditional_cdfs: weights.iter().map(|row| cdf(row)).collect(),
+            pixel_probabilities,
+            map,
+        }
+    }
+
+    // A direction toward the environment, its probability density over the sphere of
+    // directions and the radiance coming from it. None for a black environment
+    pub fn sample(&self, rng: &mut impl Rng) -> Option<(Tuple, f64, Color)> {
+        let y = pick(&self.marginal_cdf, rng.gen())?;
+        let x = pick(&self.conditional_cdfs[y], rng.gen())?;
+        // anywhere in the pixel
+        let u = (x as f64 + rng.gen::<f64>()) / self.map.width as f64;
+        let v = (y as f64 + rng.gen::<f64>()) / self.map.height as f64;
+        let (theta, phi) = (v * PI, (u - 0.5) * 2. * PI);
+        let direction = vector!(
+            theta.sin() * phi.sin(),
+            theta.cos(),
+            theta.sin() * phi.cos()
+        );
+        let pdf = self.pdf(direction);
+        if pdf <= 0. {
+            return None;
+        }
+        Some((direction, pdf, self.map.color_in(direction)))
+    }
+
+    pub fn is_black(&self) -> bool {
+        pick(&self.marginal_cdf, 0.).is_none()
+    }
+
+    // Probability density of sampling the direction, over solid angle
+    pub fn pdf(&self, direction: Tuple) -> f64 {
+        let (x, y) = self.map.pixel_in(direction);
+        let sin_theta = (1. - direction.normalize().y.powi(2)).max(0.).sqrt();
+        if sin_theta <= 0. {
+            return 0.;
+        }
+        // uniform within the pixel in image space, which covers 2PI x PI of angles
+        let image_pdf = self.pixel_probabilities[y * self.map.width + x]
+            * (self.map.width * self.map.height) as f64;
+        image_pdf / (2. * PI * PI * sin_theta)
+    }
+}
+
+fn luminance(color: Color) -> f64 {
+    (0.2126 * color.r + 0.7152 * color.g + 0.0722 * color.b).max(0.)
+}
+
+fn cdf(weights: &[f64]) -> Vec<f64> {
+    let total: f64 = weights.iter().sum();
+    let mut sum = 0.;
+    weights
+        .iter()
+        .map(|w| {
+            sum += w;
+            if total > 0. {
+                sum / total
+            } else {
+                0.
+            }
+        })
+        .collect()
+}
+
+// Index of the first entry of the distribution above u, skipping empty entries
+fn pick(cdf: &[f64], u: f64) -> Option<usize> {
+    if *cdf.last()? <= 0. {
+        return None;
+    }
+    Some(cdf.partition_point(|c| *c <= u).min(cdf.len() - 1))
+}
+
+impl World {
+    // Lights the scene with the map and shows it as the background
+    pub fn set_environment(&mut self, map: EnvironmentMap) {
+        self.background = Background::Environment(map.clone());
+        self.environment_light = Some(EnvironmentLight::new(map));
+    }
+
+    // Monte Carlo estimate of the light from the environment: each sampled direction
+    // is shaded as a far away point light, weighted by its probability, and tested
+    // for shadows like the main light
+    pub(crate) fn environment_lighting(&self, comps: &PreparedComputations) -> Color {
+        let environment = match &self.environment_light {
+            Some(environment) if !environment.is_black() => environment,
+            _ => return BLACK,
+        };
+        let samples = self.environment_samples.max(1);
+        let mut rng = rand::thread_rng();
+        let mut color = BLACK;
+        for _ in 0..samples {
+            // e.g. rounded into a neighbouring pixel that is never picked
+            let (direction, pdf, radiance) = match environment.sample(&mut rng) {
+                Some(sample) => sample,
+                None => continue,
+            };
+            if direction.dot(&comps.normalv) <= 0. {
+                continue;
+            }
+            // the shading models leave out the 1/PI of a diffuse surface
+            let intensity = radiance / (PI * pdf * samples as f64);
+            let position = comps.over_point + direction * ENVIRONMENT_DISTANCE;
+            let light = PointLight::new(position, intensity);
+            let transmittance = self.transmittance_between(comps.over_point, position);
+            color = color + self.direct_lighting(comps, &light, transmittance);
+        }
+        color
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        color,
+        color::{RED, WHITE},
+        cube,
+        material::Material,
+        matrix::Matrix,
+        plane, point, ray,
+    };
+
+    #[test]
+    fn samples_follow_the_luminance() {
+        // a single bright pixel, left of the center of the upper row
+        let mut pixels = vec![color!(0.01, 0.01, 0.01); 8];
+        pixels[1] = color!(100, 100, 100);
+        let light = EnvironmentLight::new(EnvironmentMap::new(4, 2, pixels));
+        let mut rng = rand::thread_rng();
+        let bright = (0..1000)
+            .filter(|_| {
+                let (direction, pdf, radiance) = light.sample(&mut rng).unwrap();
+                assert!(pdf > 0.);
+                let bright_pixel = light.map.pixel_in(direction) == (1, 0);
+                assert_eq!(bright_pixel, radiance == color!(100, 100, 100));
+                bright_pixel
+            })
+            .count();
+        assert!(bright > 950);
+    }
+
+    #[test]
+    fn pdf_integrates_to_one() {
+        let pixels = vec![WHITE, RED, BLACK, WHITE, color!(0, 0, 4), WHITE, RED, BLACK];
+        let light = EnvironmentLight::new(EnvironmentMap::new(4, 2, pixels));
+        let n = 200;
+        let mut sum = 0.;
+        for i in 0..n {
+            for j in 0..n {
+                let theta = (i as f64 + 0.5) / n as f64 * PI;
+                let phi = (j as f64 + 0.5) / n as f64 * 2. * PI;
+                let direction = vector!(
+                    theta.sin() * phi.sin(),
+                    theta.cos(),
+                    theta.sin() * phi.cos()
+                );
+                sum += light.pdf(direction) * theta.sin() * (PI / n as f64) * (2. * PI / n as f64);
+            }
+        }
+        assert!((sum - 1.).abs() < 0.01);
+    }
+
+    #[test]
+    fn black_environment_has_nothing_to_sample() {
+        let light = EnvironmentLight::new(EnvironmentMap::new(2, 1, vec![BLACK, BLACK]));
+        assert!(light.is_black());
+        assert!(light.sample(&mut rand::thread_rng()).is_none());
+    }
+
+    fn floor_world(map: EnvironmentMap) -> World {
+        let mut w = World::new(PointLight::new(point!(0, 10, 0), BLACK), vec![]);
+        let mut floor = plane!();
+        floor.set_material(Material {
+            ambient: 0.,
+            specular: 0.,
+            ..Material::default()
+        });
+        w.add_object(floor);
+        w.set_environment(map);
+        w.environment_samples = 64;
+        w
+    }
+
+    #[test]
+    fn uniform_environment_lights_like_a_white_light() {
+        let mut w = floor_world(EnvironmentMap::new(8, 4, vec![WHITE; 32]));
+        w.environment_samples = 1024;
+        let r = ray!(point!(0, 1, 0), vector!(0, -1, 0));
+        // the cosine weighted hemisphere integrates to the diffuse reflectance
+        let lit = w.color_at(&r);
+        assert!((lit.r - 0.9).abs() < 0.15);
+        // misses see the map
+        assert_eq!(WHITE, w.color_at(&ray!(point!(0, 1, 0), vector!(0, 1, 0))));
+    }
+
+    #[test]
+    fn samples_without_probability_are_skipped() {
+        let mut w = floor_world(EnvironmentMap::new(8, 4, vec![WHITE; 32]));
+        // half of the pixels can be picked but have no density
+        let light = w.environment_light.as_mut().unwrap();
+        for p in light.pixel_probabilities.iter_mut().step_by(2) {
+            *p = 0.;
+        }
+        let r = ray!(point!(0, 1, 0), vector!(0, -1, 0));
+        assert!(w.color_at(&r).r > 0.);
+    }
+
+    #[test]
+    fn environment_light_is_shadowed() {
+        // light only from the upper rows
+        let mut pixels = vec![BLACK; 32];
+        for pixel in pixels.iter_mut().take(8) {
+            *pixel = WHITE;
+        }
+        let mut w = floor_world(EnvironmentMap::new(8, 4, pixels));
+        let r = ray!(point!(0, 1, 0), vector!(0, -1, 0));
+        assert!(w.color_at(&r).r > 0.);
+
+        let mut roof = cube!();
+        roof.set_transform(Matrix::translation(0, 2, 0) * Matrix::scaling(100, 0.1, 100));
+        w.add_object(roof);
+        assert_eq!(BLACK, w.color_at(&ray!(point!(0, 1, 0), vector!(0, -1, 0))));
+    }
+}
@@ -0,0 +1,267 @@
+use std::{fs, io};
+
+use serde::{Deserialize, Serialize};
+
+/*
+IESNA LM-63 photometric data (type C): candela values over a grid of vertical
+angles (0 is the light's axis) and horizontal angles around it.
+
+    IESNA:LM-63-2002
+    [MANUFAC] ...
+    TILT=NONE
+    1 1000 1 <vertical count> <horizontal count> 1 1 0 0 0
+    1 1 100
+    <vertical angles>
+    <horizontal angles>
+    <candela values, one row of vertical values per horizontal angle>
+
+Values can be spread over lines in any way.
+*/
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IesProfile {
+    pub vertical_angles: Vec<f64>,
+    pub horizontal_angles: Vec<f64>,
+    // candela[horizontal][vertical]
+    pub candela: Vec<Vec<f64>>,
+    max_candela: f64,
+}
+
+impl IesProfile {
+    // Needs increasing angles and a row of candela values per horizontal angle,
+    // with one value per vertical angle
+    pub fn new(
+        vertical_angles: Vec<f64>,
+        horizontal_angles: Vec<f64>,
+        candela: Vec<Vec<f64>>,
+    ) -> io::Result<Self> {
+        let invalid = |message: &str| Err(io::Error::new(io::ErrorKind::InvalidData, message));
+
+        if vertical_angles.is_empty() || horizontal_angles.is_empty() {
+            return invalid("no angles");
+        }
+        let increasing = |angles: &[f64]| angles.windows(2).all(|w| w[0] <= w[1]);
+        if !increasing(&vertical_angles) || !increasing(&horizontal_angles) {
+            return invalid("angles out of order");
+        }
+        if candela.len() != horizontal_angles.len()
+            || candela.iter().any(|row| row.len() != vertical_angles.len())
+        {
+            return invalid("candela values don't match the angles");
+        }
+        let max_candela = candela.iter().flatten().fold(0f64, |max, c| max.max(*c));
+        Ok(Self {
+            vertical_angles,
+            horizontal_angles,
+            candela,
+            max_candela,
+        })
+    }
+
+    pub fn load(path: &str) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        Self::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message);
+
+        let mut lines = text.lines();
+        let tilt = lines
+            .by_ref()
+            .map(str::trim)
+            .find(|l| l.starts_with("TILT="))
+            .ok_or_else(|| invalid("missing TILT line"))?;
+        let mut numbers = lines
+            .flat_map(|l| l.split(|c: char| c.is_whitespace() || c == ','))
+            .filter(|t| !t.is_empty())
+            .map(|t| t.parse::<f64>().map_err(|_| invalid("invalid number")));
+        let mut next = || {
+            numbers
+                .next()
+                .unwrap_or_else(|| Err(invalid("truncated data")))
+        };
+        let mut take = |n: usize| (0..n).map(|_| next()).collect::<io::Result<Vec<_>>>();
+
+        if tilt == "TILT=INCLUDE" {
+            // lamp to luminaire geometry, then the tilt angles and factors
+            let header = take(2)?;
+            let count = (header[1] as usize)
+                .checked_mul(2)
+                .ok_or_else(|| invalid("truncated data"))?;
+            take(count)?;
+        }
+        let header = take(13)?;
+        let multiplier = header[2];
+        let (vertical_count, horizontal_count) = (header[3] as usize, header[4] as usize);
+        if header[5] != 1. {
+            return Err(invalid("unsupported photometric type, only type C is"));
+        }
+        if vertical_count == 0 || horizontal_count == 0 {
+            return Err(invalid("no angles"));
+        }
+        let vertical_angles = take(vertical_count)?;
+        let horizontal_angles = take(horizontal_count)?;
+        let candela = (0..horizontal_count)
+            .map(|_| {
+                take(vertical_count).map(|row| row.into_iter().map(|c| c * multiplier).collect())
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        Self::new(vertical_angles, horizontal_angles, candela)
+    }
+
+    // Candela toward the given angles (in degrees) relative to the brightest direction.
+    // Horizontal angles are unfolded following the symmetry of the data
+    pub fn relative_intensity(&self, vertical: f64, horizontal: f64) -> f64 {
+        if self.max_candela <= 0. {
+            return 0.;
+        }
+        let v = match lerp_position(&self.vertical_angles, vertical) {
+            Some(v) => v,
+            None => return 0.,
+        };
+        let candela_in = |plane: &Vec<f64>| plane[v.0] * (1. - v.2) + plane[v.1] * v.2;
+
+        let last = *self.horizontal_angles.last().unwrap();
+        let mut h = horizontal.rem_euclid(360.);
+        if last <= 180. && h > 180. {
+            h = 360. - h;
+        }
+        if last <= 90. && h > 90. {
+            h = 180. - h;
+        }
+        let candela = match lerp_position(&self.horizontal_angles, h) {
+            // rotationally symmetric with a single plane
+            _ if self.horizontal_angles.len() == 1 => candela_in(&self.candela[0]),
+            Some((a, b, f)) => {
+                candela_in(&self.candela[a]) * (1. - f) + candela_in(&self.candela[b]) * f
+            }
+            None => 0.,
+        };
+        candela / self.max_candela
+    }
+}
+
+// Indices of the sorted angles around `angle`, and how far it is between them
+fn lerp_position(angles: &[f64], angle: f64) -> Option<(usize, usize, f64)> {
+    let first = *angles.first()?;
+    let last = *angles.last()?;
+    if !angle.is_finite() || angle < first || angle > last {
+        return None;
+    }
+    if angles.len() == 1 {
+        return Some((0, 0, 0.));
+    }
+    let b = angles.iter().position(|a| *a >= angle).unwrap().max(1);
+    let a = b - 1;
+    let span = angles[b] - angles[a];
+    let f = if span > 0. {
+        (angle - angles[a]) / span
+    } else {
+        0.
+    };
+    Some((a, b, f))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::approx_eq;
+
+    const DOWNLIGHT: &str = "IESNA:LM-63-2002
+[TEST] downlight
+[MANUFAC] nobody
+TILT=NONE
+1 1000 2 3 1 1 1 0.1 0.1 0
+1.0 1.0 20
+0 45 90
+0
+500 250,
+0
+";
+
+    #[test]
+    fn parse_rotationally_symmetric_profile() {
+        let profile = IesProfile::parse(DOWNLIGHT).unwrap();
+        assert_eq!(vec![0., 45., 90.], profile.vertical_angles);
+        assert_eq!(vec![0.], profile.horizontal_angles);
+        // scaled by the candela multiplier
+        assert_eq!(vec![vec![1000., 500., 0.]], profile.candela);
+
+        assert_eq!(1., profile.relative_intensity(0., 0.));
+        assert_eq!(0.5, profile.relative_intensity(45., 123.));
+        assert!(approx_eq(0.75, profile.relative_intensity(22.5, 300.)));
+        // nothing above the horizon
+        assert_eq!(0., profile.relative_intensity(120., 0.));
+    }
+
+    #[test]
+    fn horizontal_symmetry() {
+        let profile = IesProfile::new(
+            vec![0., 90.],
+            vec![0., 90.],
+            vec![vec![100., 100.], vec![50., 50.]],
+        )
+        .unwrap();
+        // quadrant symmetric data mirrored into [0, 90]
+        assert_eq!(1., profile.relative_intensity(0., 0.));
+        assert_eq!(0.5, profile.relative_intensity(0., 90.));
+        assert_eq!(1., profile.relative_intensity(0., 180.));
+        assert_eq!(0.5, profile.relative_intensity(0., 270.));
+        assert_eq!(0.75, profile.relative_intensity(0., 135.));
+        assert_eq!(0.75, profile.relative_intensity(0., -45.));
+        assert_eq!(0., profile.relative_intensity(f64::NAN, 0.));
+    }
+
+    #[test]
+    fn parse_tilt_include() {
+        let text = "TILT=INCLUDE\n1\n2\n0 90\n1 1\n1 100 1 2 1 1 1 0 0 0\n1 1 10\n0 90\n0\n10 5\n";
+        let profile = IesProfile::parse(text).unwrap();
+        assert_eq!(vec![vec![10., 5.]], profile.candela);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let error = |text: &str| IesProfile::parse(text).unwrap_err().to_string();
+        assert_eq!("missing TILT line", error("IESNA:LM-63-2002\n"));
+        assert_eq!(
+            "truncated data",
+            error("TILT=NONE\n1 1000 1 3 1 1 1 0 0 0\n")
+        );
+        assert_eq!("invalid number", error("TILT=NONE\n1 lots"));
+        assert_eq!("truncated data", error("TILT=INCLUDE\n1 1e300\n0 90\n"));
+        assert_eq!(
+            "unsupported photometric type, only type C is",
+            error("TILT=NONE\n1 1000 1 1 1 2 1 0 0 0 1 1 1 0 0 1")
+        );
+    }
+
+    #[test]
+    fn invalid_profiles() {
+        let error = |v: Vec<f64>, h: Vec<f64>, candela: Vec<Vec<f64>>| {
+            IesProfile::new(v, h, candela).unwrap_err().to_string()
+        };
+        assert_eq!("no angles", error(vec![0.], vec![], vec![]));
+        assert_eq!(
+            "angles out of order",
+            error(vec![90., 0.], vec![0.], vec![vec![1., 1.]])
+        );
+        assert_eq!(
+            "candela values don't match the angles",
+            error(vec![0., 90.], vec![0.], vec![vec![1.]])
+        );
+        assert_eq!(
+            "candela values don't match the angles",
+            error(vec![0., 90.], vec![0., 90.], vec![vec![1., 1.]])
+        );
+    }
+
+    #[test]
+    fn load_from_file() {
+        let path = std::env::temp_dir().join("rust_tracer_ies_test.ies");
+        fs::write(&path, DOWNLIGHT).unwrap();
+        let profile = IesProfile::load(path.to_str().unwrap()).unwrap();
+        assert_eq!(3, profile.vertical_angles.len());
+        assert!(IesProfile::load("/nonexistent/light.ies").is_err());
+    }
+}
@@ -0,0 +1,378 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    color::{Color, BLACK, WHITE},
+    intersection::{Intersection, PreparedComputations},
+    material::ShadingModel,
+    ray,
+    ray::Ray,
+    sampling, spectrum, stats,
+    world::World,
+};
+
+// Paths shorter than this are never terminated by Russian roulette
+const MIN_ROULETTE_DEPTH: usize = 3;
+
+#[derive(Debug, Default, PartialEq, Copy, Clone, Serialize, Deserialize)]
+pub enum Integrator {
+    // direct lighting plus perfect mirror and refraction rays
+    #[default]
+    Whitted,
+    // Monte Carlo path tracing: samples paths per camera ray, with next event
+    // estimation toward the light and Russian roulette termination
+    PathTracing {
+        samples: usize,
+        max_depth: usize,
+    },
+}
+
+// Limits on the secondary rays traced by the Whitted integrator
+#[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
+pub struct TraceLimits {
+    // reflections and refractions combined
+    pub max_depth: usize,
+    // each kind on its own, within max_depth
+    pub max_reflection_depth: usize,
+    pub max_refraction_depth: usize,
+    // secondary rays that would add less than this fraction to the pixel are not traced
+    pub min_contribution: f64,
+    // from this depth on, secondary rays are traced with a probability equal to
+    // their contribution, and weighted up to compensate
+    pub russian_roulette_depth: Option<usize>,
+}
+
+impl Default for TraceLimits {
+    fn default() -> Self {
+        Self {
+            max_depth: 5,
+            max_reflection_depth: 5,
+            max_refraction_depth: 5,
+            min_contribution: 0.,
+            russian_roulette_depth: None,
+        }
+    }
+}
+
+impl World {
+    pub(crate) fn path_traced_color(&self, r: &Ray, samples: usize, max_depth: usize) -> Color {
+        let mut rng = rand::thread_rng();
+        let samples = samples.max(1);
+        let mut sum = BLACK;
+        for _ in 0..samples {
+            sum = sum + self.trace_path(r, max_depth, &mut rng);
+        }
+        sum / samples as f64
+    }
+
+    fn trace_path(&self, r: &Ray, max_depth: usize, rng: &mut impl Rng) -> Color {
+        let mut radiance = BLACK;
+        let mut throughput = WHITE;
+        let mut ray = ray!(r.origin, r.direction).with_wavelength(r.wavelength);
+
+        for depth in 0..=max_depth {
+            stats::record(|s| s.max_depth = s.max_depth.max(depth));
+
+            let xs = self.intersect(&ray);
+            let xs_refs = xs.iter().collect::<Vec<&Intersection>>();
+            let hit = xs
+                .iter()
+                .find(|i| i.t >= 0. && !i.object.material().is_volume());
+            let (light, transmittance) =
+                self.media_along(&ray, &xs, hit.map_or(f64::INFINITY, |i| i.t));
+            radiance = radiance + throughput * light;
+            throughput = throughput * transmittance;
+            let hit = match hit {
+                Some(i) => i,
+                None => {
+                    radiance = radiance + throughput * self.background.color_in(ray.direction);
+                    break;
+                }
+            };
+            if ray.wavelength.is_none() && hit.object.material().is_dispersive() {
+                // the path continues with a single wavelength picked at random
+                let samples = spectrum::spectral_samples(self.dispersion_samples);
+                let (wavelength, weight) = samples[rng.gen_range(0..samples.len())];
+                throughput = throughput * weight * samples.len() as f64;
+                ray.wavelength = Some(wavelength);
+            }
+            let comps = hit.prepare_computations(&self.arena, &ray, &xs_refs[..]);
+            throughput = throughput * comps.absorption();
+
+            // emissive shapes are not sampled as lights here, their light
+            // is gathered when paths hit them
+            radiance = radiance + throughput * comps.object.material().emitted();
+
+            // next event estimation: direct light through the same shading model
+            // as the Whitted integrator. The ambient term is replaced by indirect light.
+            let transmittance = self.shadow_transmittance(comps.over_point);
+            let direct = self.direct_lighting(&comps, &self.light, transmittance);
+            radiance = radiance
+                + throughput
+                    * (direct
+                        + self.spot_lighting(&comps)
+                        + self.sun_lighting(&comps)
+                        + self.caustic_lighting(&comps));
+
+            if depth == max_depth {
+                break;
+            }
+            let (weight, next) = match sample_bsdf(&comps, rng) {
+                Some(sample) => sample,
+                None => break,
+            };
+            throughput = throughput * weight;
+
+            if depth >= MIN_ROULETTE_DEPTH {
+                let survival = throughput.r.max(throughput.g).max(throughput.b).min(1.);
+                if rng.gen::<f64>() >= survival {
+                    break;
+                }
+                throughput = throughput / survival;
+            }
+            ray = next.with_wavelength(ray.wavelength);
+        }
+        radiance
+    }
+}
+
+// Reflects on the clearcoat and thin-film layers with a probability of their reflectance,
+// otherwise samples the base material, weighted by what the layers let through.
+fn sample_bsdf(comps: &PreparedComputations, rng: &mut impl Rng) -> Option<(Color, Ray)> {
+    let layer = match comps.layer_reflectance() {
+        Some(layer) => layer,
+        None => return sample_base_bsdf(comps, rng),
+    };
+    let probability = layer.r.max(layer.g).max(layer.b).min(1.);
+    if rng.gen::<f64>() < probability {
+        stats::record(|s| s.reflection_rays += 1);
+        return Some((layer / probability, ray!(comps.over_point, comps.reflectv)));
+    }
+    let (weight, next) = sample_base_bsdf(comps, rng)?;
+    Some((weight * (WHITE - layer) / (1. - probability), next))
+}
+
+// Picks one of the mirror, refraction and diffuse lobes proportionally to their weights.
+// Returns the throughput weight of the chosen lobe and the continuation ray,
+// or None if the path is absorbed.
+fn sample_base_bsdf(comps: &PreparedComputations, rng: &mut impl Rng) -> Option<(Color, Ray)> {
+    let material = comps.object.material();
+    let color = material
+        .pattern
+        .color_at_object(comps.object, comps.over_point);
+
+    let (mut reflect_weight, mut refract_weight) = (material.reflective, material.transparency);
+    if reflect_weight > 0. && refract_weight > 0. {
+        let reflectance = comps.schlick();
+        reflect_weight *= reflectance;
+        refract_weight *= 1. - reflectance;
+    }
+    let albedo = match material.model {
+        ShadingModel::Pbr => color * (1. - material.metallic),
+        _ => color * material.diffuse,
+    };
+    let diffuse_weight = albedo.r.max(albedo.g).max(albedo.b).max(0.);
+
+    let total = reflect_weight + refract_weight + diffuse_weight;
+    if total <= 0. {
+        return None;
+    }
+    // when the weights add up to less than one the rest is absorbed
+    let scale = total.max(1.);
+    let u = rng.gen::<f64>() * scale;
+
+    let reflectv = sampling::rough_direction(
+        comps.reflectv,
+        material.reflection_roughness,
+        comps.geometric_normalv,
+        rng,
+    );
+    if u < reflect_weight {
+        stats::record(|s| s.reflection_rays += 1);
+        Some((WHITE * scale, ray!(comps.over_point, reflectv)))
+    } else if u < reflect_weight + refract_weight {
+        let weight = WHITE * scale;
+        match comps.refracted_direction() {
+            Some(direction) => {
+                stats::record(|s| s.refraction_rays += 1);
+                let direction = sampling::rough_direction(
+                    direction,
+                    material.refraction_roughness,
+                    -comps.geometric_normalv,
+                    rng,
+                );
+                Some((weight, ray!(comps.under_point, direction)))
+            }
+            // total internal reflection
+            None => {
+                stats::record(|s| s.reflection_rays += 1);
+                Some((weight, ray!(comps.over_point, reflectv)))
+            }
+        }
+    } else if u < total {
+        // cosine weighted sampling cancels the lambertian cos / pdf terms
+        stats::record(|s| s.reflection_rays += 1);
+        let direction = sampling::cosine_hemisphere(comps.normalv, rng);
+        Some((
+            albedo * (scale / diffuse_weight),
+            ray!(comps.over_point, direction),
+        ))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        background::Background,
+        color,
+        light::PointLight,
+        material::{Clearcoat, Material, MaterialBuilder},
+        matrix::Matrix,
+        plane, point, solid, sphere, vector,
+    };
+
+    #[test]
+    fn whitted_is_the_default() {
+        assert_eq!(Integrator::Whitted, World::default().integrator);
+    }
+
+    #[test]
+    fn direct_light_matches_whitted_without_ambient() {
+        // bounces from the outer sphere of the default world escape to the sky
+        let mut w = World::default();
+        w.integrator = Integrator::PathTracing {
+            samples: 4,
+            max_depth: 5,
+        };
+        let r = ray!(point!(0, 0, -5), vector!(0, 0, 1));
+        let ambient = color!(0.8, 1, 0.6) * 0.1;
+        assert_eq!(color!(0.38066, 0.47583, 0.2855) - ambient, w.color_at(&r));
+    }
+
+    fn shadowed_floor_world() -> World {
+        let light = PointLight::new(point!(0, 10, 0), WHITE);
+        let matte = MaterialBuilder::default()
+            .ambient(0.)
+            .specular(0.)
+            .build()
+            .unwrap();
+        let mut floor = plane!();
+        floor.set_material(matte);
+        // blocks the light right above the origin
+        let mut blocker = sphere!();
+        blocker.set_transform(Matrix::translation(0, 2, 0));
+        blocker.set_material(matte);
+        // lit wall next to the shadow
+        let mut wall = plane!();
+        wall.set_transform(
+            Matrix::translation(2, 0, 0) * Matrix::rotation_z(std::f64::consts::PI / 2.),
+        );
+        wall.set_material(Material {
+            pattern: solid!(1, 0, 0),
+            ..matte
+        });
+        World::new(light, vec![floor, blocker, wall])
+    }
+
+    #[test]
+    fn whitted_shadow_is_black_without_ambient() {
+        let w = shadowed_floor_world();
+        let r = ray!(point!(0, 1, -0.5), vector!(0, -1, 0.5).normalize());
+        assert_eq!(BLACK, w.color_at(&r));
+    }
+
+    #[test]
+    fn path_tracing_shadow_receives_indirect_light() {
+        let mut w = shadowed_floor_world();
+        w.integrator = Integrator::PathTracing {
+            samples: 64,
+            max_depth: 4,
+        };
+        let r = ray!(point!(0, 1, -0.5), vector!(0, -1, 0.5).normalize());
+        let c = w.color_at(&r);
+        // color bleeding from the red wall
+        assert!(c.r > 0.01);
+        assert!(c.r > c.g);
+    }
+
+    #[test]
+    fn paths_that_escape_see_the_background() {
+        let mut w = World::new(PointLight::new(point!(0, 0, 0), WHITE), vec![]);
+        w.background = Background::Solid(color!(0.2, 0.4, 0.6));
+        w.integrator = Integrator::PathTracing {
+            samples: 4,
+            max_depth: 2,
+        };
+        let r = ray!(point!(0, 0, 0), vector!(0, 1, 0));
+        assert_eq!(color!(0.2, 0.4, 0.6), w.color_at(&r));
+    }
+
+    #[test]
+    fn path_between_mirrors_terminates() {
+        let mut w = World::new(PointLight::new(point!(0, 0, 0), WHITE), vec![]);
+        // half of the light is absorbed at each bounce, which adds at most its highlight
+        let mirror = Material {
+            reflective: 0.5,
+            diffuse: 0.,
+            ..Material::default()
+        };
+        let mut lower = plane!();
+        lower.set_transform(Matrix::translation(0, -1, 0));
+        lower.set_material(mirror);
+        let mut upper = plane!();
+        upper.set_transform(Matrix::translation(0, 1, 0));
+        upper.set_material(mirror);
+        w.add_object(lower);
+        w.add_object(upper);
+        w.integrator = Integrator::PathTracing {
+            samples: 1,
+            max_depth: 100,
+        };
+        let r = ray!(point!(0, 0, 0), vector!(0, 1, 0));
+        stats::take();
+        let color = w.color_at(&r);
+        let depth = stats::take().max_depth;
+        // absorbed long before the limit
+        assert!(depth < 100);
+        assert!(color.r > 0. && color.r < (depth + 1) as f64);
+    }
+
+    #[test]
+    fn absorbing_surface_ends_the_path() {
+        let black = Material {
+            pattern: solid!(0, 0, 0),
+            ..Material::default()
+        };
+        let mut s = sphere!();
+        s.set_material(black);
+        let w = World::new(PointLight::new(point!(-10, 10, -10), WHITE), vec![s]);
+        let r = ray!(point!(0, 0, -5), vector!(0, 0, 1));
+        let xs = w.intersect(&r);
+        let comps = xs[0].prepare_computations(&w.arena, &r, &[&xs[0], &xs[1]]);
+        assert!(sample_bsdf(&comps, &mut rand::thread_rng()).is_none());
+    }
+
+    #[test]
+    fn path_tracing_reflects_on_clearcoat() {
+        let mut floor = plane!();
+        floor.set_material(Material {
+            ambient: 0.,
+            diffuse: 0.,
+            specular: 0.,
+            clearcoat: Some(Clearcoat::default()),
+            ..Material::default()
+        });
+        let mut w = World::new(PointLight::new(point!(10, 1, 0), WHITE), vec![floor]);
+        w.background = Background::Solid(WHITE);
+        w.integrator = Integrator::PathTracing {
+            samples: 2000,
+            max_depth: 2,
+        };
+        let r = ray!(point!(0, 1, 0), vector!(0, -1, 0));
+        let c = w.color_at(&r);
+        assert!((c.r - 0.04).abs() < 0.02);
+    }
+}
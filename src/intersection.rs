@@ -1,4 +1,13 @@
-use crate::{arena::Arena, ray::Ray, shapes::Shape, tuple::Tuple, EPSILON};
+use crate::{
+    arena::Arena,
+    color::{Color, BLACK, WHITE},
+    material::{ShadingModel, RGB_WAVELENGTHS},
+    ray::Ray,
+    sampling,
+    shapes::Shape,
+    tuple::Tuple,
+    EPSILON,
+};
 
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub struct Intersection<'a> {
@@ -14,11 +23,19 @@ pub struct PreparedComputations<'a> {
     pub under_point: Tuple,
     pub over_point: Tuple,
     pub eyev: Tuple,
+    // shading normal, perturbed by the material's bump pattern
     pub normalv: Tuple,
+    // true surface normal, used for the over_point and under_point offsets
+    pub geometric_normalv: Tuple,
+    // perpendicular to normalv, orients anisotropic highlights
+    pub tangentv: Tuple,
     pub inside: bool,
     pub reflectv: Tuple,
     pub n1: f64,
     pub n2: f64,
+    // object the ray travelled through to reach the hit (the one n1 comes from)
+    pub medium: Option<&'a Shape>,
+    pub wavelength: Option<f64>,
 }
 
 impl Intersection<'_> {
@@ -49,31 +66,51 @@ impl Intersection<'_> {
         &'a self,
         arena: &'a Arena,
         r: &Ray,
-        xs: &[&Intersection],
+        xs: &[&Intersection<'a>],
     ) -> PreparedComputations {
         let point = r.position(self.t);
         let eyev = -r.direction;
         let temp_normalv = self.object.normal_at(arena, point);
-        let (inside, normalv) = if temp_normalv.dot(&eyev) < 0. {
+        let (inside, geometric_normalv) = if temp_normalv.dot(&eyev) < 0. {
             (true, -temp_normalv)
         } else {
             (false, temp_normalv)
         };
-        let under_point = point - normalv * EPSILON;
-        let over_point = point + normalv * EPSILON;
+        let under_point = point - geometric_normalv * EPSILON;
+        let over_point = point + geometric_normalv * EPSILON;
+        let normalv = self
+            .object
+            .material()
+            .bumped_normal(self.object, point, geometric_normalv);
         let reflectv = r.direction.reflect(normalv);
+        let material = self.object.material();
+        let tangentv = match material.model {
+            ShadingModel::Anisotropic { .. } => material.tangent(
+                self.object,
+                point,
+                normalv,
+                self.object.tangent_at(arena, point),
+            ),
+            _ => sampling::orthonormal_basis(normalv).0,
+        };
 
         // compute n1 and n2
+        let refractive_index = |object: &Shape| match r.wavelength {
+            Some(wavelength) => object.material().refractive_index_at(wavelength),
+            None => object.material().refractive_index,
+        };
         let mut n1 = 1.;
         let mut n2 = 1.;
+        let mut medium = None;
         let mut containers: Vec<&Shape> = vec![];
         for i in xs {
             let same = self == *i;
             if same {
+                medium = containers.last().copied();
                 if containers.is_empty() {
                     n1 = 1.;
                 } else {
-                    n1 = containers.last().unwrap().material().refractive_index;
+                    n1 = refractive_index(containers.last().unwrap());
                 }
             }
             match containers.iter().position(|&o| o == i.object) {
@@ -88,7 +125,7 @@ impl Intersection<'_> {
                 if containers.is_empty() {
                     n2 = 1.;
                 } else {
-                    n2 = containers.last().unwrap().material().refractive_index;
+                    n2 = refractive_index(containers.last().unwrap());
                 }
                 break;
             }
@@ -102,21 +139,55 @@ impl Intersection<'_> {
             over_point,
             eyev,
             normalv,
+            geometric_normalv,
+            tangentv,
             inside,
             reflectv,
             n1,
             n2,
+            medium,
+            wavelength: r.wavelength,
         }
     }
 }
 
 impl PreparedComputations<'_> {
+    // None under total internal reflection
+    pub fn refracted_direction(&self) -> Option<Tuple> {
+        // detect total internal reflection using Snell's Law
+        let n_ratio = self.n1 / self.n2;
+        // cos(theta_i) is the same as the dot product of the two vectors​
+        let cos_i = self.eyev.dot(&self.normalv);
+        // Find sin(theta_t)^2 via trigonometric identity​
+        let sin2_t = (n_ratio * n_ratio) * (1. - (cos_i * cos_i));
+        if sin2_t > 1. {
+            return None;
+        }
+
+        let cos_t = (1. - sin2_t).sqrt();
+        // Compute the direction of the refracted ray​
+        Some(self.normalv * (n_ratio * cos_i - cos_t) - self.eyev * n_ratio)
+    }
+
+    // Beer-Lambert attenuation along the ray segment that reached the hit
+    pub fn absorption(&self) -> Color {
+        match self.medium {
+            Some(medium) => medium.material().transmittance(self.t),
+            None => WHITE,
+        }
+    }
+
     pub fn schlick(&self) -> f64 {
+        self.schlick_between(self.n1, self.n2)
+    }
+
+    // Same as schlick, for an interface between other media (e.g. a clearcoat)
+    pub fn schlick_between(&self, n1: f64, n2: f64) -> f64 {
         //  find the cosine of the angle between the eye and normal vectors​
         let mut cos = self.eyev.dot(&self.normalv);
         // total internal reflection can only occur if n1 > n2​
-        if self.n1 > self.n2 {
-            let n_ratio = self.n1 / self.n2;
+        if n1 > n2 {
+            let n_ratio = n1 / n2;
             let sin2_t = (n_ratio * n_ratio) * (1. - (cos * cos));
             if sin2_t > 1. {
                 return 1.;
@@ -124,17 +195,46 @@ impl PreparedComputations<'_> {
             // when n1 > n2, use cos(theta_t) instead​
             cos = (1. - sin2_t).sqrt();
         }
-        let r0 = ((self.n1 - self.n2) / (self.n1 + self.n2)).powi(2);
+        let r0 = ((n1 - n2) / (n1 + n2)).powi(2);
         r0 + (1. - r0) * (1. - cos).powi(5)
     }
+
+    // Per channel reflectance of the clearcoat and thin-film layers of the material.
+    // None without layers, or when the ray is inside the object
+    pub fn layer_reflectance(&self) -> Option<Color> {
+        let material = self.object.material();
+        if !material.is_layered() || self.inside {
+            return None;
+        }
+        let mut reflectance = BLACK;
+        if let Some(coat) = material.clearcoat {
+            reflectance =
+                WHITE * (coat.strength * self.schlick_between(self.n1, coat.refractive_index));
+        }
+        if let Some(film) = material.thin_film {
+            // the film lies on top of the coat, if any
+            let below = material
+                .clearcoat
+                .map_or(material.refractive_index, |coat| coat.refractive_index);
+            let cos_i = self.eyev.dot(&self.normalv).max(0.);
+            let [r, g, b] = RGB_WAVELENGTHS.map(|nm| film.reflectance(self.n1, below, cos_i, nm));
+            let film = Color::new(r, g, b);
+            reflectance = reflectance + film - reflectance * film;
+        }
+        Some(reflectance)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::{
-        approx_eq, color::BLACK, material::MaterialBuilder, matrix::Matrix, point, ray, solid,
-        sphere, vector,
+        approx_eq, color,
+        color::BLACK,
+        gradient_pattern,
+        material::{Clearcoat, MaterialBuilder, ThinFilm},
+        matrix::Matrix,
+        plane, point, ray, solid, sphere, vector,
     };
 
     #[test]
@@ -302,6 +402,92 @@ mod tests {
         }
     }
 
+    #[test]
+    fn medium_is_the_enclosing_object() {
+        let arena = Arena::new();
+        let mut a = sphere!();
+        a.set_transform(Matrix::scaling(2, 2, 2));
+        let b = sphere!();
+        let r = ray!(0, 0, -4; 0, 0, 1);
+        let xs = [
+            Intersection::new(2, &a),
+            Intersection::new(3, &b),
+            Intersection::new(5, &b),
+            Intersection::new(6, &a),
+        ];
+        let xs_refs = xs.iter().collect::<Vec<&Intersection>>();
+        let media = xs
+            .iter()
+            .map(|i| i.prepare_computations(&arena, &r, &xs_refs[..]).medium)
+            .collect::<Vec<_>>();
+        assert_eq!(vec![None, Some(&a), Some(&b), Some(&a)], media);
+    }
+
+    #[test]
+    fn absorption_over_the_distance_travelled_inside() {
+        let arena = Arena::new();
+        let mut s = sphere!();
+        s.set_material(
+            MaterialBuilder::default()
+                .transparency(1)
+                .absorption(color!(1, 0.5, 0.5))
+                .density(0.5)
+                .build()
+                .unwrap(),
+        );
+        // refracted rays start on the surface, so t is the distance travelled inside
+        let r = ray!(0, 0, -1; 0, 0, 1);
+        let xs = [Intersection::new(0, &s), Intersection::new(2, &s)];
+        let xs_refs = xs.iter().collect::<Vec<&Intersection>>();
+        let entering = xs[0].prepare_computations(&arena, &r, &xs_refs[..]);
+        assert_eq!(WHITE, entering.absorption());
+        let exiting = xs[1].prepare_computations(&arena, &r, &xs_refs[..]);
+        assert_eq!(color!(1, 0.5, 0.5), exiting.absorption());
+    }
+
+    #[test]
+    fn refractive_index_for_the_ray_wavelength() {
+        let arena = Arena::new();
+        let mut s = sphere!();
+        let flint = MaterialBuilder::default()
+            .transparency(1)
+            .refractive_index(1.62)
+            .abbe_number(36.)
+            .build()
+            .unwrap();
+        s.set_material(flint);
+        let xs = [Intersection::new(4, &s), Intersection::new(6, &s)];
+        let xs_refs = xs.iter().collect::<Vec<&Intersection>>();
+
+        let r = ray!(0, 0, -5; 0, 0, 1);
+        let comps = xs[0].prepare_computations(&arena, &r, &xs_refs[..]);
+        assert_eq!(1.62, comps.n2);
+
+        let r = ray!(0, 0, -5; 0, 0, 1).with_wavelength(Some(450.));
+        let comps = xs[0].prepare_computations(&arena, &r, &xs_refs[..]);
+        assert_eq!(Some(450.), comps.wavelength);
+        assert_eq!(flint.refractive_index_at(450.), comps.n2);
+        let comps = xs[1].prepare_computations(&arena, &r, &xs_refs[..]);
+        assert_eq!(flint.refractive_index_at(450.), comps.n1);
+    }
+
+    #[test]
+    fn bump_patterns_only_change_the_shading_normal() {
+        let arena = Arena::new();
+        let mut ramp = gradient_pattern!(BLACK, WHITE);
+        ramp.set_transform(Matrix::scaling(10, 1, 1));
+        let mut floor = plane!();
+        floor.set_material(MaterialBuilder::default().bump(ramp).build().unwrap());
+        let r = ray!(0.3, 1, 0; 0, -1, 0);
+        let i = Intersection::new(1, &floor);
+        let comps = i.prepare_computations(&arena, &r, &[&i]);
+        assert_eq!(vector!(0, 1, 0), comps.geometric_normalv);
+        assert_eq!(vector!(-0.1, 1, 0).normalize(), comps.normalv);
+        assert_eq!(point!(0.3, EPSILON, 0), comps.over_point);
+        assert_eq!(point!(0.3, -EPSILON, 0), comps.under_point);
+        assert_eq!(r.direction.reflect(comps.normalv), comps.reflectv);
+    }
+
     #[test]
     fn under_point_is_below_surface() {
         let arena = Arena::new();
@@ -372,4 +558,68 @@ mod tests {
         let reflectance = comps.schlick();
         assert!(approx_eq(0.48873, dbg!(reflectance)));
     }
+
+    #[test]
+    fn layer_reflectance() {
+        let arena = Arena::new();
+        let r = ray!(0, 0, -5; 0, 0, 1);
+        let plain = sphere!();
+        let i = Intersection::new(4, &plain);
+        assert_eq!(
+            None,
+            i.prepare_computations(&arena, &r, &[&i])
+                .layer_reflectance()
+        );
+
+        let mut coated = sphere!();
+        coated.set_material(
+            MaterialBuilder::default()
+                .clearcoat(Clearcoat::default())
+                .build()
+                .unwrap(),
+        );
+        let i = Intersection::new(4, &coated);
+        let comps = i.prepare_computations(&arena, &r, &[&i]);
+        // 4% at normal incidence for an index of 1.5
+        assert_eq!(Some(color!(0.04, 0.04, 0.04)), comps.layer_reflectance());
+        // no layers seen from inside
+        let exit = Intersection::new(6, &coated);
+        let comps = exit.prepare_computations(&arena, &r, &[&i, &exit]);
+        assert_eq!(None, comps.layer_reflectance());
+
+        let mut bubble = sphere!();
+        bubble.set_material(
+            MaterialBuilder::default()
+                .thin_film(ThinFilm::default())
+                .build()
+                .unwrap(),
+        );
+        let i = Intersection::new(4, &bubble);
+        let film = i
+            .prepare_computations(&arena, &r, &[&i])
+            .layer_reflectance()
+            .unwrap();
+        // interference colors, channels differ
+        assert!(film.r != film.g && film.g != film.b);
+    }
+
+    #[test]
+    fn tangent_for_anisotropic_materials() {
+        let arena = Arena::new();
+        let mut shape = sphere!();
+        shape.set_material(
+            MaterialBuilder::default()
+                .model(ShadingModel::Anisotropic {
+                    roughness_u: 0.2,
+                    roughness_v: 0.05,
+                })
+                .build()
+                .unwrap(),
+        );
+        let r = ray!(0, 0, -5; 0, 0, 1);
+        let i = Intersection::new(4, &shape);
+        let comps = i.prepare_computations(&arena, &r, &[&i]);
+        assert_eq!(vector!(-1, 0, 0), comps.tangentv);
+        assert!(approx_eq(0., comps.tangentv.dot(&comps.normalv)));
+    }
 }
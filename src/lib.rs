@@ -2,22 +2,35 @@
 extern crate derive_builder;
 
 pub mod arena;
+pub mod background;
 pub mod bounds;
 pub mod camera;
 pub mod canvas;
 pub mod color;
+pub mod distributed;
+pub mod environment;
+pub mod ies;
+pub mod integrator;
 pub mod intersection;
 pub mod light;
 pub mod material;
+pub mod material_library;
 pub mod matrix;
+pub mod media;
+pub mod occlusion;
 pub mod patterns;
+pub mod photon;
 pub mod ray;
+pub mod sampling;
 pub mod shapes;
+pub mod sky;
+pub mod spectrum;
+pub mod stats;
+pub mod stereo;
 pub mod transformations;
 pub mod tuple;
 pub mod world;
 
-pub const MAX_REFLECTION_RECURSION: usize = 5;
 pub const EPSILON: f64 = 0.00001;
 
 pub fn approx_eq(a: f64, b: f64) -> bool {
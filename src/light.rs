@@ -1,5 +1,17 @@
-use crate::{color::Color, tuple::Tuple};
+use serde::{Deserialize, Serialize};
 
+use std::f64::consts::PI;
+
+use crate::{
+    canvas::Canvas,
+    color::{Color, BLACK},
+    ies::IesProfile,
+    patterns::Pattern,
+    point, sampling,
+    tuple::Tuple,
+};
+
+#[derive(Serialize, Deserialize)]
 pub struct PointLight {
     pub position: Tuple,
     pub intensity: Color,
@@ -14,10 +26,117 @@ impl PointLight {
     }
 }
 
+// Light from a point into a cone around `direction`: full intensity within
+// `inner_angle` of the axis, fading out to nothing at `outer_angle` (half angles,
+// in radians). A gobo projects a pattern or an image through the cone and an
+// IES profile shapes the intensity by direction.
+// Both are oriented by sampling::orthonormal_basis of the direction: its first
+// vector is the gobo's u axis and the profile's 0 degrees horizontal angle
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SpotLight {
+    pub position: Tuple,
+    pub direction: Tuple,
+    pub intensity: Color,
+    pub inner_angle: f64,
+    pub outer_angle: f64,
+    pub gobo: Option<Gobo>,
+    pub profile: Option<IesProfile>,
+}
+
+// What a spot light projects. Both span [-1, 1] in u and v across the outer cone
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Gobo {
+    // sampled at (u, 0, v) through the pattern transform
+    Pattern(Pattern),
+    // top row toward +v. An empty image projects nothing
+    Image(Canvas),
+}
+
+impl Gobo {
+    pub fn color_at(&self, u: f64, v: f64) -> Color {
+        match self {
+            Gobo::Pattern(pattern) => pattern.color_at(point!(u, 0, v)),
+            Gobo::Image(image) if image.width == 0 || image.height == 0 => BLACK,
+            Gobo::Image(image) => {
+                let to_pixel =
+                    |t: f64, size: usize| ((t.clamp(0., 1.) * size as f64) as usize).min(size - 1);
+                let x = to_pixel((u + 1.) / 2., image.width);
+                let y = to_pixel((1. - v) / 2., image.height);
+                image.pixel_at(x, y)
+            }
+        }
+    }
+}
+
+impl SpotLight {
+    pub fn new(
+        position: Tuple,
+        direction: Tuple,
+        intensity: Color,
+        inner_angle: f64,
+        outer_angle: f64,
+    ) -> Self {
+        Self {
+            position,
+            direction,
+            intensity,
+            inner_angle,
+            outer_angle,
+            gobo: None,
+            profile: None,
+        }
+    }
+
+    // Intensity sent toward the point, black outside the cone
+    pub fn intensity_toward(&self, point: Tuple) -> Color {
+        let axis = self.direction.normalize();
+        let to_point = point - self.position;
+        // no direction toward the light's own position
+        if to_point.magnitude() == 0. {
+            return BLACK;
+        }
+        let to_point = to_point.normalize();
+        let cos = to_point.dot(&axis);
+        let cos_outer = self.outer_angle.cos();
+        let cos_inner = self.inner_angle.min(self.outer_angle).cos();
+        if cos <= cos_outer {
+            return BLACK;
+        }
+        let falloff = if cos >= cos_inner {
+            1.
+        } else {
+            let t = (cos - cos_outer) / (cos_inner - cos_outer);
+            t * t * (3. - 2. * t)
+        };
+        let mut color = self.intensity * falloff;
+
+        let (u_axis, v_axis) = sampling::orthonormal_basis(axis);
+        let (x, y) = (to_point.dot(&u_axis), to_point.dot(&v_axis));
+        if let Some(gobo) = &self.gobo {
+            // on the plane one unit along the axis, scaled to the outer cone
+            let extent = self.outer_angle.min(PI / 2. - 0.01).tan();
+            let distance = cos.max(1e-6);
+            color = color * gobo.color_at(x / distance / extent, y / distance / extent);
+        }
+        if let Some(profile) = &self.profile {
+            let vertical = cos.clamp(-1., 1.).acos().to_degrees();
+            let horizontal = y.atan2(x).to_degrees();
+            color = color * profile.relative_intensity(vertical, horizontal);
+        }
+        color
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{color::WHITE, point};
+    use crate::{
+        approx_eq, color,
+        color::{RED, WHITE},
+        matrix::Matrix,
+        patterns::StripePattern,
+        vector,
+    };
 
     #[test]
     fn point_light_has_position_and_intensity() {
@@ -27,4 +146,61 @@ mod tests {
         assert_eq!(position, light.position);
         assert_eq!(intensity, light.intensity);
     }
+
+    fn spot_down() -> SpotLight {
+        SpotLight::new(point!(0, 1, 0), vector!(0, -1, 0), WHITE, PI / 8., PI / 4.)
+    }
+
+    #[test]
+    fn spot_light_cone_falloff() {
+        let spot = spot_down();
+        assert_eq!(WHITE, spot.intensity_toward(point!(0, 0, 0)));
+        assert_eq!(WHITE, spot.intensity_toward(point!(0.3, 0, 0)));
+        // past the outer cone and behind the light
+        assert_eq!(BLACK, spot.intensity_toward(point!(1.5, 0, 0)));
+        assert_eq!(BLACK, spot.intensity_toward(point!(0, 2, 0)));
+        // smoothly between the cones
+        let between =
+            spot.intensity_toward(point!(0, 1, 0) + vector!((PI * 3. / 16.).tan(), -1, 0));
+        assert!(between.r > 0.2 && between.r < 0.8);
+    }
+
+    #[test]
+    fn spot_light_projects_a_pattern() {
+        let mut spot = spot_down();
+        let mut stripes = Pattern::Stripes(StripePattern::new(WHITE, RED));
+        stripes.set_transform(Matrix::scaling(0.25, 1, 1));
+        spot.gobo = Some(Gobo::Pattern(stripes));
+        let (u_axis, _) = sampling::orthonormal_basis(vector!(0, -1, 0));
+        // within the inner cone: u in [0, 0.25) is white, [0.25, 0.5) is red
+        assert_eq!(WHITE, spot.intensity_toward(point!(0, 0, 0) + u_axis * 0.2));
+        assert_eq!(RED, spot.intensity_toward(point!(0, 0, 0) + u_axis * 0.3));
+    }
+
+    #[test]
+    fn spot_light_projects_an_image() {
+        let mut image = Canvas::new(2, 2);
+        image.write_pixel(0, 0, RED);
+        image.write_pixel(1, 0, WHITE);
+        image.write_pixel(0, 1, color!(0, 0, 1));
+        image.write_pixel(1, 1, color!(0, 1, 0));
+        let gobo = Gobo::Image(image);
+        assert_eq!(RED, gobo.color_at(-0.5, 0.5));
+        assert_eq!(WHITE, gobo.color_at(0.5, 0.5));
+        assert_eq!(color!(0, 1, 0), gobo.color_at(0.5, -0.5));
+        assert_eq!(BLACK, Gobo::Image(Canvas::new(0, 0)).color_at(0., 0.));
+    }
+
+    #[test]
+    fn spot_light_shaped_by_ies_profile() {
+        let mut spot = spot_down();
+        spot.outer_angle = PI / 2.;
+        spot.inner_angle = PI / 2.;
+        spot.profile =
+            Some(IesProfile::new(vec![0., 90.], vec![0.], vec![vec![100., 0.]]).unwrap());
+        assert_eq!(WHITE, spot.intensity_toward(point!(0, 0, 0)));
+        assert_eq!(BLACK, spot.intensity_toward(spot.position));
+        let sideways = spot.intensity_toward(point!(1, 0, 0));
+        assert!(approx_eq(0.5, sideways.r));
+    }
 }
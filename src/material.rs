@@ -1,12 +1,28 @@
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+
 use crate::{
     color::{Color, BLACK, WHITE},
     light::PointLight,
+    media::Volume,
     patterns::Pattern,
+    sampling,
     shapes::Shape,
     solid,
+    spectrum::{FRAUNHOFER_C, FRAUNHOFER_D, FRAUNHOFER_F},
     tuple::Tuple,
+    vector, EPSILON,
 };
 
+// finite differences step for bump patterns
+const BUMP_DELTA: f64 = 0.0001;
+// sharpness of the light's highlight mirrored by clearcoat and thin-film layers
+const LAYER_SHININESS: f64 = 300.;
+// wavelengths (nm) standing for the red, green and blue channels in thin films
+pub const RGB_WAVELENGTHS: [f64; 3] = [650., 532., 450.];
+// how close to the silhouette (1 - eye·normal) the toon rim light starts
+const TOON_RIM_WIDTH: f64 = 0.3;
+
 const DEFAULT_MATERIAL: Material = Material {
     pattern: solid!(WHITE),
     ambient: 0.1,
@@ -16,9 +32,108 @@ const DEFAULT_MATERIAL: Material = Material {
     reflective: 0.,
     transparency: 0.,
     refractive_index: 1.,
+    model: ShadingModel::Phong,
+    metallic: 0.,
+    roughness: 0.5,
+    absorption: WHITE,
+    density: 0.,
+    shadow: ShadowMode::Transmissive,
+    emission: BLACK,
+    emission_strength: 1.,
+    abbe_number: 0.,
+    bump: None,
+    bump_strength: 1.,
+    reflection_roughness: 0.,
+    refraction_roughness: 0.,
+    volume: None,
+    tangent_field: None,
+    clearcoat: None,
+    thin_film: None,
 };
 
-#[derive(Copy, Clone, Debug, PartialEq, Builder)]
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ShadingModel {
+    // uses ambient, diffuse, specular and shininess
+    Phong,
+    // metallic/roughness workflow: GGX distribution, Smith shadowing and Schlick Fresnel,
+    // with the pattern as base color (ambient is still applied)
+    Pbr,
+    // Phong with the highlight from the half vector, wider for the same shininess
+    BlinnPhong,
+    // rough matte diffuse (clay, plaster) with sigma the deviation of the microfacet
+    // slopes in radians, 0 is lambertian. No specular term
+    OrenNayar { sigma: f64 },
+    // cel shading: the diffuse term quantized in bands, a hard-edged highlight
+    // and a rim light of the given strength on the lit silhouette
+    Toon { bands: u32, rim: f64 },
+    // Ward's anisotropic highlight (e.g. brushed metal, hair) with separate roughness along
+    // the tangent and the bitangent, plus the Phong diffuse term. See Material::tangent
+    Anisotropic { roughness_u: f64, roughness_v: f64 },
+}
+
+// How shadow rays pass through objects with transparency > 0
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ShadowMode {
+    // light is scaled by the transparency
+    Transmissive,
+    // like Transmissive, also filtered by the surface color
+    Tinted,
+    // always blocks the light, regardless of transparency
+    Hard,
+}
+
+// Clear varnish over the base, e.g. car paint. Reflects by its own Fresnel term
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Clearcoat {
+    // 0 disables the layer, 1 reflects the full Fresnel amount
+    pub strength: f64,
+    pub refractive_index: f64,
+}
+
+impl Default for Clearcoat {
+    fn default() -> Self {
+        Self {
+            strength: 1.,
+            refractive_index: 1.5,
+        }
+    }
+}
+
+// Transparent film (soap, oil) whose two reflections interfere, giving colors
+// that change with its thickness and the view angle
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ThinFilm {
+    // in nanometers
+    pub thickness: f64,
+    pub refractive_index: f64,
+}
+
+impl Default for ThinFilm {
+    fn default() -> Self {
+        Self {
+            thickness: 400.,
+            refractive_index: 1.33,
+        }
+    }
+}
+
+impl ThinFilm {
+    // Airy reflectance of the film between the outside (n1) and what is under it (n3),
+    // for light arriving at cos_i from the normal. Uses the normal incidence amplitudes
+    pub fn reflectance(&self, n1: f64, n3: f64, cos_i: f64, wavelength: f64) -> f64 {
+        let nf = self.refractive_index;
+        let sin2_t = (n1 / nf).powi(2) * (1. - cos_i * cos_i);
+        let cos_t = (1. - sin2_t).max(0.).sqrt();
+        // phase difference of the light reflected under the film
+        let delta = 4. * PI * nf * self.thickness * cos_t / wavelength;
+        let r1 = (n1 - nf) / (n1 + nf);
+        let r2 = (nf - n3) / (nf + n3);
+        let interference = 2. * r1 * r2 * delta.cos();
+        (r1 * r1 + r2 * r2 + interference) / (1. + r1 * r1 * r2 * r2 + interference)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Builder, Serialize, Deserialize)]
 #[builder(default)]
 #[builder(setter(into))]
 pub struct Material {
@@ -30,6 +145,35 @@ pub struct Material {
     pub reflective: f64,
     pub transparency: f64,
     pub refractive_index: f64,
+    pub model: ShadingModel,
+    pub metallic: f64,
+    pub roughness: f64,
+    // Beer-Lambert absorption for light travelling inside the object:
+    // `absorption` is the color left after 1 / density units
+    pub absorption: Color,
+    pub density: f64,
+    pub shadow: ShadowMode,
+    // radiance added regardless of lights. Emissive shapes also light the scene
+    pub emission: Color,
+    pub emission_strength: f64,
+    // dispersion of transparent materials, lower is stronger (e.g. 64 for crown glass,
+    // 55 for diamond). Zero disables it
+    pub abbe_number: f64,
+    // height pattern (brightness) that perturbs the shading normal
+    pub bump: Option<Pattern>,
+    pub bump_strength: f64,
+    // spread of reflected and refracted rays (glossy reflection, frosted glass):
+    // 0 traces the perfect direction, 1 spreads them over a hemisphere
+    pub reflection_roughness: f64,
+    pub refraction_roughness: f64,
+    // makes the shape a participating medium instead of a surface
+    pub volume: Option<Volume>,
+    // direction field for anisotropic shading, with the colors mapped from [0, 1] to [-1, 1]
+    // on each axis. Replaces the tangents derived from the shape
+    pub tangent_field: Option<Pattern>,
+    // reflective layers over the base shading model, seen from outside the object
+    pub clearcoat: Option<Clearcoat>,
+    pub thin_film: Option<ThinFilm>,
 }
 
 impl Material {
@@ -52,6 +196,7 @@ impl Material {
             reflective,
             transparency,
             refractive_index,
+            ..DEFAULT_MATERIAL
         }
     }
 
@@ -63,37 +208,366 @@ impl Material {
         eyev: Tuple,
         normalv: Tuple,
         in_shadow: bool,
+    ) -> Color {
+        let transmittance = if in_shadow { BLACK } else { WHITE };
+        self.lightning_with_transmittance(object, light, point, eyev, normalv, transmittance)
+    }
+
+    // Same as lightning, with the diffuse and specular terms scaled by the
+    // fraction of the light that reaches the point (see World::shadow_transmittance)
+    pub fn lightning_with_transmittance(
+        &self,
+        object: &Shape,
+        light: &PointLight,
+        point: Tuple,
+        eyev: Tuple,
+        normalv: Tuple,
+        transmittance: Color,
+    ) -> Color {
+        let tangentv = sampling::orthonormal_basis(normalv).0;
+        self.lightning_with_tangent(object, light, point, eyev, normalv, tangentv, transmittance)
+    }
+
+    // Same as lightning_with_transmittance, with the tangent that orients anisotropic
+    // highlights (see PreparedComputations::tangentv)
+    #[allow(clippy::too_many_arguments)]
+    pub fn lightning_with_tangent(
+        &self,
+        object: &Shape,
+        light: &PointLight,
+        point: Tuple,
+        eyev: Tuple,
+        normalv: Tuple,
+        tangentv: Tuple,
+        transmittance: Color,
     ) -> Color {
         let color = self.pattern.color_at_object(object, point);
         let effective_color = color * light.intensity;
 
         let ambient = effective_color * self.ambient;
-        if in_shadow {
+        if transmittance == BLACK {
             return ambient;
         }
 
         let lightv = (light.position - point).normalize();
         let light_dot_normal = lightv.dot(&normalv);
 
-        let diffuse;
-        let specular;
-        if light_dot_normal < 0. {
-            diffuse = BLACK;
-            specular = BLACK;
+        let (diffuse, specular) = if light_dot_normal < 0. {
+            (BLACK, BLACK)
         } else {
-            diffuse = effective_color * self.diffuse * light_dot_normal;
-
-            let reflectv = (-lightv).reflect(normalv);
-            let reflect_dot_eye = reflectv.dot(&eyev);
-            if reflect_dot_eye <= 0. {
-                specular = BLACK;
-            } else {
-                let factor = reflect_dot_eye.powf(self.shininess);
-                specular = light.intensity * self.specular * factor;
+            match self.model {
+                ShadingModel::Phong => {
+                    self.phong(effective_color, light.intensity, lightv, eyev, normalv)
+                }
+                ShadingModel::Pbr => self.pbr(color, light.intensity, lightv, eyev, normalv),
+                ShadingModel::BlinnPhong => {
+                    self.blinn_phong(effective_color, light.intensity, lightv, eyev, normalv)
+                }
+                ShadingModel::OrenNayar { sigma } => (
+                    self.oren_nayar(effective_color, lightv, eyev, normalv, sigma),
+                    BLACK,
+                ),
+                ShadingModel::Anisotropic {
+                    roughness_u,
+                    roughness_v,
+                } => {
+                    let diffuse = effective_color * self.diffuse * light_dot_normal;
+                    let ward = self.ward(lightv, eyev, normalv, tangentv, roughness_u, roughness_v);
+                    (diffuse, light.intensity * ward)
+                }
+                ShadingModel::Toon { bands, rim } => self.toon(
+                    effective_color,
+                    light.intensity,
+                    lightv,
+                    eyev,
+                    normalv,
+                    bands,
+                    rim,
+                ),
             }
+        };
+
+        ambient + (diffuse + specular) * transmittance
+    }
+
+    // Tilts the normal against the slope of the bump pattern, estimated with central differences
+    pub fn bumped_normal(&self, object: &Shape, point: Tuple, normalv: Tuple) -> Tuple {
+        let bump = match self.bump {
+            Some(bump) => bump,
+            None => return normalv,
+        };
+        let height = |p: Tuple| {
+            let c = bump.color_at_object(object, p);
+            (c.r + c.g + c.b) / 3.
+        };
+        let slope =
+            |delta: Tuple| (height(point + delta) - height(point - delta)) / (2. * BUMP_DELTA);
+        let gradient = vector!(
+            slope(vector!(BUMP_DELTA, 0, 0)),
+            slope(vector!(0, BUMP_DELTA, 0)),
+            slope(vector!(0, 0, BUMP_DELTA))
+        );
+        // only the slope along the surface tilts the normal
+        let tangential = gradient - normalv * gradient.dot(&normalv);
+        (normalv - tangential * self.bump_strength).normalize()
+    }
+
+    pub fn emitted(&self) -> Color {
+        self.emission * self.emission_strength
+    }
+
+    pub fn is_emissive(&self) -> bool {
+        self.emission_strength > 0. && self.emission != BLACK
+    }
+
+    pub fn is_layered(&self) -> bool {
+        self.clearcoat.is_some() || self.thin_film.is_some()
+    }
+
+    // Highlight of the light mirrored by the clearcoat and thin-film layers,
+    // before their reflectance is applied
+    pub fn layer_highlight(
+        &self,
+        light: &PointLight,
+        point: Tuple,
+        eyev: Tuple,
+        normalv: Tuple,
+    ) -> Color {
+        let lightv = (light.position - point).normalize();
+        if lightv.dot(&normalv) < 0. {
+            return BLACK;
+        }
+        let reflect_dot_eye = (-lightv).reflect(normalv).dot(&eyev);
+        if reflect_dot_eye <= 0. {
+            BLACK
+        } else {
+            light.intensity * reflect_dot_eye.powf(LAYER_SHININESS)
         }
+    }
 
-        ambient + diffuse + specular
+    pub fn is_volume(&self) -> bool {
+        self.volume.is_some()
+    }
+
+    pub fn is_dispersive(&self) -> bool {
+        self.abbe_number > 0. && self.transparency > 0.
+    }
+
+    // Cauchy's equation n = A + B / λ², fitted to refractive_index at the
+    // Fraunhofer d line and the Abbe number. Wavelength in nanometers
+    pub fn refractive_index_at(&self, wavelength: f64) -> f64 {
+        if self.abbe_number <= 0. {
+            return self.refractive_index;
+        }
+        let inv_sq = |nm: f64| (1000. / nm).powi(2);
+        let b = (self.refractive_index - 1.)
+            / (self.abbe_number * (inv_sq(FRAUNHOFER_F) - inv_sq(FRAUNHOFER_C)));
+        self.refractive_index + b * (inv_sq(wavelength) - inv_sq(FRAUNHOFER_D))
+    }
+
+    // Fraction of the light that survives travelling the given distance inside the object
+    pub fn transmittance(&self, distance: f64) -> Color {
+        if self.density == 0. {
+            return WHITE;
+        }
+        let optical_depth = self.density * distance;
+        Color::new(
+            self.absorption.r.powf(optical_depth),
+            self.absorption.g.powf(optical_depth),
+            self.absorption.b.powf(optical_depth),
+        )
+    }
+
+    fn phong(
+        &self,
+        effective_color: Color,
+        intensity: Color,
+        lightv: Tuple,
+        eyev: Tuple,
+        normalv: Tuple,
+    ) -> (Color, Color) {
+        let diffuse = effective_color * self.diffuse * lightv.dot(&normalv);
+
+        let reflectv = (-lightv).reflect(normalv);
+        let reflect_dot_eye = reflectv.dot(&eyev);
+        let specular = if reflect_dot_eye <= 0. {
+            BLACK
+        } else {
+            let factor = reflect_dot_eye.powf(self.shininess);
+            intensity * self.specular * factor
+        };
+        (diffuse, specular)
+    }
+
+    fn blinn_phong(
+        &self,
+        effective_color: Color,
+        intensity: Color,
+        lightv: Tuple,
+        eyev: Tuple,
+        normalv: Tuple,
+    ) -> (Color, Color) {
+        let diffuse = effective_color * self.diffuse * lightv.dot(&normalv);
+
+        let halfv = (lightv + eyev).normalize();
+        let half_dot_normal = halfv.dot(&normalv);
+        let specular = if half_dot_normal <= 0. {
+            BLACK
+        } else {
+            intensity * self.specular * half_dot_normal.powf(self.shininess)
+        };
+        (diffuse, specular)
+    }
+
+    // Qualitative model from Oren and Nayar's "Generalization of Lambert's reflectance model"
+    fn oren_nayar(
+        &self,
+        effective_color: Color,
+        lightv: Tuple,
+        eyev: Tuple,
+        normalv: Tuple,
+        sigma: f64,
+    ) -> Color {
+        let n_dot_l = lightv.dot(&normalv);
+        let n_dot_v = eyev.dot(&normalv).max(0.);
+        let sigma2 = sigma * sigma;
+        let a = 1. - 0.5 * sigma2 / (sigma2 + 0.33);
+        let b = 0.45 * sigma2 / (sigma2 + 0.09);
+
+        let theta_l = n_dot_l.min(1.).acos();
+        let theta_v = n_dot_v.min(1.).acos();
+        let (alpha, beta) = (theta_l.max(theta_v), theta_l.min(theta_v));
+        // cosine of the azimuth between light and eye, projected on the surface
+        let project = |v: Tuple| v - normalv * v.dot(&normalv);
+        let (light_t, eye_t) = (project(lightv), project(eyev));
+        let cos_phi = if light_t.magnitude() < 1e-6 || eye_t.magnitude() < 1e-6 {
+            0.
+        } else {
+            light_t.normalize().dot(&eye_t.normalize())
+        };
+
+        let factor = a + b * cos_phi.max(0.) * alpha.sin() * beta.tan();
+        effective_color * self.diffuse * n_dot_l * factor
+    }
+
+    // Ward's anisotropic model, as a fraction of the light's intensity. Like the PBR
+    // model, the BRDF is multiplied by PI * cos(theta)
+    fn ward(
+        &self,
+        lightv: Tuple,
+        eyev: Tuple,
+        normalv: Tuple,
+        tangentv: Tuple,
+        roughness_u: f64,
+        roughness_v: f64,
+    ) -> f64 {
+        let n_dot_l = lightv.dot(&normalv);
+        let n_dot_v = eyev.dot(&normalv);
+        if n_dot_l <= 0. || n_dot_v <= 0. {
+            return 0.;
+        }
+        let bitangentv = normalv.cross(&tangentv);
+        let (alpha_u, alpha_v) = (roughness_u.max(0.001), roughness_v.max(0.001));
+        let halfv = (lightv + eyev).normalize();
+        let h_dot_n = halfv.dot(&normalv);
+        let exponent = -((halfv.dot(&tangentv) / alpha_u).powi(2)
+            + (halfv.dot(&bitangentv) / alpha_v).powi(2))
+            / (h_dot_n * h_dot_n);
+        let brdf = exponent.exp() / (4. * PI * alpha_u * alpha_v * (n_dot_l * n_dot_v).sqrt());
+        self.specular * brdf * PI * n_dot_l
+    }
+
+    // Tangent for anisotropic shading at a point: the tangent field's direction when there
+    // is one, otherwise the shape's, made perpendicular to the shading normal
+    pub fn tangent(
+        &self,
+        object: &Shape,
+        point: Tuple,
+        normalv: Tuple,
+        shape_tangent: Tuple,
+    ) -> Tuple {
+        let tangent = match self.tangent_field {
+            Some(field) => {
+                let c = field.color_at_object(object, point);
+                vector!(c.r * 2. - 1., c.g * 2. - 1., c.b * 2. - 1.)
+            }
+            None => shape_tangent,
+        };
+        let tangent = tangent - normalv * tangent.dot(&normalv);
+        if tangent.magnitude() < EPSILON {
+            // parallel to the normal, any direction will do
+            sampling::orthonormal_basis(normalv).0
+        } else {
+            tangent.normalize()
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn toon(
+        &self,
+        effective_color: Color,
+        intensity: Color,
+        lightv: Tuple,
+        eyev: Tuple,
+        normalv: Tuple,
+        bands: u32,
+        rim: f64,
+    ) -> (Color, Color) {
+        let bands = bands.max(1) as f64;
+        let n_dot_l = lightv.dot(&normalv);
+        let level = (n_dot_l * bands).ceil() / bands;
+        let diffuse = effective_color * self.diffuse * level;
+
+        let halfv = (lightv + eyev).normalize();
+        let highlight = halfv.dot(&normalv).max(0.).powf(self.shininess);
+        let mut specular = if highlight > 0.5 {
+            intensity * self.specular
+        } else {
+            BLACK
+        };
+        let edge = 1. - eyev.dot(&normalv).max(0.);
+        if rim > 0. && edge > 1. - TOON_RIM_WIDTH {
+            specular = specular + intensity * rim;
+        }
+        (diffuse, specular)
+    }
+
+    // Cook-Torrance. The light intensity is treated as the irradiance at normal incidence,
+    // so a white lambertian surface lit head-on has the same brightness as with Phong
+    fn pbr(
+        &self,
+        base_color: Color,
+        intensity: Color,
+        lightv: Tuple,
+        eyev: Tuple,
+        normalv: Tuple,
+    ) -> (Color, Color) {
+        let n_dot_l = lightv.dot(&normalv);
+        let n_dot_v = eyev.dot(&normalv).max(0.0001);
+        let halfv = (lightv + eyev).normalize();
+        let n_dot_h = halfv.dot(&normalv).max(0.);
+        let v_dot_h = halfv.dot(&eyev).max(0.);
+
+        // GGX / Trowbridge-Reitz normal distribution
+        let alpha = (self.roughness * self.roughness).max(0.001);
+        let alpha2 = alpha * alpha;
+        let denom = n_dot_h * n_dot_h * (alpha2 - 1.) + 1.;
+        let distribution = alpha2 / (PI * denom * denom);
+
+        // Smith shadowing with the Schlick-GGX approximation
+        let k = (self.roughness + 1.).powi(2) / 8.;
+        let g1 = |n_dot_x: f64| n_dot_x / (n_dot_x * (1. - k) + k);
+        let geometry = g1(n_dot_v) * g1(n_dot_l);
+
+        // Schlick Fresnel, dielectrics reflect 4% at normal incidence
+        let f0 = WHITE * 0.04 * (1. - self.metallic) + base_color * self.metallic;
+        let fresnel = f0 + (WHITE - f0) * (1. - v_dot_h).powi(5);
+
+        let specular = fresnel * (distribution * geometry / (4. * n_dot_v));
+        let diffuse = (WHITE - fresnel) * (1. - self.metallic) * base_color;
+
+        // the BRDF is multiplied by PI * irradiance * cos(theta)
+        (diffuse * intensity * n_dot_l, specular * intensity * PI)
     }
 }
 
@@ -107,8 +581,8 @@ impl Default for Material {
 mod tests {
     use super::*;
     use crate::{
-        arena::Arena, color, color::GREEN, intersection::Intersection, plane, point, ray, sphere,
-        stripe_pattern, vector,
+        approx_eq, arena::Arena, color, color::GREEN, gradient_pattern, intersection::Intersection,
+        matrix::Matrix, plane, point, ray, sphere, stripe_pattern, vector,
     };
 
     #[test]
@@ -126,6 +600,81 @@ mod tests {
         assert_eq!(0.1, DEFAULT_MATERIAL.ambient);
     }
 
+    #[test]
+    fn transmittance_depends_on_distance() {
+        let m = MaterialBuilder::default()
+            .absorption(color!(0.5, 1, 0.25))
+            .density(2.)
+            .build()
+            .unwrap();
+        assert_eq!(WHITE, m.transmittance(0.));
+        assert_eq!(color!(0.5, 1, 0.25), m.transmittance(0.5));
+        assert_eq!(color!(0.25, 1, 0.0625), m.transmittance(1.));
+        assert_eq!(WHITE, Material::default().transmittance(10.));
+    }
+
+    #[test]
+    fn emission() {
+        assert!(!Material::default().is_emissive());
+        let m = MaterialBuilder::default()
+            .emission(color!(1, 0.5, 0))
+            .emission_strength(4.)
+            .build()
+            .unwrap();
+        assert!(m.is_emissive());
+        assert_eq!(color!(4, 2, 0), m.emitted());
+    }
+
+    #[test]
+    fn dispersion_from_abbe_number() {
+        let flint = MaterialBuilder::default()
+            .transparency(1)
+            .refractive_index(1.62)
+            .abbe_number(36.)
+            .build()
+            .unwrap();
+        assert!(flint.is_dispersive());
+        let n_c = flint.refractive_index_at(FRAUNHOFER_C);
+        let n_d = flint.refractive_index_at(FRAUNHOFER_D);
+        let n_f = flint.refractive_index_at(FRAUNHOFER_F);
+        assert!(approx_eq(1.62, n_d));
+        assert!(approx_eq(36., (n_d - 1.) / (n_f - n_c)));
+        // blue bends more than red
+        assert!(flint.refractive_index_at(450.) > flint.refractive_index_at(650.));
+
+        let glass = Material {
+            abbe_number: 0.,
+            ..flint
+        };
+        assert!(!glass.is_dispersive());
+        assert_eq!(1.62, glass.refractive_index_at(450.));
+    }
+
+    #[test]
+    fn bumped_normal_follows_the_pattern_slope() {
+        let object = plane!();
+        let normalv = vector!(0, 1, 0);
+        let flat = Material::default();
+        assert_eq!(
+            normalv,
+            flat.bumped_normal(&object, point!(0.3, 0, 0), normalv)
+        );
+
+        let mut ramp = gradient_pattern!(BLACK, WHITE);
+        ramp.set_transform(Matrix::scaling(10, 1, 1));
+        let bumpy = MaterialBuilder::default().bump(ramp).build().unwrap();
+        // the height grows 0.1 per unit in x, so the normal leans toward -x
+        let n = bumpy.bumped_normal(&object, point!(0.3, 0, 0), normalv);
+        assert_eq!(vector!(-0.1, 1, 0).normalize(), n);
+
+        let steeper = Material {
+            bump_strength: 10.,
+            ..bumpy
+        };
+        let n = steeper.bumped_normal(&object, point!(0.3, 0, 0), normalv);
+        assert_eq!(vector!(-1, 1, 0).normalize(), n);
+    }
+
     #[test]
     fn builder() {
         let m1 = MaterialBuilder::default()
@@ -160,6 +709,29 @@ mod tests {
         assert_eq!(color!(1.9, 1.9, 1.9), result);
     }
 
+    #[test]
+    fn lightning_with_partial_transmittance() {
+        let material = Material::default();
+        let position = point!(0, 0, 0);
+        let object = sphere!();
+
+        let eyev = vector!(0, 0, -1);
+        let normalv = vector!(0, 0, -1);
+        let light = PointLight::new(point!(0, 0, -10), WHITE);
+
+        let transmittance = color!(0.5, 1, 0);
+        let result = material.lightning_with_transmittance(
+            &object,
+            &light,
+            position,
+            eyev,
+            normalv,
+            transmittance,
+        );
+        // the ambient term is not affected
+        assert_eq!(color!(1, 1.9, 0.1), result);
+    }
+
     #[test]
     fn lightning_eye_between_light_and_surface_eye_offset_45_deg() {
         let material = Material::default();
@@ -253,6 +825,254 @@ mod tests {
         assert_eq!(BLACK, c2);
     }
 
+    fn pbr_material(metallic: f64, roughness: f64) -> Material {
+        MaterialBuilder::default()
+            .model(ShadingModel::Pbr)
+            .ambient(0)
+            .metallic(metallic)
+            .roughness(roughness)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn pbr_dielectric_lit_head_on() {
+        let material = pbr_material(0., 1.);
+        let object = sphere!();
+        let eyev = vector!(0, 0, -1);
+        let normalv = vector!(0, 0, -1);
+        let light = PointLight::new(point!(0, 0, -10), WHITE);
+
+        let result = material.lightning(&object, &light, point!(), eyev, normalv, false);
+        // 96% diffuse plus a dim, wide highlight
+        assert_eq!(color!(0.97, 0.97, 0.97), result);
+    }
+
+    #[test]
+    fn pbr_metal_has_no_diffuse() {
+        let material = pbr_material(1., 0.1);
+        let object = sphere!();
+        // eye far from the mirror direction
+        let eyev = vector!(0, 2f64.sqrt() / 2., -2f64.sqrt() / 2.);
+        let normalv = vector!(0, 0, -1);
+        let light = PointLight::new(point!(0, 0, -10), WHITE);
+
+        let result = material.lightning(&object, &light, point!(), eyev, normalv, false);
+        assert!(result.r < 0.01);
+    }
+
+    #[test]
+    fn pbr_rough_highlights_are_wider() {
+        let object = sphere!();
+        let eyev = vector!(0, 0.3, -1).normalize();
+        let normalv = vector!(0, 0, -1);
+        let light = PointLight::new(point!(0, 0, -10), WHITE);
+
+        let smooth =
+            pbr_material(1., 0.1).lightning(&object, &light, point!(), eyev, normalv, false);
+        let rough =
+            pbr_material(1., 0.6).lightning(&object, &light, point!(), eyev, normalv, false);
+        assert!(rough.r > smooth.r);
+    }
+
+    #[test]
+    fn pbr_light_behind_surface() {
+        let material = MaterialBuilder::default()
+            .model(ShadingModel::Pbr)
+            .build()
+            .unwrap();
+        let object = sphere!();
+        let eyev = vector!(0, 0, -1);
+        let normalv = vector!(0, 0, -1);
+        let light = PointLight::new(point!(0, 0, 10), WHITE);
+
+        let result = material.lightning(&object, &light, point!(), eyev, normalv, false);
+        assert_eq!(color!(0.1, 0.1, 0.1), result);
+    }
+
+    #[test]
+    fn anisotropic_highlight_stretches_along_the_rougher_axis() {
+        let material = MaterialBuilder::default()
+            .model(ShadingModel::Anisotropic {
+                roughness_u: 0.5,
+                roughness_v: 0.05,
+            })
+            .ambient(0)
+            .diffuse(0)
+            .build()
+            .unwrap();
+        let object = sphere!();
+        let normalv = vector!(0, 0, -1);
+        let tangentv = vector!(1, 0, 0);
+        let light = PointLight::new(point!(0, 0, -10), WHITE);
+        let highlight = |eyev: Tuple| {
+            material
+                .lightning_with_tangent(
+                    &object,
+                    &light,
+                    point!(),
+                    eyev.normalize(),
+                    normalv,
+                    tangentv,
+                    WHITE,
+                )
+                .r
+        };
+        let along_u = highlight(vector!(0.3, 0, -1));
+        let along_v = highlight(vector!(0, 0.3, -1));
+        assert!(along_u > 0.1);
+        assert!(along_v < along_u / 10.);
+        // without a tangent, lightning picks one perpendicular to the normal
+        let plain = material.lightning(&object, &light, point!(), normalv, normalv, false);
+        assert!(plain.r > 0.);
+    }
+
+    #[test]
+    fn tangent_from_field_or_shape() {
+        let object = sphere!();
+        let normalv = vector!(0, 1, 0);
+        let material = Material::default();
+        let shape_tangent = vector!(1, 1, 0);
+        assert_eq!(
+            vector!(1, 0, 0),
+            material.tangent(&object, point!(), normalv, shape_tangent)
+        );
+        // the field points toward z, overriding the shape's tangent
+        let field = MaterialBuilder::default()
+            .tangent_field(solid!(0.5, 0.5, 1))
+            .build()
+            .unwrap();
+        assert_eq!(
+            vector!(0, 0, 1),
+            field.tangent(&object, point!(), normalv, shape_tangent)
+        );
+        // a tangent along the normal is replaced by any perpendicular direction
+        let t = material.tangent(&object, point!(), normalv, normalv);
+        assert!(approx_eq(0., t.dot(&normalv)));
+        assert!(approx_eq(1., t.magnitude()));
+    }
+
+    #[test]
+    fn thin_film_interference() {
+        // a film of no thickness between the same media vanishes
+        let empty = ThinFilm {
+            thickness: 0.,
+            ..ThinFilm::default()
+        };
+        assert!(approx_eq(0., empty.reflectance(1., 1., 1., 550.)));
+
+        // quarter wave coating on glass cancels its reflection at that wavelength
+        let coating = ThinFilm {
+            thickness: 550. / (4. * 1.5f64.sqrt()),
+            refractive_index: 1.5f64.sqrt(),
+        };
+        assert!(approx_eq(0., coating.reflectance(1., 1.5, 1., 550.)));
+        assert!(coating.reflectance(1., 1.5, 1., 450.) > 0.);
+
+        // the color changes with the view angle
+        let soap = ThinFilm::default();
+        assert!(!approx_eq(
+            soap.reflectance(1., 1., 1., 550.),
+            soap.reflectance(1., 1., 0.5, 550.)
+        ));
+    }
+
+    #[test]
+    fn layer_highlight_mirrors_the_light() {
+        let material = Material::default();
+        let normalv = vector!(0, 0, -1);
+        let light = PointLight::new(point!(0, 0, -10), WHITE);
+        let head_on = material.layer_highlight(&light, point!(), vector!(0, 0, -1), normalv);
+        assert_eq!(WHITE, head_on);
+        let eyev = vector!(0, 2f64.sqrt() / 2., -2f64.sqrt() / 2.);
+        assert_eq!(
+            BLACK,
+            material.layer_highlight(&light, point!(), eyev, normalv)
+        );
+    }
+
+    #[test]
+    fn blinn_phong_highlight_is_wider_than_phong() {
+        let highlight = |model| {
+            let material = MaterialBuilder::default()
+                .model(model)
+                .ambient(0)
+                .diffuse(0)
+                .specular(1)
+                .shininess(10)
+                .build()
+                .unwrap();
+            let object = sphere!();
+            let eyev = vector!(0, 2f64.sqrt() / 2., -2f64.sqrt() / 2.);
+            let normalv = vector!(0, 0, -1);
+            let light = PointLight::new(point!(0, 0, -10), WHITE);
+            material.lightning(&object, &light, point!(), eyev, normalv, false)
+        };
+        assert_eq!(
+            color!(0.03125, 0.03125, 0.03125),
+            highlight(ShadingModel::Phong)
+        );
+        assert_eq!(
+            color!(0.45306, 0.45306, 0.45306),
+            highlight(ShadingModel::BlinnPhong)
+        );
+    }
+
+    #[test]
+    fn oren_nayar_darkens_rough_surfaces() {
+        let lit_head_on = |sigma| {
+            let material = MaterialBuilder::default()
+                .model(ShadingModel::OrenNayar { sigma })
+                .build()
+                .unwrap();
+            let object = sphere!();
+            let eyev = vector!(0, 0, -1);
+            let normalv = vector!(0, 0, -1);
+            let light = PointLight::new(point!(0, 0, -10), WHITE);
+            material.lightning(&object, &light, point!(), eyev, normalv, false)
+        };
+        // lambertian without the specular highlight
+        assert_eq!(color!(1, 1, 1), lit_head_on(0.));
+        assert_eq!(color!(0.80603, 0.80603, 0.80603), lit_head_on(0.5));
+    }
+
+    #[test]
+    fn toon_quantizes_the_diffuse_term() {
+        let lit_at_60_deg = |bands| {
+            let material = MaterialBuilder::default()
+                .model(ShadingModel::Toon { bands, rim: 0. })
+                .build()
+                .unwrap();
+            let object = sphere!();
+            let eyev = vector!(0, 0, -1);
+            let normalv = vector!(0, 0, -1);
+            let light = PointLight::new(point!(0, 10. * (PI / 3.).sin(), -5), WHITE);
+            material.lightning(&object, &light, point!(), eyev, normalv, false)
+        };
+        assert_eq!(color!(0.7, 0.7, 0.7), lit_at_60_deg(3));
+        assert_eq!(color!(0.55, 0.55, 0.55), lit_at_60_deg(2));
+    }
+
+    #[test]
+    fn toon_highlight_and_rim_light() {
+        let material = MaterialBuilder::default()
+            .model(ShadingModel::Toon { bands: 1, rim: 0.5 })
+            .build()
+            .unwrap();
+        let object = sphere!();
+        let normalv = vector!(0, 0, -1);
+        let light = PointLight::new(point!(0, 0, -10), WHITE);
+
+        // ambient, diffuse and the full highlight
+        let eyev = vector!(0, 0, -1);
+        let result = material.lightning(&object, &light, point!(), eyev, normalv, false);
+        assert_eq!(color!(1.9, 1.9, 1.9), result);
+        // close to the silhouette: no highlight, rim light instead
+        let eyev = vector!(0, 0.96f64.sqrt(), -0.2);
+        let result = material.lightning(&object, &light, point!(), eyev, normalv, false);
+        assert_eq!(color!(1.5, 1.5, 1.5), result);
+    }
+
     #[test]
     fn precomputing_reflection_vector() {
         let arena = Arena::new();
@@ -0,0 +1,452 @@
+use std::{collections::HashMap, fs, io, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    color,
+    color::{Color, WHITE},
+    material::{Clearcoat, Material, ShadingModel, ShadowMode, ThinFilm},
+    patterns::Pattern,
+    solid,
+};
+
+/*
+Material files are a list of named sections with `key = value` lines:
+
+    ; a comment
+    [tinted_glass]
+    base = glass            ; starts from another entry, should come first
+    absorption = #80c0ff    ; colors are hex codes or "r, g, b"
+    density = 0.5
+
+    [brushed_gold]
+    base = gold
+    roughness = 0.4
+
+Keys are the Material field names, with `color` setting a solid pattern.
+`clearcoat` (strength) and `clearcoat_index` add a clearcoat layer, and
+`film_thickness` (nm) and `film_index` a thin-film layer.
+`model` is phong, pbr, blinn_phong, oren_nayar, toon or anisotropic. Oren-Nayar takes
+an optional slope deviation in radians (`model = oren_nayar 0.3`), toon the
+band count and rim light strength (`model = toon 3 0.4`), anisotropic
+the roughness along the tangent and the bitangent (`model = anisotropic 0.3 0.05`).
+`shadow` is transmissive, tinted or hard.
+*/
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct MaterialLibrary {
+    materials: HashMap<String, Material>,
+}
+
+impl MaterialLibrary {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Common dielectrics (refractive index at the d line and Abbe number) and metals
+    // (base color as in the usual metallic/roughness references)
+    pub fn with_presets() -> Self {
+        let mut library = Self::new();
+        for (name, refractive_index, abbe_number) in &[
+            ("air", 1.00029, 0.),
+            ("water", 1.333, 55.7),
+            ("ice", 1.31, 0.),
+            ("glass", 1.52, 0.),
+            ("crown_glass", 1.523, 58.5),
+            ("flint_glass", 1.62, 36.4),
+            ("sapphire", 1.77, 72.2),
+            ("diamond", 2.417, 55.3),
+        ] {
+            library.insert(*name, dielectric(*refractive_index, *abbe_number));
+        }
+        for (name, base_color, roughness) in &[
+            ("chrome", color!(0.55, 0.556, 0.554), 0.1),
+            ("silver", color!(0.972, 0.96, 0.915), 0.15),
+            ("aluminum", color!(0.913, 0.922, 0.924), 0.3),
+            ("gold", color!(1, 0.766, 0.336), 0.2),
+            ("copper", color!(0.955, 0.637, 0.538), 0.25),
+            ("iron", color!(0.56, 0.57, 0.58), 0.5),
+        ] {
+            library.insert(*name, metal(*base_color, *roughness));
+        }
+        library.insert(
+            "plastic",
+            Material {
+                specular: 0.5,
+                shininess: 100.,
+                reflective: 0.05,
+                ..Material::default()
+            },
+        );
+        library.insert(
+            "rubber",
+            Material {
+                pattern: solid!(0.1, 0.1, 0.1),
+                specular: 0.1,
+                shininess: 10.,
+                ..Material::default()
+            },
+        );
+        library
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Material> {
+        self.materials.get(name)
+    }
+
+    pub fn insert(&mut self, name: impl Into<String>, material: Material) {
+        self.materials.insert(name.into(), material);
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.materials.contains_key(name)
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        let mut names = self
+            .materials
+            .keys()
+            .map(|n| n.as_str())
+            .collect::<Vec<_>>();
+        names.sort_unstable();
+        names
+    }
+
+    // Adds (or replaces) the entries of a material file
+    pub fn load(&mut self, path: &str) -> io::Result<()> {
+        let text = fs::read_to_string(path)?;
+        self.parse(&text)
+    }
+
+    pub fn parse(&mut self, text: &str) -> io::Result<()> {
+        let mut current: Option<(String, Material)> = None;
+        for (index, line) in text.lines().enumerate() {
+            let invalid = |message: String| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: {}", index + 1, message),
+                )
+            };
+            let line = match line.find(';') {
+                Some(comment) => &line[..comment],
+                None => line,
+            }
+            .trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if line.starts_with('[') && line.ends_with(']') {
+                if let Some((name, material)) = current.take() {
+                    self.insert(name, material);
+                }
+                let name = line[1..line.len() - 1].trim();
+                if name.is_empty() {
+                    return Err(invalid("empty material name".to_string()));
+                }
+                current = Some((name.to_string(), Material::default()));
+                continue;
+            }
+
+            let (key, value) = match line.find('=') {
+                Some(eq) => (line[..eq].trim(), line[eq + 1..].trim()),
+                None => return Err(invalid(format!("expected key = value, got {}", line))),
+            };
+            let material = match current.as_mut() {
+                Some((_, material)) => material,
+                None => return Err(invalid(format!("{} is outside of a [material]", key))),
+            };
+            if key == "base" {
+                *material = *self
+                    .get(value)
+                    .ok_or_else(|| invalid(format!("unknown base material {}", value)))?;
+            } else {
+                set_property(material, key, value).map_err(invalid)?;
+            }
+        }
+        if let Some((name, material)) = current {
+            self.insert(name, material);
+        }
+        Ok(())
+    }
+}
+
+fn dielectric(refractive_index: f64, abbe_number: f64) -> Material {
+    Material {
+        pattern: solid!(WHITE),
+        ambient: 0.,
+        diffuse: 0.1,
+        specular: 1.,
+        shininess: 300.,
+        reflective: 0.9,
+        transparency: 0.9,
+        refractive_index,
+        abbe_number,
+        ..Material::default()
+    }
+}
+
+fn metal(base_color: Color, roughness: f64) -> Material {
+    Material {
+        pattern: solid!(base_color),
+        model: ShadingModel::Pbr,
+        metallic: 1.,
+        roughness,
+        reflective: 0.6,
+        ..Material::default()
+    }
+}
+
+fn set_property(material: &mut Material, key: &str, value: &str) -> Result<(), String> {
+    let number = || {
+        value
+            .parse::<f64>()
+            .map_err(|_| format!("{} expects a number, got {}", key, value))
+    };
+    match key {
+        "color" => material.pattern = Pattern::Solid(parse_color(value)?),
+        "ambient" => material.ambient = number()?,
+        "diffuse" => material.diffuse = number()?,
+        "specular" => material.specular = number()?,
+        "shininess" => material.shininess = number()?,
+        "reflective" => material.reflective = number()?,
+        "transparency" => material.transparency = number()?,
+        "refractive_index" => material.refractive_index = number()?,
+        "metallic" => material.metallic = number()?,
+        "roughness" => material.roughness = number()?,
+        "absorption" => material.absorption = parse_color(value)?,
+        "density" => material.density = number()?,
+        "emission" => material.emission = parse_color(value)?,
+        "emission_strength" => material.emission_strength = number()?,
+        "abbe_number" => material.abbe_number = number()?,
+        "bump_strength" => material.bump_strength = number()?,
+        "reflection_roughness" => material.reflection_roughness = number()?,
+        "refraction_roughness" => material.refraction_roughness = number()?,
+        "clearcoat" => {
+            material
+                .clearcoat
+                .get_or_insert_with(Clearcoat::default)
+                .strength = number()?
+        }
+        "clearcoat_index" => {
+            material
+                .clearcoat
+                .get_or_insert_with(Clearcoat::default)
+                .refractive_index = number()?
+        }
+        "film_thickness" => {
+            material
+                .thin_film
+                .get_or_insert_with(ThinFilm::default)
+                .thickness = number()?
+        }
+        "film_index" => {
+            material
+                .thin_film
+                .get_or_insert_with(ThinFilm::default)
+                .refractive_index = number()?
+        }
+        "model" => material.model = parse_model(value)?,
+        "shadow" => {
+            material.shadow = match value {
+                "transmissive" => ShadowMode::Transmissive,
+                "tinted" => ShadowMode::Tinted,
+                "hard" => ShadowMode::Hard,
+                _ => return Err(format!("unknown shadow mode {}", value)),
+            }
+        }
+        _ => return Err(format!("unknown property {}", key)),
+    }
+    Ok(())
+}
+
+fn parse_model(value: &str) -> Result<ShadingModel, String> {
+    let mut words = value.split_whitespace();
+    let model = match words.next().unwrap_or_default() {
+        "phong" => ShadingModel::Phong,
+        "pbr" => ShadingModel::Pbr,
+        "blinn_phong" => ShadingModel::BlinnPhong,
+        "oren_nayar" => {
+            let sigma = words
+                .next()
+                .map_or(Ok(0.3), str::parse)
+                .map_err(|_| format!("invalid oren_nayar model {}", value))?;
+            ShadingModel::OrenNayar { sigma }
+        }
+        "anisotropic" => {
+            let invalid = || format!("invalid anisotropic model {}", value);
+            let roughness_u = words
+                .next()
+                .map_or(Ok(0.3), str::parse)
+                .map_err(|_| invalid())?;
+            let roughness_v = words
+                .next()
+                .map_or(Ok(0.05), str::parse)
+                .map_err(|_| invalid())?;
+            ShadingModel::Anisotropic {
+                roughness_u,
+                roughness_v,
+            }
+        }
+        "toon" => {
+            let invalid = || format!("invalid toon model {}", value);
+            let bands = words
+                .next()
+                .map_or(Ok(3), str::parse)
+                .map_err(|_| invalid())?;
+            let rim = words
+                .next()
+                .map_or(Ok(0.), str::parse)
+                .map_err(|_| invalid())?;
+            ShadingModel::Toon { bands, rim }
+        }
+        _ => return Err(format!("unknown shading model {}", value)),
+    };
+    match words.next() {
+        Some(_) => Err(format!("unexpected arguments for shading model {}", value)),
+        None => Ok(model),
+    }
+}
+
+// "#rrggbb" or "r, g, b"
+fn parse_color(value: &str) -> Result<Color, String> {
+    let invalid = || format!("invalid color {}", value);
+    if value.starts_with('#') {
+        return Color::from_str(value).map_err(|_| invalid());
+    }
+    let channels = value
+        .split(',')
+        .map(|c| c.trim().parse::<f64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| invalid())?;
+    match channels[..] {
+        [r, g, b] => Ok(color!(r, g, b)),
+        _ => Err(invalid()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::RED;
+
+    #[test]
+    fn presets() {
+        let library = MaterialLibrary::with_presets();
+        assert_eq!(1.333, library.get("water").unwrap().refractive_index);
+        assert_eq!(2.417, library.get("diamond").unwrap().refractive_index);
+        assert!(library.get("diamond").unwrap().is_dispersive());
+        let gold = library.get("gold").unwrap();
+        assert_eq!(ShadingModel::Pbr, gold.model);
+        assert_eq!(1., gold.metallic);
+        assert!(library.get("unobtainium").is_none());
+        assert!(library.names().contains(&"chrome"));
+    }
+
+    #[test]
+    fn parse_materials() {
+        let mut library = MaterialLibrary::with_presets();
+        library
+            .parse(
+                "
+                ; tinted glass
+                [tinted_glass]
+                base = glass
+                absorption = #ff0000
+                density = 0.5
+
+                [red_plastic]
+                color = 1, 0, 0
+                specular = 0.3
+                model = pbr
+                shadow = hard
+                ",
+            )
+            .unwrap();
+        let tinted = library.get("tinted_glass").unwrap();
+        assert_eq!(1.52, tinted.refractive_index);
+        assert_eq!(RED, tinted.absorption);
+        assert_eq!(0.5, tinted.density);
+        let plastic = library.get("red_plastic").unwrap();
+        assert_eq!(solid!(RED), plastic.pattern);
+        assert_eq!(0.3, plastic.specular);
+        assert_eq!(ShadingModel::Pbr, plastic.model);
+        assert_eq!(ShadowMode::Hard, plastic.shadow);
+
+        library
+            .parse("[clay]\nmodel = oren_nayar\n[cel]\nmodel = toon 4 0.3\n[flat]\nmodel = toon")
+            .unwrap();
+        assert_eq!(
+            ShadingModel::OrenNayar { sigma: 0.3 },
+            library.get("clay").unwrap().model
+        );
+        library.parse("[plaster]\nmodel = oren_nayar 0.5").unwrap();
+        assert_eq!(
+            ShadingModel::OrenNayar { sigma: 0.5 },
+            library.get("plaster").unwrap().model
+        );
+
+        library
+            .parse("[car_paint]\ncolor = #c00000\nclearcoat = 0.8\n[bubble]\nfilm_thickness = 350")
+            .unwrap();
+        let coat = library.get("car_paint").unwrap().clearcoat.unwrap();
+        assert_eq!((0.8, 1.5), (coat.strength, coat.refractive_index));
+        let film = library.get("bubble").unwrap().thin_film.unwrap();
+        assert_eq!((350., 1.33), (film.thickness, film.refractive_index));
+        assert_eq!(
+            ShadingModel::Toon { bands: 4, rim: 0.3 },
+            library.get("cel").unwrap().model
+        );
+        assert_eq!(
+            ShadingModel::Toon { bands: 3, rim: 0. },
+            library.get("flat").unwrap().model
+        );
+        library
+            .parse("[brushed]\nbase = aluminum\nmodel = anisotropic 0.4 0.1")
+            .unwrap();
+        assert_eq!(
+            ShadingModel::Anisotropic {
+                roughness_u: 0.4,
+                roughness_v: 0.1
+            },
+            library.get("brushed").unwrap().model
+        );
+    }
+
+    #[test]
+    fn parse_errors_have_line_numbers() {
+        let error = |text: &str| MaterialLibrary::new().parse(text).unwrap_err().to_string();
+        assert_eq!(
+            "line 1: diffuse is outside of a [material]",
+            error("diffuse = 1")
+        );
+        assert_eq!(
+            "line 2: diffuse expects a number, got lots",
+            error("[m]\ndiffuse = lots")
+        );
+        assert_eq!(
+            "line 2: unknown base material nope",
+            error("[m]\nbase = nope")
+        );
+        assert_eq!("line 3: unknown property glow", error("[m]\n\nglow = 1"));
+        assert_eq!("line 2: invalid color 1, 2", error("[m]\ncolor = 1, 2"));
+        assert_eq!(
+            "line 2: unknown shading model lambert",
+            error("[m]\nmodel = lambert")
+        );
+        assert_eq!(
+            "line 2: invalid toon model toon many",
+            error("[m]\nmodel = toon many")
+        );
+    }
+
+    #[test]
+    fn load_from_file() {
+        let path = std::env::temp_dir().join("rust_tracer_materials_test.mtl");
+        fs::write(&path, "[mirror]\nreflective = 1\n").unwrap();
+        let mut library = MaterialLibrary::new();
+        library.load(path.to_str().unwrap()).unwrap();
+        assert_eq!(1., library.get("mirror").unwrap().reflective);
+        assert!(library.load("/nonexistent/materials.mtl").is_err());
+    }
+}
@@ -1,6 +1,8 @@
 use core::ops::{Index, IndexMut, Mul};
 use std::fmt;
 
+use serde::{Deserialize, Serialize};
+
 use crate::{approx_eq, ray::Ray, tuple::Tuple};
 
 #[macro_export]
@@ -43,7 +45,7 @@ pub const IDENTITY_MATRIX: Matrix = Matrix {
 
 const EMPTY_ROW: [f64; 4] = [0.; 4];
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Serialize, Deserialize)]
 pub struct Matrix {
     data: [[f64; 4]; 4],
     size: usize,
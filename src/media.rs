@@ -0,0 +1,292 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    color::{Color, BLACK, WHITE},
+    intersection::Intersection,
+    ray::Ray,
+    shapes::Shape,
+    world::World,
+};
+
+// Global fog between the camera (or any ray origin) and what the ray hits
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Fog {
+    // the same density everywhere
+    Exponential {
+        color: Color,
+        density: f64,
+    },
+    // density * e^(-falloff * (y - base)), thinning out with height
+    Height {
+        color: Color,
+        density: f64,
+        falloff: f64,
+        base: f64,
+    },
+}
+
+impl Fog {
+    pub fn color(&self) -> Color {
+        match self {
+            Fog::Exponential { color, .. } | Fog::Height { color, .. } => *color,
+        }
+    }
+
+    // Fraction of the light that travels the given distance along the ray
+    // without being replaced by fog. The distance can be infinite
+    pub fn transmittance(&self, r: &Ray, distance: f64) -> f64 {
+        let length = distance * r.direction.magnitude();
+        let optical_depth = match *self {
+            Fog::Exponential { density, .. } => {
+                if density <= 0. {
+                    return 1.;
+                }
+                density * length
+            }
+            Fog::Height {
+                density,
+                falloff,
+                base,
+                ..
+            } => {
+                if density <= 0. {
+                    return 1.;
+                }
+                // closed form of the density integrated along the ray
+                let origin_density = density * (-falloff * (r.origin.y - base)).exp();
+                let k = falloff * r.direction.normalize().y;
+                if k.abs() < 1e-9 {
+                    origin_density * length
+                } else {
+                    origin_density * (1. - (-k * length).exp()) / k
+                }
+            }
+        };
+        (-optical_depth).exp()
+    }
+}
+
+// Participating medium filling a closed shape, e.g. smoke. The surface itself
+// is invisible: rays are marched through the inside, where the material's pattern
+// is the color of the scattered light
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Volume {
+    // fractions of the light absorbed and scattered per unit of distance
+    pub absorption: f64,
+    pub scattering: f64,
+    // ray marching step
+    pub step: f64,
+}
+
+impl Default for Volume {
+    fn default() -> Self {
+        Self {
+            absorption: 0.5,
+            scattering: 0.5,
+            step: 0.1,
+        }
+    }
+}
+
+impl Volume {
+    pub fn extinction(&self) -> f64 {
+        self.absorption + self.scattering
+    }
+
+    // Fraction of the light that crosses the given distance without being
+    // absorbed or scattered away
+    pub fn transmittance(&self, distance: f64) -> f64 {
+        (-self.extinction() * distance).exp()
+    }
+}
+
+impl World {
+    // Light added by the media in front of `distance` along the ray (volumes, then the fog),
+    // and the fraction of the light from behind that gets through them
+    pub(crate) fn media_along(&self, r: &Ray, xs: &[Intersection], distance: f64) -> (Color, f64) {
+        let (mut light, mut transmittance) = self.march_volumes(r, xs, distance);
+        if let Some(fog) = self.fog {
+            let fog_transmittance = fog.transmittance(r, distance);
+            light = light * fog_transmittance + fog.color() * (1. - fog_transmittance);
+            transmittance *= fog_transmittance;
+        }
+        (light, transmittance)
+    }
+
+    // Single scattering toward the ray origin, with isotropic scattering on the same
+    // brightness scale as the diffuse term. Each step is lit through shadow_transmittance,
+    // so the volume also shadows itself. Overlapping volumes are marched one after the other
+    fn march_volumes(&self, r: &Ray, xs: &[Intersection], distance: f64) -> (Color, f64) {
+        let mut light = BLACK;
+        let mut transmittance = 1.;
+        for (start, end, object, volume) in volume_segments(xs, distance) {
+            let material = object.material();
+            let steps = ((end - start) / volume.step.max(1e-3)).ceil().max(1.);
+            let dt = (end - start) / steps;
+            let step_transmittance = volume.transmittance(dt * r.direction.magnitude());
+            let scattered = if volume.extinction() > 0. {
+                (1. - step_transmittance) * volume.scattering / volume.extinction()
+            } else {
+                0.
+            };
+            for step in 0..steps as usize {
+                let point = r.position(start + (step as f64 + 0.5) * dt);
+                let color = material.pattern.color_at_object(object, point);
+                let incoming = self.shadow_transmittance(point) + WHITE * material.ambient;
+                light = light + color * self.light.intensity * incoming * scattered * transmittance;
+                transmittance *= step_transmittance;
+            }
+        }
+        (light, transmittance)
+    }
+}
+
+// Parts of the ray in [0, distance) inside volumes, in the order they are entered
+fn volume_segments<'a>(
+    xs: &[Intersection<'a>],
+    distance: f64,
+) -> Vec<(f64, f64, &'a Shape, Volume)> {
+    let mut inside: Vec<(&Shape, f64)> = vec![];
+    let mut segments = vec![];
+    for i in xs {
+        let volume = match i.object.material().volume {
+            Some(volume) => volume,
+            None => continue,
+        };
+        match inside.iter().position(|(o, _)| *o == i.object) {
+            Some(pos) => {
+                let (object, entered) = inside.remove(pos);
+                let (start, end) = (entered.max(0.), i.t.min(distance));
+                if end > start {
+                    segments.push((start, end, object, volume));
+                }
+            }
+            None => inside.push((i.object, i.t)),
+        }
+    }
+    segments.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    segments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        approx_eq, color, cube, light::PointLight, material::Material, point, ray, vector,
+    };
+
+    #[test]
+    fn exponential_fog() {
+        let fog = Fog::Exponential {
+            color: WHITE,
+            density: 0.5,
+        };
+        let r = ray!(point!(0, 0, 0), vector!(0, 0, 1));
+        assert_eq!(1., fog.transmittance(&r, 0.));
+        assert!(approx_eq((-1f64).exp(), fog.transmittance(&r, 2.)));
+        assert_eq!(0., fog.transmittance(&r, f64::INFINITY));
+        let clear = Fog::Exponential {
+            color: WHITE,
+            density: 0.,
+        };
+        assert_eq!(1., clear.transmittance(&r, f64::INFINITY));
+    }
+
+    #[test]
+    fn height_fog_thins_out_upward() {
+        let fog = Fog::Height {
+            color: WHITE,
+            density: 0.5,
+            falloff: 1.,
+            base: 0.,
+        };
+        // horizontal rays see a constant density
+        let low = ray!(point!(0, 0, 0), vector!(0, 0, 1));
+        let high = ray!(point!(0, 2, 0), vector!(0, 0, 1));
+        assert!(approx_eq((-1f64).exp(), fog.transmittance(&low, 2.)));
+        assert!(fog.transmittance(&high, 2.) > fog.transmittance(&low, 2.));
+        // looking up, the fog runs out; looking down, it never does
+        let up = ray!(point!(0, 0, 0), vector!(0, 1, 0));
+        assert!(approx_eq(
+            (-0.5f64).exp(),
+            fog.transmittance(&up, f64::INFINITY)
+        ));
+        let down = ray!(point!(0, 0, 0), vector!(0, -1, 0));
+        assert_eq!(0., fog.transmittance(&down, f64::INFINITY));
+    }
+
+    fn smoke_world(volume: Volume) -> World {
+        let mut w = World::new(PointLight::new(point!(0, 10, 0), WHITE), vec![]);
+        let mut smoke = cube!();
+        smoke.set_material(Material {
+            ambient: 0.,
+            volume: Some(volume),
+            ..Material::default()
+        });
+        w.add_object(smoke);
+        w
+    }
+
+    #[test]
+    fn volume_segments_are_clipped_to_the_ray() {
+        let w = smoke_world(Volume::default());
+        let r = ray!(point!(0, 0, -5), vector!(0, 0, 1));
+        let xs = w.intersect(&r);
+        let segments = volume_segments(&xs, f64::INFINITY);
+        assert_eq!(1, segments.len());
+        assert_eq!((4., 6.), (segments[0].0, segments[0].1));
+        let segments = volume_segments(&xs, 5.);
+        assert_eq!((4., 5.), (segments[0].0, segments[0].1));
+
+        let inside = ray!(point!(0, 0, 0), vector!(0, 0, 1));
+        let xs = w.intersect(&inside);
+        let segments = volume_segments(&xs, f64::INFINITY);
+        assert_eq!((0., 1.), (segments[0].0, segments[0].1));
+    }
+
+    #[test]
+    fn absorbing_volume_darkens_what_is_behind() {
+        let w = smoke_world(Volume {
+            absorption: 0.5,
+            scattering: 0.,
+            step: 0.1,
+        });
+        let r = ray!(point!(0, 0, -5), vector!(0, 0, 1));
+        let (light, transmittance) = w.media_along(&r, &w.intersect(&r), f64::INFINITY);
+        assert_eq!(BLACK, light);
+        assert!(approx_eq((-1f64).exp(), transmittance));
+    }
+
+    #[test]
+    fn scattering_volume_is_lit_by_the_light() {
+        let w = smoke_world(Volume {
+            absorption: 0.,
+            scattering: 0.5,
+            step: 0.1,
+        });
+        let r = ray!(point!(0, 0, -5), vector!(0, 0, 1));
+        let (light, transmittance) = w.media_along(&r, &w.intersect(&r), f64::INFINITY);
+        assert!(approx_eq((-1f64).exp(), transmittance));
+        // less than what is scattered away, as the smoke above shadows the ray
+        assert!(light.r > 0. && light.r < 1. - (-1f64).exp());
+        assert_eq!(light.r, light.g);
+    }
+
+    #[test]
+    fn fog_is_composited_over_volumes() {
+        let mut w = smoke_world(Volume {
+            absorption: 0.5,
+            scattering: 0.,
+            step: 0.1,
+        });
+        w.fog = Some(Fog::Exponential {
+            color: color!(0.5, 0.5, 0.5),
+            density: 0.1,
+        });
+        let r = ray!(point!(0, 0, -5), vector!(0, 0, 1));
+        let (light, transmittance) = w.media_along(&r, &w.intersect(&r), 10.);
+        let fog_transmittance = (-1f64).exp();
+        assert!(approx_eq((-1f64).exp() * fog_transmittance, transmittance));
+        assert!(approx_eq(0.5 * (1. - fog_transmittance), light.r));
+    }
+}
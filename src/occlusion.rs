@@ -0,0 +1,107 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{ray, sampling, stats, tuple::Tuple, world::World};
+
+// Hemisphere sampled ambient occlusion: a point is occluded in the directions
+// where something is closer than `radius`
+#[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
+pub struct AmbientOcclusion {
+    pub radius: f64,
+    pub samples: usize,
+}
+
+impl Default for AmbientOcclusion {
+    fn default() -> Self {
+        Self {
+            radius: 1.,
+            samples: 16,
+        }
+    }
+}
+
+impl World {
+    // Fraction of the cosine weighted hemisphere around the normal that is open:
+    // 1 with nothing within reach, 0 when every sampled direction is blocked.
+    // Pass a point offset from the surface (e.g. over_point)
+    pub fn ambient_visibility(
+        &self,
+        point: Tuple,
+        normalv: Tuple,
+        occlusion: AmbientOcclusion,
+    ) -> f64 {
+        let samples = occlusion.samples.max(1);
+        let mut rng = rand::thread_rng();
+        let open = (0..samples)
+            .filter(|_| {
+                let direction = sampling::cosine_hemisphere(normalv, &mut rng);
+                // counted as shadow rays, they are visibility tests too
+                stats::record(|s| s.shadow_rays += 1);
+                !self.any_hit(&ray!(point, direction), occlusion.radius)
+            })
+            .count();
+        open as f64 / samples as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    use crate::{
+        color::WHITE, light::PointLight, matrix::Matrix, plane, point, shapes::Shape, vector,
+    };
+
+    fn floor_world(walls: Vec<Shape>) -> World {
+        let mut w = World::new(PointLight::new(point!(0, 10, 0), WHITE), vec![plane!()]);
+        for wall in walls {
+            w.add_object(wall);
+        }
+        w
+    }
+
+    #[test]
+    fn open_surface_is_not_occluded() {
+        let w = floor_world(vec![]);
+        let visibility = w.ambient_visibility(
+            point!(0, 0.001, 0),
+            vector!(0, 1, 0),
+            AmbientOcclusion::default(),
+        );
+        assert_eq!(1., visibility);
+    }
+
+    #[test]
+    fn ceiling_within_radius_occludes_everything() {
+        let mut ceiling = plane!();
+        ceiling.set_transform(Matrix::translation(0, 0.5, 0));
+        let w = floor_world(vec![ceiling]);
+        let point = point!(0, 0.001, 0);
+        let normalv = vector!(0, 1, 0);
+        // the radius is large enough for all but the most grazing directions
+        let long = AmbientOcclusion {
+            radius: 1000.,
+            samples: 16,
+        };
+        assert_eq!(0., w.ambient_visibility(point, normalv, long));
+        // out of reach with a smaller radius
+        let short = AmbientOcclusion {
+            radius: 0.25,
+            samples: 8,
+        };
+        assert_eq!(1., w.ambient_visibility(point, normalv, short));
+    }
+
+    #[test]
+    fn corner_is_partially_occluded() {
+        let mut wall = plane!();
+        wall.set_transform(Matrix::translation(0.1, 0, 0) * Matrix::rotation_z(PI / 2.));
+        let w = floor_world(vec![wall]);
+        let occlusion = AmbientOcclusion {
+            radius: 10.,
+            samples: 256,
+        };
+        let visibility = w.ambient_visibility(point!(0, 0.001, 0), vector!(0, 1, 0), occlusion);
+        assert!(visibility > 0.2 && visibility < 0.8);
+    }
+}
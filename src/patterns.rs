@@ -1,3 +1,6 @@
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+
 use crate::{
     color,
     color::Color,
@@ -64,39 +67,50 @@ macro_rules! checkers_pattern {
     };
 }
 
-#[derive(Debug, PartialEq, Copy, Clone)]
+#[macro_export]
+macro_rules! ripple_pattern {
+    ($a:expr, $b:expr) => {
+        $crate::patterns::Pattern::Ripple($crate::patterns::RipplePattern::new($a, $b))
+    };
+    // ripple_pattern!(0.1, 1, 0.5; 1, 0.8, 0.1)
+    ($($a: expr),+; $($b: expr),+) => {
+        $crate::patterns::Pattern::Ripple($crate::patterns::RipplePattern::new(
+            $crate::color::Color::new($($a),*), $crate::color::Color::new($($b),*)))
+    };
+}
+
+#[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
 pub enum Pattern {
     Solid(Color),
     Stripes(StripePattern),
     Gradient(GradientPattern),
     Ring(RingPattern),
     Checkers(CheckersPattern),
+    Ripple(RipplePattern),
     Test(TestPattern),
 }
 
 impl Pattern {
     pub fn color_at_object(&self, object: &Shape, world_point: Tuple) -> Color {
+        let object_point = object.transform().inverse().unwrap() * world_point;
+        self.color_at(object_point)
+    }
+
+    // Color at a point in the space the pattern transform applies to: object space
+    // for materials, the projection plane for spot lights
+    pub fn color_at(&self, point: Tuple) -> Color {
+        let pattern_point = || self.transform().inverse().unwrap() * point;
         match self {
             Pattern::Solid(color) => *color,
-            Pattern::Stripes(pattern) => {
-                pattern.color_at(self.to_pattern_point(object, world_point))
-            }
-            Pattern::Gradient(pattern) => {
-                pattern.color_at(self.to_pattern_point(object, world_point))
-            }
-            Pattern::Ring(pattern) => pattern.color_at(self.to_pattern_point(object, world_point)),
-            Pattern::Checkers(pattern) => {
-                pattern.color_at(self.to_pattern_point(object, world_point))
-            }
-            Pattern::Test(pattern) => pattern.color_at(self.to_pattern_point(object, world_point)),
+            Pattern::Stripes(pattern) => pattern.color_at(pattern_point()),
+            Pattern::Gradient(pattern) => pattern.color_at(pattern_point()),
+            Pattern::Ring(pattern) => pattern.color_at(pattern_point()),
+            Pattern::Checkers(pattern) => pattern.color_at(pattern_point()),
+            Pattern::Ripple(pattern) => pattern.color_at(pattern_point()),
+            Pattern::Test(pattern) => pattern.color_at(pattern_point()),
         }
     }
 
-    fn to_pattern_point(&self, object: &Shape, world_point: Tuple) -> Tuple {
-        let object_point = object.transform().inverse().unwrap() * world_point;
-        self.transform().inverse().unwrap() * object_point
-    }
-
     pub fn transform(&self) -> &Matrix {
         match self {
             Pattern::Solid(_) => &IDENTITY_MATRIX,
@@ -104,6 +118,7 @@ impl Pattern {
             Pattern::Gradient(pattern) => &pattern.transform,
             Pattern::Ring(pattern) => &pattern.transform,
             Pattern::Checkers(pattern) => &pattern.transform,
+            Pattern::Ripple(pattern) => &pattern.transform,
             Pattern::Test(pattern) => &pattern.transform,
         }
     }
@@ -115,12 +130,13 @@ impl Pattern {
             Pattern::Gradient(pattern) => pattern.transform = transform,
             Pattern::Ring(pattern) => pattern.transform = transform,
             Pattern::Checkers(pattern) => pattern.transform = transform,
+            Pattern::Ripple(pattern) => pattern.transform = transform,
             Pattern::Test(pattern) => pattern.transform = transform,
         }
     }
 }
 
-#[derive(Debug, PartialEq, Copy, Clone)]
+#[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
 pub struct StripePattern {
     pub a: Color,
     pub b: Color,
@@ -145,7 +161,7 @@ impl StripePattern {
     }
 }
 
-#[derive(Debug, PartialEq, Copy, Clone)]
+#[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
 pub struct GradientPattern {
     pub a: Color,
     pub b: Color,
@@ -168,7 +184,7 @@ impl GradientPattern {
     }
 }
 
-#[derive(Debug, PartialEq, Copy, Clone)]
+#[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
 pub struct RingPattern {
     pub a: Color,
     pub b: Color,
@@ -193,7 +209,7 @@ impl RingPattern {
     }
 }
 
-#[derive(Debug, PartialEq, Copy, Clone)]
+#[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
 pub struct CheckersPattern {
     pub a: Color,
     pub b: Color,
@@ -218,7 +234,31 @@ impl CheckersPattern {
     }
 }
 
-#[derive(Debug, PartialEq, Copy, Clone)]
+// Smooth concentric waves in x and z, mostly useful as a bump pattern
+#[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
+pub struct RipplePattern {
+    pub a: Color,
+    pub b: Color,
+    transform: Matrix,
+}
+
+impl RipplePattern {
+    pub fn new(a: Color, b: Color) -> Self {
+        Self {
+            a,
+            b,
+            transform: IDENTITY_MATRIX,
+        }
+    }
+
+    fn color_at(&self, p: Tuple) -> Color {
+        let distance = (p.x * p.x + p.z * p.z).sqrt();
+        let fraction = ((2. * PI * distance).cos() + 1.) / 2.;
+        self.a + (self.b - self.a) * fraction
+    }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
 pub struct TestPattern {
     transform: Matrix,
 }
@@ -353,4 +393,13 @@ mod tests {
         assert_eq!(WHITE, pattern.color_at(point!(0, 0, 0.99)));
         assert_eq!(BLACK, pattern.color_at(point!(0, 0, 1.01)));
     }
+
+    #[test]
+    fn ripples_are_smooth_rings() {
+        let pattern = RipplePattern::new(WHITE, BLACK);
+        assert_eq!(BLACK, pattern.color_at(point!(0, 0, 0)));
+        assert_eq!(color!(0.5, 0.5, 0.5), pattern.color_at(point!(0.25, 0, 0)));
+        assert_eq!(WHITE, pattern.color_at(point!(0, 0, 0.5)));
+        assert_eq!(BLACK, pattern.color_at(point!(0.6, 0, 0.8)));
+    }
 }
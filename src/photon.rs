@@ -0,0 +1,459 @@
+use std::f64::consts::PI;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    color::{Color, BLACK},
+    intersection::{Intersection, PreparedComputations},
+    ray,
+    ray::Ray,
+    sampling,
+    shapes::Shape,
+    tuple::Tuple,
+    world::World,
+    EPSILON,
+};
+
+// Light arriving on a diffuse surface after at least one specular bounce
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Photon {
+    pub position: Tuple,
+    // of travel, toward the surface
+    pub direction: Tuple,
+    pub power: Color,
+}
+
+// Caustic photons in a kd-tree: the photons are stored in tree order, each node
+// being the median of its range along the axis where the range is widest
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PhotonMap {
+    photons: Vec<Photon>,
+    axes: Vec<u8>,
+    // of the disc the caustic radiance is gathered from
+    pub radius: f64,
+}
+
+impl PhotonMap {
+    pub fn new(photons: Vec<Photon>, radius: f64) -> Self {
+        let mut photons = photons;
+        let mut axes = vec![0; photons.len()];
+        build(&mut photons, &mut axes);
+        Self {
+            photons,
+            axes,
+            radius,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.photons.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.photons.is_empty()
+    }
+
+    // Photons closer than the radius to the point
+    pub fn within(&self, point: Tuple, radius: f64) -> Vec<&Photon> {
+        let mut found = vec![];
+        self.search(0, self.photons.len(), point, radius * radius, &mut found);
+        found
+    }
+
+    fn search<'a>(
+        &'a self,
+        start: usize,
+        end: usize,
+        point: Tuple,
+        radius_squared: f64,
+        found: &mut Vec<&'a Photon>,
+    ) {
+        if start >= end {
+            return;
+        }
+        let mid = (start + end) / 2;
+        let photon = &self.photons[mid];
+        let offset = point - photon.position;
+        if offset.dot(&offset) < radius_squared {
+            found.push(photon);
+        }
+        let axis = self.axes[mid];
+        let distance = component(point, axis) - component(photon.position, axis);
+        let (near, far) = if distance < 0. {
+            ((start, mid), (mid + 1, end))
+        } else {
+            ((mid + 1, end), (start, mid))
+        };
+        self.search(near.0, near.1, point, radius_squared, found);
+        if distance * distance < radius_squared {
+            self.search(far.0, far.1, point, radius_squared, found);
+        }
+    }
+
+    // Light per unit of area arriving on the front side of the surface at the point
+    pub fn irradiance(&self, point: Tuple, normalv: Tuple) -> Color {
+        let power = self
+            .within(point, self.radius)
+            .into_iter()
+            .filter(|p| p.direction.dot(&normalv) < 0.)
+            .fold(BLACK, |acc, p| acc + p.power);
+        power / (PI * self.radius * self.radius)
+    }
+}
+
+fn build(photons: &mut [Photon], axes: &mut [u8]) {
+    if photons.is_empty() {
+        return;
+    }
+    let (min, max) = photons.iter().fold(
+        ([f64::INFINITY; 3], [f64::NEG_INFINITY; 3]),
+        |(mut min, mut max), p| {
+            for axis in 0..3 {
+                min[axis] = min[axis].min(component(p.position, axis as u8));
+                max[axis] = max[axis].max(component(p.position, axis as u8));
+            }
+            (min, max)
+        },
+    );
+    let axis = (0..3u8)
+        .max_by(|a, b| {
+            let extent = |axis: u8| max[axis as usize] - min[axis as usize];
+            extent(*a).partial_cmp(&extent(*b)).unwrap()
+        })
+        .unwrap();
+    let mid = photons.len() / 2;
+    photons.select_nth_unstable_by(mid, |a, b| {
+        component(a.position, axis)
+            .partial_cmp(&component(b.position, axis))
+            .unwrap()
+    });
+    axes[mid] = axis;
+    let (left, right) = photons.split_at_mut(mid);
+    let (left_axes, right_axes) = axes.split_at_mut(mid);
+    build(left, left_axes);
+    build(&mut right[1..], &mut right_axes[1..]);
+}
+
+fn component(t: Tuple, axis: u8) -> f64 {
+    match axis {
+        0 => t.x,
+        1 => t.y,
+        _ => t.z,
+    }
+}
+
+fn is_specular(object: &Shape) -> bool {
+    let material = object.material();
+    material.reflective > 0. || material.transparency > 0.
+}
+
+// A direction in the union of the cones (axis and half angle) and its probability
+// density over solid angle. A cone is picked in proportion to its solid angle, so the
+// density is the number of cones containing the direction over their total solid angle
+fn sample_cones(cones: &[(Tuple, f64)], rng: &mut impl Rng) -> (Tuple, f64) {
+    let solid_angle = |max_angle: f64| 2. * PI * (1. - max_angle.cos());
+    let total: f64 = cones.iter().map(|(_, a)| solid_angle(*a)).sum();
+    let mut pick = rng.gen::<f64>() * total;
+    let (axis, max_angle) = *cones
+        .iter()
+        .find(|(_, a)| {
+            pick -= solid_angle(*a);
+            pick < 0.
+        })
+        .unwrap_or_else(|| cones.last().unwrap());
+    let direction = sampling::cone_direction(axis, max_angle, rng);
+    let containing = cones
+        .iter()
+        .filter(|(axis, a)| direction.dot(&axis.normalize()) >= a.cos() - EPSILON)
+        .count()
+        .max(1);
+    (direction, containing as f64 / total)
+}
+
+impl World {
+    // Emits photons from the main light and the spot lights toward the reflective and
+    // transparent shapes, and keeps those that reach a diffuse surface through them.
+    // Shapes with infinite bounds (e.g. planes) are not aimed at.
+    // The photons are split evenly between the lights, so with fewer photons than
+    // lights some lights emit none.
+    // Each light samples the union of the cones around the shapes: a cone is picked in
+    // proportion to its solid angle, and photons are weighted by how many cones contain
+    // their direction, so overlapping shapes don't count the same light twice.
+    // Lights don't fade with distance here, so photons carry their power times the
+    // squared length of their path: their density then gives the same brightness
+    // as direct light, concentrated where the specular shapes focus it.
+    // Shadow rays stop at transparent shapes from then on, see transmittance_between
+    pub fn build_caustic_map(&mut self, photons: usize, radius: f64) {
+        let mut rng = rand::thread_rng();
+        let targets = self
+            .arena
+            .objects
+            .iter()
+            .flatten()
+            .filter(|o| !matches!(o, Shape::Group(_)) && is_specular(o))
+            .map(|o| o.world_bounds(&self.arena))
+            .filter(|b| b.min.x.is_finite() && b.min.y.is_finite() && b.min.z.is_finite())
+            .filter(|b| b.max.x.is_finite() && b.max.y.is_finite() && b.max.z.is_finite())
+            .map(|b| {
+                let center = b.min + (b.max - b.min) / 2.;
+                (center, (b.max - b.min).magnitude() / 2.)
+            })
+            .collect::<Vec<_>>();
+        let mut lights = vec![(self.light.position, None)];
+        lights.extend(self.spot_lights.iter().map(|s| (s.position, Some(s))));
+
+        let mut stored = vec![];
+        if !targets.is_empty() {
+            for (i, (position, spot)) in lights.iter().enumerate() {
+                let count = photons / lights.len() + usize::from(i < photons % lights.len());
+                if count == 0 {
+                    continue;
+                }
+                // the cone around each target, or every direction from inside it
+                let cones = targets
+                    .iter()
+                    .map(|(center, target_radius)| {
+                        let to_center = *center - *position;
+                        let distance = to_center.magnitude();
+                        if distance > *target_radius {
+                            (to_center, (target_radius / distance).asin())
+                        } else {
+                            (to_center, PI)
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                for _ in 0..count {
+                    let (direction, pdf) = sample_cones(&cones, &mut rng);
+                    let intensity = match spot {
+                        Some(spot) => spot.intensity_toward(*position + direction),
+                        None => self.light.intensity,
+                    };
+                    if intensity == BLACK {
+                        continue;
+                    }
+                    let power = intensity / (pdf * count as f64);
+                    let r = ray!(*position, direction);
+                    self.trace_photon(r, power, &mut stored, &mut rng);
+                }
+            }
+        }
+        self.caustic_map = Some(PhotonMap::new(stored, radius));
+    }
+
+    fn trace_photon(&self, r: Ray, power: Color, stored: &mut Vec<Photon>, rng: &mut impl Rng) {
+        let max_bounces = self.limits.max_depth;
+        let (mut ray, mut power) = (r, power);
+        let mut length = 0.;
+        for bounce in 0..=max_bounces {
+            let xs = self.intersect(&ray);
+            let xs_refs = xs.iter().collect::<Vec<&Intersection>>();
+            let hit = match xs
+                .iter()
+                .find(|i| i.t >= 0. && !i.object.material().is_volume())
+            {
+                Some(hit) => hit,
+                None => return,
+            };
+            let comps = hit.prepare_computations(&self.arena, &ray, &xs_refs[..]);
+            length += hit.t * ray.direction.magnitude();
+            power = power * comps.absorption();
+            let material = comps.object.material();
+
+            if bounce > 0 && material.diffuse > 0. {
+                stored.push(Photon {
+                    position: comps.point,
+                    direction: ray.direction.normalize(),
+                    power: power * length * length,
+                });
+            }
+
+            // Russian roulette between the mirror and refraction, the rest is absorbed
+            let (mut reflect_weight, mut refract_weight) =
+                (material.reflective, material.transparency);
+            if reflect_weight > 0. && refract_weight > 0. {
+                let reflectance = comps.schlick();
+                reflect_weight *= reflectance;
+                refract_weight *= 1. - reflectance;
+            }
+            let total = reflect_weight + refract_weight;
+            if total <= 0. {
+                return;
+            }
+            let scale = total.max(1.);
+            power = power * scale;
+            let u = rng.gen::<f64>() * scale;
+            ray = if u < reflect_weight {
+                ray!(comps.over_point, comps.reflectv)
+            } else if u < total {
+                match comps.refracted_direction() {
+                    Some(direction) => ray!(comps.under_point, direction),
+                    // total internal reflection
+                    None => ray!(comps.over_point, comps.reflectv),
+                }
+            } else {
+                return;
+            };
+        }
+    }
+
+    // Caustic light reflected by the diffuse term at the hit
+    pub(crate) fn caustic_lighting(&self, comps: &PreparedComputations) -> Color {
+        let map = match &self.caustic_map {
+            Some(map) if !map.is_empty() => map,
+            _ => return BLACK,
+        };
+        let material = comps.object.material();
+        if material.diffuse <= 0. {
+            return BLACK;
+        }
+        let color = material
+            .pattern
+            .color_at_object(comps.object, comps.over_point);
+        color * material.diffuse * map.irradiance(comps.point, comps.normalv)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        approx_eq,
+        color::WHITE,
+        light::PointLight,
+        material::{Material, ShadowMode},
+        matrix::Matrix,
+        plane, point, sphere, vector,
+    };
+
+    #[test]
+    fn kd_tree_finds_the_photons_within_the_radius() {
+        let mut rng = rand::thread_rng();
+        let photons = (0..500)
+            .map(|_| Photon {
+                position: point!(rng.gen::<f64>(), rng.gen::<f64>(), rng.gen::<f64>()),
+                direction: vector!(0, -1, 0),
+                power: WHITE,
+            })
+            .collect::<Vec<_>>();
+        let map = PhotonMap::new(photons.clone(), 0.1);
+        assert_eq!(500, map.len());
+        for _ in 0..20 {
+            let point = point!(rng.gen::<f64>(), rng.gen::<f64>(), rng.gen::<f64>());
+            let expected = photons
+                .iter()
+                .filter(|p| (p.position - point).magnitude() < 0.2)
+                .count();
+            assert_eq!(expected, map.within(point, 0.2).len());
+        }
+        assert!(PhotonMap::new(vec![], 0.1)
+            .within(point!(0, 0, 0), 1.)
+            .is_empty());
+    }
+
+    #[test]
+    fn irradiance_counts_the_photons_arriving_on_the_front() {
+        let photon = |direction| Photon {
+            position: point!(0, 0, 0),
+            direction,
+            power: WHITE * PI,
+        };
+        let map = PhotonMap::new(
+            vec![photon(vector!(0, -1, 0)), photon(vector!(0, 1, 0))],
+            1.,
+        );
+        assert_eq!(WHITE, map.irradiance(point!(0.5, 0, 0), vector!(0, 1, 0)));
+        assert_eq!(BLACK, map.irradiance(point!(3, 0, 0), vector!(0, 1, 0)));
+    }
+
+    fn lens_world() -> World {
+        let mut w = World::new(PointLight::new(point!(0, 10, 0), WHITE), vec![plane!()]);
+        let mut lens = sphere!();
+        // the floor is near the focal point of the ball
+        lens.set_transform(Matrix::translation(0, 1.6, 0));
+        lens.set_material(Material {
+            diffuse: 0.,
+            specular: 0.,
+            ambient: 0.,
+            transparency: 1.,
+            refractive_index: 1.5,
+            shadow: ShadowMode::Hard,
+            ..Material::default()
+        });
+        w.add_object(lens);
+        w
+    }
+
+    #[test]
+    fn glass_focuses_photons_on_the_floor() {
+        let mut w = lens_world();
+        w.build_caustic_map(5000, 0.1);
+        let map = w.caustic_map.as_ref().unwrap();
+        assert!(!map.is_empty());
+        // only stored on the floor, the lens isn't diffuse
+        assert!(map.photons.iter().all(|p| p.position.y.abs() < 0.001));
+        let focus = map.irradiance(point!(0, 0, 0), vector!(0, 1, 0));
+        let edge = map.irradiance(point!(0.8, 0, 0), vector!(0, 1, 0));
+        assert!(focus.r > 1.);
+        assert!(focus.r > edge.r * 2.);
+    }
+
+    #[test]
+    fn caustics_light_the_hard_shadow() {
+        let mut w = lens_world();
+        let r = ray!(point!(0, 0.5, -1), vector!(0, -0.5, 1).normalize());
+        // the ambient term only
+        assert_eq!(WHITE * 0.1, w.color_at(&r));
+        w.build_caustic_map(5000, 0.1);
+        assert!(w.color_at(&r).r > 1.);
+    }
+
+    #[test]
+    fn caustics_replace_the_light_through_glass() {
+        let mut w = lens_world();
+        w.apply_changes_by_index(1, |s| {
+            let material = Material {
+                shadow: ShadowMode::Transmissive,
+                ..*s.material()
+            };
+            s.set_material(material)
+        });
+        let floor = point!(0, EPSILON, 0);
+        assert_eq!(WHITE, w.transmittance_between(floor, w.light.position));
+        w.build_caustic_map(5000, 0.1);
+        // only the photons bring the light through the lens, it isn't counted twice
+        assert_eq!(BLACK, w.transmittance_between(floor, w.light.position));
+        let mut hard = lens_world();
+        hard.caustic_map = w.caustic_map.clone();
+        let r = ray!(point!(0, 0.5, -1), vector!(0, -0.5, 1).normalize());
+        assert_eq!(hard.color_at(&r), w.color_at(&r));
+    }
+
+    #[test]
+    fn overlapping_cones_are_sampled_once() {
+        let mut rng = rand::thread_rng();
+        let wide = (vector!(0, 1, 0), PI / 4.);
+        let wide_solid_angle = 2. * PI * (1. - (PI / 4.).cos());
+        // the same cone twice has the density of one
+        for _ in 0..100 {
+            let (direction, pdf) = sample_cones(&[wide, wide], &mut rng);
+            assert!(direction.y >= (PI / 4.).cos() - EPSILON);
+            assert!(approx_eq(1. / wide_solid_angle, pdf));
+        }
+        // a cone inside another: 1 / pdf averages to the solid angle of the union
+        let narrow = (vector!(0.1, 1, 0), 0.1);
+        let n = 20000;
+        let mean = (0..n)
+            .map(|_| 1. / sample_cones(&[wide, narrow], &mut rng).1)
+            .sum::<f64>()
+            / n as f64;
+        assert!((mean - wide_solid_angle).abs() < wide_solid_angle * 0.02);
+    }
+
+    #[test]
+    fn no_photons_without_specular_shapes() {
+        let mut w = World::new(PointLight::new(point!(0, 10, 0), WHITE), vec![plane!()]);
+        w.build_caustic_map(1000, 0.1);
+        assert!(w.caustic_map.unwrap().is_empty());
+    }
+}
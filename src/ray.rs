@@ -20,13 +20,22 @@ macro_rules! ray {
 pub struct Ray {
     pub origin: Tuple,
     pub direction: Tuple,
+    // in nanometers, set on rays traced for a single wavelength (see World::dispersion_samples)
+    pub wavelength: Option<f64>,
 }
 
 impl Ray {
     pub fn new(origin: Tuple, direction: Tuple) -> Self {
         assert!(origin.is_point());
         assert!(direction.is_vector());
-        Ray { origin, direction }
+        Ray {
+            origin,
+            direction,
+            wavelength: None,
+        }
+    }
+    pub fn with_wavelength(self, wavelength: Option<f64>) -> Self {
+        Ray { wavelength, ..self }
     }
     pub fn position(&self, t: impl Into<f64>) -> Tuple {
         return self.origin + self.direction * t.into();
@@ -40,6 +49,7 @@ impl Mul<Matrix> for Ray {
         Ray {
             origin: self.origin * other,
             direction: self.direction * other,
+            wavelength: self.wavelength,
         }
     }
 }
@@ -51,6 +61,7 @@ impl Mul<Matrix> for &Ray {
         Ray {
             origin: self.origin * other,
             direction: self.direction * other,
+            wavelength: self.wavelength,
         }
     }
 }
@@ -67,6 +78,14 @@ mod tests {
         let r = Ray::new(origin, direction);
         assert_eq!(origin, r.origin);
         assert_eq!(direction, r.direction);
+        assert_eq!(None, r.wavelength);
+    }
+
+    #[test]
+    fn wavelength_is_kept_by_transformations() {
+        let r = ray!(1, 2, 3; 0, 1, 0).with_wavelength(Some(550.));
+        let r2 = r * Matrix::translation(3, 4, 5);
+        assert_eq!(Some(550.), r2.wavelength);
     }
 
     #[test]
@@ -0,0 +1,101 @@
+use std::f64::consts::PI;
+
+use rand::Rng;
+
+use crate::{tuple::Tuple, vector};
+
+// Two unit vectors perpendicular to n and to each other
+pub fn orthonormal_basis(n: Tuple) -> (Tuple, Tuple) {
+    let a = if n.x.abs() > 0.9 {
+        vector!(0, 1, 0)
+    } else {
+        vector!(1, 0, 0)
+    };
+    let t = n.cross(&a).normalize();
+    let b = n.cross(&t);
+    (t, b)
+}
+
+// Direction in the hemisphere around the normal, with pdf = cos(theta) / PI
+pub fn cosine_hemisphere(normal: Tuple, rng: &mut impl Rng) -> Tuple {
+    let u1: f64 = rng.gen();
+    let u2: f64 = rng.gen();
+    let r = u1.sqrt();
+    let phi = 2. * PI * u2;
+    let (t, b) = orthonormal_basis(normal);
+    (t * (r * phi.cos()) + b * (r * phi.sin()) + normal * (1. - u1).sqrt()).normalize()
+}
+
+// Uniformly distributed direction within max_angle (radians) of the axis
+pub fn cone_direction(axis: Tuple, max_angle: f64, rng: &mut impl Rng) -> Tuple {
+    let axis = axis.normalize();
+    let cos_max = max_angle.cos();
+    let cos_theta = 1. - rng.gen::<f64>() * (1. - cos_max);
+    let sin_theta = (1. - cos_theta * cos_theta).max(0.).sqrt();
+    let phi = 2. * PI * rng.gen::<f64>();
+    let (t, b) = orthonormal_basis(axis);
+    (t * (sin_theta * phi.cos()) + b * (sin_theta * phi.sin()) + axis * cos_theta).normalize()
+}
+
+// Direction in the lobe of a rough surface around the perfect one: roughness 0 keeps it,
+// 1 spreads over a hemisphere. Samples crossing the surface are mirrored back to the
+// side that `side` points to
+pub fn rough_direction(direction: Tuple, roughness: f64, side: Tuple, rng: &mut impl Rng) -> Tuple {
+    if roughness <= 0. {
+        return direction;
+    }
+    let d = cone_direction(direction, roughness.min(1.) * PI / 2., rng);
+    let below = d.dot(&side);
+    if below < 0. {
+        d - side * (2. * below)
+    } else {
+        d
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::approx_eq;
+
+    #[test]
+    fn basis_is_orthonormal() {
+        for n in &[
+            vector!(0, 1, 0),
+            vector!(1, 0, 0),
+            vector!(1, 2, 3).normalize(),
+        ] {
+            let (t, b) = orthonormal_basis(*n);
+            assert!(approx_eq(1., t.magnitude()));
+            assert!(approx_eq(1., b.magnitude()));
+            assert!(approx_eq(0., t.dot(n)));
+            assert!(approx_eq(0., b.dot(n)));
+            assert!(approx_eq(0., t.dot(&b)));
+        }
+    }
+
+    #[test]
+    fn cosine_samples_are_in_the_hemisphere() {
+        let mut rng = rand::thread_rng();
+        let normal = vector!(0, 0, -1);
+        for _ in 0..100 {
+            let d = cosine_hemisphere(normal, &mut rng);
+            assert!(d.is_vector());
+            assert!(approx_eq(1., d.magnitude()));
+            assert!(d.dot(&normal) >= 0.);
+        }
+    }
+
+    #[test]
+    fn cone_samples_stay_within_the_angle() {
+        let mut rng = rand::thread_rng();
+        let axis = vector!(1, 1, 0).normalize();
+        let max_angle = PI / 8.;
+        for _ in 0..100 {
+            let d = cone_direction(axis, max_angle, &mut rng);
+            assert!(approx_eq(1., d.magnitude()));
+            assert!(d.dot(&axis) >= max_angle.cos() - 1e-9);
+        }
+        assert_eq!(axis, cone_direction(axis, 0., &mut rng));
+    }
+}
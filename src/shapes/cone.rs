@@ -1,4 +1,6 @@
-use std::mem;
+use std::{f64::consts::PI, mem};
+
+use serde::{Deserialize, Serialize};
 
 use crate::{
     bounds::BoundingBox,
@@ -6,11 +8,12 @@ use crate::{
     matrix::{Matrix, IDENTITY_MATRIX},
     point,
     ray::Ray,
+    shapes::{azimuthal_tangent, cap_sample_count, disk_samples, SurfaceSample},
     tuple::Tuple,
     vector, EPSILON,
 };
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct Cone {
     pub minimum: f64,
     pub maximum: f64,
@@ -122,12 +125,67 @@ impl Cone {
         (x * x + z * z) <= radius * radius
     }
 
+    pub fn local_tangent_at(&self, local_point: Tuple) -> Tuple {
+        azimuthal_tangent(local_point)
+    }
+
     pub fn local_normal_at(&self, local_point: Tuple) -> Tuple {
         let y = (local_point.x.powi(2) + local_point.z.powi(2)).sqrt();
         let y = if local_point.y > 0.0 { -y } else { y };
         vector!(local_point.x, y, local_point.z)
     }
 
+    // Grid of points on the side, and points on the caps of closed cones, split by area.
+    // Used when the cone is a light source. Infinite cones can't be sampled
+    pub fn local_surface_samples(&self, n: usize) -> Vec<SurfaceSample> {
+        if !self.minimum.is_finite() || !self.maximum.is_finite() || n == 0 {
+            return vec![];
+        }
+        let (minimum, maximum) = (self.minimum, self.maximum);
+        // the side is at 45 degrees, sqrt(2) times wider than its height
+        let side_area = PI * 2f64.sqrt() * (maximum * maximum.abs() - minimum * minimum.abs());
+        let cap_areas = [PI * minimum * minimum, PI * maximum * maximum];
+        let cap_samples = if self.closed {
+            let total = side_area + cap_areas[0] + cap_areas[1];
+            cap_areas.map(|area| cap_sample_count(n, area, total))
+        } else {
+            [0, 0]
+        };
+        let side_samples = n.saturating_sub(cap_samples[0] + cap_samples[1]).max(1);
+
+        let rows = (side_samples as f64).sqrt().ceil() as usize;
+        let columns = side_samples.div_ceil(rows);
+        let height = maximum - minimum;
+        let mut samples = Vec::with_capacity(rows * columns + cap_samples[0] + cap_samples[1]);
+        for i in 0..rows {
+            let y = minimum + height * (i as f64 + 0.5) / rows as f64;
+            let radius = y.abs();
+            let area = 2. * PI * radius * 2f64.sqrt() * height / (rows * columns) as f64;
+            for j in 0..columns {
+                let (sin, cos) = (2. * PI * (j as f64 + 0.5) / columns as f64).sin_cos();
+                let point = point!(radius * cos, y, radius * sin);
+                samples.push(SurfaceSample {
+                    point,
+                    normal: self.local_normal_at(point).normalize(),
+                    area,
+                });
+            }
+        }
+        samples.extend(disk_samples(
+            minimum.abs(),
+            minimum,
+            vector!(0, -1, 0),
+            cap_samples[0],
+        ));
+        samples.extend(disk_samples(
+            maximum.abs(),
+            maximum,
+            vector!(0, 1, 0),
+            cap_samples[1],
+        ));
+        samples
+    }
+
     pub fn bounds(&self) -> BoundingBox {
         let a = self.minimum.abs();
         let b = self.maximum.abs();
@@ -231,4 +289,28 @@ mod tests {
             c.bounds()
         );
     }
+
+    #[test]
+    fn surface_samples_of_a_bounded_cone() {
+        assert!(Cone::new().local_surface_samples(16).is_empty());
+        let c = Cone::new_with_min_max(-1, 0);
+        let samples = c.local_surface_samples(16);
+        assert_eq!(16, samples.len());
+        for s in &samples {
+            let p = s.point;
+            assert!(approx_eq(p.y * p.y, p.x * p.x + p.z * p.z));
+            assert!(p.y > -1. && p.y < 0.);
+        }
+        let area: f64 = samples.iter().map(|s| s.area).sum();
+        assert!(approx_eq(PI * 2f64.sqrt(), area));
+
+        // the cap of a closed cone faces away from the tip
+        let closed = Cone::new_with_min_max_closed(-1, 0, true);
+        let samples = closed.local_surface_samples(16);
+        assert!(samples
+            .iter()
+            .any(|s| s.point.y == -1. && s.normal == vector!(0, -1, 0)));
+        let area: f64 = samples.iter().map(|s| s.area).sum();
+        assert!(approx_eq(PI * 2f64.sqrt() + PI, area));
+    }
 }
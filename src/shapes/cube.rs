@@ -1,14 +1,17 @@
+use serde::{Deserialize, Serialize};
+
 use crate::{
     bounds::BoundingBox,
     material::Material,
     matrix::{Matrix, IDENTITY_MATRIX},
     point,
     ray::Ray,
+    shapes::SurfaceSample,
     tuple::Tuple,
     vector,
 };
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct Cube {
     pub transform: Matrix,
     pub material: Material,
@@ -55,6 +58,43 @@ impl Cube {
         }
     }
 
+    // x on the faces perpendicular to y and z, z on the other two
+    pub fn local_tangent_at(&self, local_point: Tuple) -> Tuple {
+        if self.local_normal_at(local_point).x != 0. {
+            vector!(0, 0, 1)
+        } else {
+            vector!(1, 0, 0)
+        }
+    }
+
+    // Grid of points on each face, used when the cube is a light source
+    pub fn local_surface_samples(&self, n: usize) -> Vec<SurfaceSample> {
+        let k = ((n as f64 / 6.).sqrt().ceil() as usize).max(1);
+        let coord = |i: usize| -1. + 2. * (i as f64 + 0.5) / k as f64;
+        let area = 4. / (k * k) as f64;
+        let mut samples = Vec::with_capacity(6 * k * k);
+        for i in 0..k {
+            for j in 0..k {
+                let (u, v) = (coord(i), coord(j));
+                for (point, normal) in [
+                    (point!(1, u, v), vector!(1, 0, 0)),
+                    (point!(-1, u, v), vector!(-1, 0, 0)),
+                    (point!(u, 1, v), vector!(0, 1, 0)),
+                    (point!(u, -1, v), vector!(0, -1, 0)),
+                    (point!(u, v, 1), vector!(0, 0, 1)),
+                    (point!(u, v, -1), vector!(0, 0, -1)),
+                ] {
+                    samples.push(SurfaceSample {
+                        point,
+                        normal,
+                        area,
+                    });
+                }
+            }
+        }
+        samples
+    }
+
     pub fn bounds(&self) -> BoundingBox {
         BoundingBox::new(point!(-1, -1, -1), point!(1, 1, 1))
     }
@@ -116,4 +156,25 @@ mod tests {
         t(point!(1, 1, 1), vector!(1, 0, 0));
         t(point!(-1, -1, -1), vector!(-1, 0, 0));
     }
+
+    #[test]
+    fn surface_samples_cover_every_face() {
+        let c = Cube::new();
+        let samples = c.local_surface_samples(24);
+        assert_eq!(24, samples.len());
+        for s in &samples {
+            let p = s.point;
+            assert_eq!(1., p.x.abs().max(p.y.abs()).max(p.z.abs()));
+            assert_eq!(1., s.area);
+        }
+        for axis in 0..3 {
+            for side in &[-1., 1.] {
+                let on_face = |s: &&SurfaceSample| {
+                    [s.point.x, s.point.y, s.point.z][axis] == *side
+                        && [s.normal.x, s.normal.y, s.normal.z][axis] == *side
+                };
+                assert_eq!(4, samples.iter().filter(on_face).count());
+            }
+        }
+    }
 }
@@ -1,4 +1,6 @@
-use std::mem;
+use std::{f64::consts::PI, mem};
+
+use serde::{Deserialize, Serialize};
 
 use crate::{
     bounds::BoundingBox,
@@ -6,11 +8,12 @@ use crate::{
     matrix::{Matrix, IDENTITY_MATRIX},
     point,
     ray::Ray,
+    shapes::{azimuthal_tangent, cap_sample_count, disk_samples, SurfaceSample},
     tuple::Tuple,
     vector, EPSILON,
 };
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct Cylinder {
     pub minimum: f64,
     pub maximum: f64,
@@ -118,6 +121,10 @@ impl Cylinder {
         (x * x + z * z) <= 1.
     }
 
+    pub fn local_tangent_at(&self, local_point: Tuple) -> Tuple {
+        azimuthal_tangent(local_point)
+    }
+
     pub fn local_normal_at(&self, local_point: Tuple) -> Tuple {
         // compute the square of the distance from the y axis
         let dist = local_point.x.powi(2) + local_point.z.powi(2);
@@ -131,6 +138,51 @@ impl Cylinder {
         }
     }
 
+    // Grid of points on the side, and points on the caps of closed cylinders, split
+    // by area. Used when the cylinder is a light source. Infinite cylinders can't be sampled
+    pub fn local_surface_samples(&self, n: usize) -> Vec<SurfaceSample> {
+        if !self.minimum.is_finite() || !self.maximum.is_finite() || n == 0 {
+            return vec![];
+        }
+        let height = self.maximum - self.minimum;
+        let side_area = 2. * PI * height;
+        let cap_samples = if self.closed {
+            cap_sample_count(n, PI, side_area + 2. * PI)
+        } else {
+            0
+        };
+        let side_samples = n.saturating_sub(2 * cap_samples).max(1);
+
+        let rows = (side_samples as f64).sqrt().ceil() as usize;
+        let columns = side_samples.div_ceil(rows);
+        let area = side_area / (rows * columns) as f64;
+        let mut samples = Vec::with_capacity(rows * columns + 2 * cap_samples);
+        for i in 0..rows {
+            let y = self.minimum + height * (i as f64 + 0.5) / rows as f64;
+            for j in 0..columns {
+                let phi = 2. * PI * (j as f64 + 0.5) / columns as f64;
+                samples.push(SurfaceSample {
+                    point: point!(phi.cos(), y, phi.sin()),
+                    normal: vector!(phi.cos(), 0, phi.sin()),
+                    area,
+                });
+            }
+        }
+        samples.extend(disk_samples(
+            1.,
+            self.minimum,
+            vector!(0, -1, 0),
+            cap_samples,
+        ));
+        samples.extend(disk_samples(
+            1.,
+            self.maximum,
+            vector!(0, 1, 0),
+            cap_samples,
+        ));
+        samples
+    }
+
     pub fn bounds(&self) -> BoundingBox {
         BoundingBox::new(point!(-1, self.minimum, -1), point!(1, self.maximum, 1))
     }
@@ -274,4 +326,38 @@ mod tests {
             c.bounds()
         );
     }
+
+    #[test]
+    fn surface_samples_of_a_bounded_cylinder() {
+        assert!(Cylinder::new().local_surface_samples(16).is_empty());
+        let c = Cylinder::new_with_min_max(1, 2);
+        let samples = c.local_surface_samples(16);
+        assert_eq!(16, samples.len());
+        for s in &samples {
+            let p = s.point;
+            assert!(approx_eq(1., p.x * p.x + p.z * p.z));
+            assert!(p.y > 1. && p.y < 2.);
+        }
+        let area: f64 = samples.iter().map(|s| s.area).sum();
+        assert!(approx_eq(2. * PI, area));
+    }
+
+    #[test]
+    fn surface_samples_of_a_closed_cylinder_include_the_caps() {
+        let c = Cylinder::new_with_min_max_closed(1, 2, true);
+        let samples = c.local_surface_samples(32);
+        let on_cap = |y: f64, normal: Tuple| {
+            samples
+                .iter()
+                .filter(|s| s.point.y == y && s.normal == normal)
+                .filter(|s| s.point.x * s.point.x + s.point.z * s.point.z <= 1.)
+                .count()
+        };
+        // each cap has a quarter of the area
+        assert_eq!(8, on_cap(1., vector!(0, -1, 0)));
+        assert_eq!(8, on_cap(2., vector!(0, 1, 0)));
+        assert_eq!(32, samples.len());
+        let area: f64 = samples.iter().map(|s| s.area).sum();
+        assert!(approx_eq(4. * PI, area));
+    }
 }
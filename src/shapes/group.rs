@@ -1,14 +1,17 @@
+use serde::{Deserialize, Serialize};
+
 use crate::{
     arena::Arena,
     bounds::BoundingBox,
     intersection::Intersection,
     matrix::{Matrix, IDENTITY_MATRIX},
     ray::Ray,
+    stats,
 };
 
 use super::Shape;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct Group {
     id: usize,
     pub transform: Matrix,
@@ -56,6 +59,7 @@ impl Group {
     }
 
     pub fn local_intersect<'a>(&self, arena: &'a Arena, local_ray: &Ray) -> Vec<Intersection<'a>> {
+        stats::record(|s| s.bounding_box_tests += 1);
         if self.bounds(arena).intersects(&local_ray) {
             let mut result = self
                 .children_ids
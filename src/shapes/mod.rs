@@ -5,19 +5,73 @@ pub mod group;
 pub mod plane;
 pub mod sphere;
 
+use serde::{Deserialize, Serialize};
+
 use crate::{
     arena::Arena,
     bounds::BoundingBox,
     intersection::Intersection,
     material::Material,
     matrix::Matrix,
+    point,
     ray::Ray,
+    sampling,
     shapes::{
         cone::Cone, cube::Cube, cylinder::Cylinder, group::Group, plane::Plane, sphere::Sphere,
     },
+    stats,
     tuple::Tuple,
+    vector, EPSILON,
 };
 
+// A point spread on the surface of an emissive shape, with the outward normal and
+// the area of the patch of surface it stands for
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SurfaceSample {
+    pub point: Tuple,
+    pub normal: Tuple,
+    pub area: f64,
+}
+
+// Evenly spread points on the disk of the given radius across the y axis (sunflower
+// pattern), the caps of cylinders and cones
+pub(crate) fn disk_samples(radius: f64, y: f64, normal: Tuple, n: usize) -> Vec<SurfaceSample> {
+    if radius <= 0. || n == 0 {
+        return vec![];
+    }
+    let golden_angle = std::f64::consts::PI * (3. - 5f64.sqrt());
+    let area = std::f64::consts::PI * radius * radius / n as f64;
+    (0..n)
+        .map(|i| {
+            let r = radius * ((i as f64 + 0.5) / n as f64).sqrt();
+            let phi = golden_angle * i as f64;
+            SurfaceSample {
+                point: point!(r * phi.cos(), y, r * phi.sin()),
+                normal,
+                area,
+            }
+        })
+        .collect()
+}
+
+// How many of n samples go to a cap, in proportion to its share of the area
+pub(crate) fn cap_sample_count(n: usize, cap_area: f64, total_area: f64) -> usize {
+    if cap_area <= 0. || total_area <= 0. {
+        return 0;
+    }
+    ((n as f64 * cap_area / total_area).round() as usize).max(1)
+}
+
+// Direction of increasing longitude around the y axis, the u tangent of round shapes.
+// Arbitrary on the axis itself
+pub(crate) fn azimuthal_tangent(local_point: Tuple) -> Tuple {
+    if local_point.x.abs() < EPSILON && local_point.z.abs() < EPSILON {
+        vector!(1, 0, 0)
+    } else {
+        vector!(local_point.z, 0, -local_point.x)
+    }
+}
+
 #[macro_export]
 macro_rules! sphere {
     () => {
@@ -94,7 +148,7 @@ enum vs boxed trait polymorphism:
 https://stackoverflow.com/questions/52240099/should-i-use-enums-or-boxed-trait-objects-to-emulate-polymorphism
 */
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub enum Shape {
     Sphere(Sphere),
     Plane(Plane),
@@ -107,6 +161,10 @@ pub enum Shape {
 impl Shape {
     pub fn intersect<'a>(&'a self, arena: &'a Arena, r: &Ray) -> Vec<Intersection> {
         let local_ray = r * self.transform().inverse().unwrap();
+        // groups are counted as bounding box tests
+        if !matches!(self, Shape::Group(_)) {
+            stats::record(|s| s.intersection_tests += 1);
+        }
         match self {
             Shape::Sphere(s) => self.as_intersections(s.local_intersect(&local_ray)),
             Shape::Plane(p) => self.as_intersections(p.local_intersect(&local_ray)),
@@ -156,6 +214,30 @@ impl Shape {
         self.normal_to_world(arena, local_normal)
     }
 
+    // Direction of the u surface coordinate, the first tangent for anisotropic shading.
+    // Not necessarily normalized against the normal
+    pub fn tangent_at<'a>(&'a self, arena: &'a Arena, p: Tuple) -> Tuple {
+        let local_point = self.world_to_object(arena, p);
+        let local_tangent = match self {
+            Shape::Sphere(s) => s.local_tangent_at(local_point),
+            Shape::Plane(p) => p.local_tangent_at(local_point),
+            Shape::Cube(c) => c.local_tangent_at(local_point),
+            Shape::Cylinder(c) => c.local_tangent_at(local_point),
+            Shape::Cone(c) => c.local_tangent_at(local_point),
+            Shape::Group(_) => panic!("Called tangent_at on a group"),
+        };
+        self.vector_to_world(arena, local_tangent).normalize()
+    }
+
+    // Tangent vectors follow the transformation itself (unlike normals)
+    fn vector_to_world<'a>(&'a self, arena: &'a Arena, v: Tuple) -> Tuple {
+        let v = *self.transform() * v;
+        match self.get_parent(arena) {
+            Some(parent) => parent.vector_to_world(arena, v),
+            None => v,
+        }
+    }
+
     fn world_to_object<'a>(&'a self, arena: &'a Arena, point: Tuple) -> Tuple {
         let mut point = point;
         if let Some(parent) = self.get_parent(arena) {
@@ -164,6 +246,40 @@ impl Shape {
         self.transform().inverse().unwrap() * point
     }
 
+    fn object_to_world<'a>(&'a self, arena: &'a Arena, point: Tuple) -> Tuple {
+        let point = *self.transform() * point;
+        match self.get_parent(arena) {
+            Some(parent) => parent.object_to_world(arena, point),
+            None => point,
+        }
+    }
+
+    // World space points spread on the surface, used to light the scene with emissive shapes.
+    // Empty for planes, infinite shapes and groups
+    pub fn surface_samples<'a>(&'a self, arena: &'a Arena, n: usize) -> Vec<SurfaceSample> {
+        let local_samples = match self {
+            Shape::Sphere(s) => s.local_surface_samples(n),
+            Shape::Cube(c) => c.local_surface_samples(n),
+            Shape::Cylinder(c) => c.local_surface_samples(n),
+            Shape::Cone(c) => c.local_surface_samples(n),
+            Shape::Plane(_) | Shape::Group(_) => vec![],
+        };
+        local_samples
+            .into_iter()
+            .map(|s| {
+                // the patch is stretched like two tangents across it
+                let (t, b) = sampling::orthonormal_basis(s.normal.normalize());
+                let t = self.object_to_world(arena, t);
+                let b = self.object_to_world(arena, b);
+                SurfaceSample {
+                    point: self.object_to_world(arena, s.point),
+                    normal: self.normal_to_world(arena, s.normal),
+                    area: s.area * t.cross(&b).magnitude(),
+                }
+            })
+            .collect()
+    }
+
     fn normal_to_world<'a>(&'a self, arena: &'a Arena, normal: Tuple) -> Tuple {
         let mut normal = self.transform().inverse().unwrap().transpose() * normal;
         normal.w = 0.;
@@ -234,6 +350,16 @@ impl Shape {
         self.bounds(arena).transform(*self.transform())
     }
 
+    pub fn world_bounds<'a>(&'a self, arena: &'a Arena) -> BoundingBox {
+        let mut bounds = self.parent_space_bounds(arena);
+        let mut parent = self.get_parent(arena);
+        while let Some(shape) = parent {
+            bounds = bounds.transform(*shape.transform());
+            parent = shape.get_parent(arena);
+        }
+        bounds
+    }
+
     pub fn divide(&mut self, threshold: usize, arena: &mut Arena) {
         match self {
             Shape::Group(g) => g.divide(threshold, arena),
@@ -248,7 +374,7 @@ mod tests {
     use std::f64::consts::PI;
 
     use super::*;
-    use crate::{material::MaterialBuilder, matrix::IDENTITY_MATRIX, point, sphere, vector};
+    use crate::{material::MaterialBuilder, matrix::IDENTITY_MATRIX, point, vector};
 
     fn test_shape() -> Shape {
         let mut rng = rand::thread_rng();
@@ -396,4 +522,52 @@ mod tests {
         assert_eq!(point!(-4.5, -3, -5), bbox.min, "min");
         assert_eq!(point!(4, 7, 4.5), bbox.max, "max");
     }
+
+    #[test]
+    fn surface_samples_are_in_world_space() {
+        let mut arena = Arena::new();
+
+        let mut s = sphere!();
+        s.set_transform(Matrix::translation(5, 0, 0));
+        let s_id = arena.add(s);
+
+        let g_id = arena.next_id();
+        let mut g_inner = Group::new(g_id);
+        g_inner.add_child(s_id, &mut arena);
+        let mut g = Shape::Group(g_inner);
+        g.set_transform(Matrix::scaling(2, 2, 2));
+        arena.add_with_id(g_id, g);
+
+        let samples = arena.get(s_id).surface_samples(&arena, 8);
+        assert_eq!(8, samples.len());
+        for s in &samples {
+            assert!(crate::approx_eq(
+                2.,
+                (s.point - point!(10, 0, 0)).magnitude()
+            ));
+            assert_eq!((s.point - point!(10, 0, 0)).normalize(), s.normal);
+        }
+        // the area of a sphere of radius 2
+        let area: f64 = samples.iter().map(|s| s.area).sum();
+        assert!(crate::approx_eq(16. * PI, area));
+        assert!(arena.get(g_id).surface_samples(&arena, 8).is_empty());
+        assert!(plane!().surface_samples(&arena, 8).is_empty());
+    }
+
+    #[test]
+    fn tangents_follow_the_u_coordinate() {
+        let arena = Arena::new();
+        // around the y axis on round shapes
+        let s = sphere!();
+        assert_eq!(vector!(0, 0, -1), s.tangent_at(&arena, point!(1, 0, 0)));
+        assert_eq!(vector!(1, 0, 0), s.tangent_at(&arena, point!(0, 1, 0)));
+        let c = cube!();
+        assert_eq!(vector!(0, 0, 1), c.tangent_at(&arena, point!(1, 0.5, 0)));
+        assert_eq!(vector!(1, 0, 0), c.tangent_at(&arena, point!(0.5, 1, 0)));
+
+        // tangents are transformed with the shape
+        let mut p = plane!();
+        p.set_transform(Matrix::rotation_y(PI / 2.));
+        assert_eq!(vector!(0, 0, -1), p.tangent_at(&arena, point!(3, 0, 2)));
+    }
 }
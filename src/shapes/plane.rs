@@ -1,3 +1,5 @@
+use serde::{Deserialize, Serialize};
+
 use crate::{
     bounds::BoundingBox,
     material::Material,
@@ -8,7 +10,7 @@ use crate::{
     vector, EPSILON,
 };
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct Plane {
     pub transform: Matrix,
     pub material: Material,
@@ -34,6 +36,10 @@ impl Plane {
         }
     }
 
+    pub fn local_tangent_at(&self, _local_point: Tuple) -> Tuple {
+        vector!(1, 0, 0)
+    }
+
     pub fn local_normal_at(&self, _local_point: Tuple) -> Tuple {
         vector!(0, 1, 0)
     }
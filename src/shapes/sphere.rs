@@ -1,13 +1,17 @@
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+
 use crate::{
     bounds::BoundingBox,
     material::Material,
     matrix::{Matrix, IDENTITY_MATRIX},
     point,
     ray::Ray,
+    shapes::{azimuthal_tangent, SurfaceSample},
     tuple::Tuple,
 };
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct Sphere {
     pub transform: Matrix,
     pub material: Material,
@@ -40,10 +44,32 @@ impl Sphere {
         }
     }
 
+    pub fn local_tangent_at(&self, local_point: Tuple) -> Tuple {
+        azimuthal_tangent(local_point)
+    }
+
     pub fn local_normal_at(&self, local_point: Tuple) -> Tuple {
         local_point - point!()
     }
 
+    // Evenly spread points (Fibonacci lattice), used when the sphere is a light source
+    pub fn local_surface_samples(&self, n: usize) -> Vec<SurfaceSample> {
+        let golden_angle = PI * (3. - 5f64.sqrt());
+        (0..n)
+            .map(|i| {
+                let y = 1. - 2. * (i as f64 + 0.5) / n as f64;
+                let radius = (1. - y * y).sqrt();
+                let phi = golden_angle * i as f64;
+                let point = point!(radius * phi.cos(), y, radius * phi.sin());
+                SurfaceSample {
+                    point,
+                    normal: point - point!(),
+                    area: 4. * PI / n as f64,
+                }
+            })
+            .collect()
+    }
+
     pub fn bounds(&self) -> BoundingBox {
         BoundingBox::new(point!(-1, -1, -1), point!(1, 1, 1))
     }
@@ -219,4 +245,16 @@ mod tests {
         s.set_material(MaterialBuilder::default().ambient(1).build().unwrap());
         assert_eq!(1., s.material().ambient);
     }
+
+    #[test]
+    fn surface_samples_are_on_the_sphere() {
+        let s = Sphere::new();
+        let samples = s.local_surface_samples(20);
+        assert_eq!(20, samples.len());
+        for s in samples {
+            assert!(s.point.is_point());
+            assert!(crate::approx_eq(1., (s.point - point!()).magnitude()));
+            assert!(crate::approx_eq(PI / 5., s.area));
+        }
+    }
 }
@@ -0,0 +1,204 @@
+use std::f64::consts::PI;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    color::{Color, BLACK},
+    light::PointLight,
+    material::RGB_WAVELENGTHS,
+    tuple::Tuple,
+    vector,
+};
+
+// far enough for the light to reach every point in the scene from the same direction
+const SUN_DISTANCE: f64 = 1e6;
+// lowest cosine of the view zenith angle, the Perez function diverges at the horizon
+const HORIZON: f64 = 0.01;
+
+// Preetham et al., "A Practical Analytic Model for Daylight": the sky's luminance
+// and chromaticity from the sun position and the turbidity (haziness, 2 clear to 10 hazy).
+// As a background it is the sky color; the World also shades a sun light from it
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Sky {
+    // of the sun above the horizon, in radians
+    pub elevation: f64,
+    // of the sun around the y axis in radians, 0 toward +z and PI/2 toward +x
+    pub azimuth: f64,
+    pub turbidity: f64,
+    // from the model's kcd/m^2 to scene colors
+    pub exposure: f64,
+    // of the sun light before it crosses the atmosphere
+    pub sun_intensity: f64,
+}
+
+impl Sky {
+    pub fn new(elevation: f64, azimuth: f64, turbidity: f64) -> Self {
+        Self {
+            elevation,
+            azimuth,
+            turbidity,
+            exposure: 0.05,
+            sun_intensity: 1.,
+        }
+    }
+
+    pub fn sun_direction(&self) -> Tuple {
+        let (sin_e, cos_e) = self.elevation.sin_cos();
+        vector!(
+            cos_e * self.azimuth.sin(),
+            sin_e,
+            cos_e * self.azimuth.cos()
+        )
+    }
+
+    pub fn color_in(&self, direction: Tuple) -> Color {
+        // below the horizon, the sky is continued with its horizon color
+        let mut d = direction.normalize();
+        if d.y < HORIZON {
+            let horizontal = (d.x * d.x + d.z * d.z).sqrt();
+            let (x, z) = if horizontal > 0. {
+                (d.x / horizontal, d.z / horizontal)
+            } else {
+                (0., 1.)
+            };
+            let scale = (1. - HORIZON * HORIZON).sqrt();
+            d = vector!(x * scale, HORIZON, z * scale);
+        }
+        let cos_theta = d.y;
+        let theta_s = PI / 2. - self.elevation.clamp(-PI / 2., PI / 2.);
+        let gamma = d.dot(&self.sun_direction()).clamp(-1., 1.).acos();
+        let t = self.turbidity;
+
+        let perez = |[a, b, c, d, e]: [f64; 5], zenith: f64| {
+            let f = |cos_theta: f64, gamma: f64| {
+                (1. + a * (b / cos_theta).exp())
+                    * (1. + c * (d * gamma).exp() + e * gamma.cos().powi(2))
+            };
+            zenith * f(cos_theta, gamma) / f(1., theta_s)
+        };
+
+        let chi = (4. / 9. - t / 120.) * (PI - 2. * theta_s);
+        let zenith_luminance = ((4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192).max(0.);
+        let cubic = |[a, b, c, d]: [f64; 4]| ((a * theta_s + b) * theta_s + c) * theta_s + d;
+        let zenith_x = t * t * cubic([0.00166, -0.00375, 0.00209, 0.])
+            + t * cubic([-0.02903, 0.06377, -0.03202, 0.00394])
+            + cubic([0.11693, -0.21196, 0.06052, 0.25886]);
+        let zenith_y = t * t * cubic([0.00275, -0.00610, 0.00317, 0.])
+            + t * cubic([-0.04214, 0.08970, -0.04153, 0.00516])
+            + cubic([0.15346, -0.26756, 0.06670, 0.26688]);
+
+        let luminance = perez(
+            [
+                0.1787 * t - 1.4630,
+                -0.3554 * t + 0.4275,
+                -0.0227 * t + 5.3251,
+                0.1206 * t - 2.5771,
+                -0.0670 * t + 0.3703,
+            ],
+            zenith_luminance,
+        );
+        let x = perez(
+            [
+                -0.0193 * t - 0.2592,
+                -0.0665 * t + 0.0008,
+                -0.0004 * t + 0.2125,
+                -0.0641 * t - 0.8989,
+                -0.0033 * t + 0.0452,
+            ],
+            zenith_x,
+        );
+        let y = perez(
+            [
+                -0.0167 * t - 0.2608,
+                -0.0950 * t + 0.0092,
+                -0.0079 * t + 0.2102,
+                -0.0441 * t - 1.6537,
+                -0.0109 * t + 0.0529,
+            ],
+            zenith_y,
+        );
+        xyy_to_rgb(x, y, luminance * self.exposure)
+    }
+
+    // Sun light after the atmosphere's Rayleigh and aerosol extinction, black once set
+    pub fn sun_color(&self) -> Color {
+        if self.elevation <= 0. {
+            return BLACK;
+        }
+        let theta_s = PI / 2. - self.elevation.min(PI / 2.);
+        // relative optical mass, Kasten's formula
+        let mass = 1. / (theta_s.cos() + 0.15 * (93.885 - theta_s.to_degrees()).powf(-1.253));
+        let beta = 0.04608 * self.turbidity - 0.04586;
+        let [r, g, b] = RGB_WAVELENGTHS.map(|nm| {
+            let micrometers = nm / 1000.;
+            let rayleigh = (-0.008735 * micrometers.powf(-4.08) * mass).exp();
+            let aerosol = (-beta * micrometers.powf(-1.3) * mass).exp();
+            rayleigh * aerosol
+        });
+        Color::new(r, g, b) * self.sun_intensity
+    }
+
+    // The sun as a point light far away along its direction from the point
+    pub fn sun_light(&self, point: Tuple) -> PointLight {
+        PointLight::new(
+            point + self.sun_direction() * SUN_DISTANCE,
+            self.sun_color(),
+        )
+    }
+}
+
+// CIE xyY to linear sRGB
+fn xyy_to_rgb(x: f64, y: f64, luminance: f64) -> Color {
+    if y <= 0. {
+        return BLACK;
+    }
+    let big_x = x / y * luminance;
+    let big_z = (1. - x - y) / y * luminance;
+    Color::new(
+        (3.2406 * big_x - 1.5372 * luminance - 0.4986 * big_z).max(0.),
+        (-0.9689 * big_x + 1.8758 * luminance + 0.0415 * big_z).max(0.),
+        (0.0557 * big_x - 0.2040 * luminance + 1.0570 * big_z).max(0.),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{approx_eq, point};
+
+    #[test]
+    fn sun_direction_from_elevation_and_azimuth() {
+        let up = Sky::new(PI / 2., 0., 3.);
+        assert_eq!(vector!(0, 1, 0), up.sun_direction());
+        let east = Sky::new(0., PI / 2., 3.);
+        assert_eq!(vector!(1, 0, 0), east.sun_direction());
+        let light = Sky::new(PI / 4., 0., 3.).sun_light(point!(0, 0, 0));
+        assert!(approx_eq(light.position.y, light.position.z));
+    }
+
+    #[test]
+    fn clear_sky_is_blue_and_brighter_around_the_sun() {
+        let sky = Sky::new(PI / 4., 0., 3.);
+        let zenith = sky.color_in(vector!(0, 1, 0));
+        assert!(zenith.b > zenith.r);
+        let near_sun = sky.color_in(sky.sun_direction() + vector!(0.05, 0, 0));
+        let away = sky.color_in(vector!(0, 0.7, -0.7));
+        assert!(near_sun.r + near_sun.g + near_sun.b > away.r + away.g + away.b);
+        // continued below the horizon
+        assert_eq!(
+            sky.color_in(vector!(1, 0.005, 0)),
+            sky.color_in(vector!(1, -1, 0))
+        );
+    }
+
+    #[test]
+    fn sun_reddens_toward_the_horizon() {
+        let high = Sky::new(PI / 3., 0., 3.).sun_color();
+        let low = Sky::new(0.05, 0., 3.).sun_color();
+        assert!(high.b / high.r > low.b / low.r);
+        assert!(high.r < 1.);
+        // hazier skies let less through
+        assert!(Sky::new(PI / 3., 0., 8.).sun_color().g < high.g);
+        assert_eq!(BLACK, Sky::new(-0.1, 0., 3.).sun_color());
+    }
+}
@@ -0,0 +1,67 @@
+use crate::{color, color::Color};
+
+// Fraunhofer lines used to define the Abbe number, in nanometers
+pub const FRAUNHOFER_C: f64 = 656.3;
+pub const FRAUNHOFER_D: f64 = 587.6;
+pub const FRAUNHOFER_F: f64 = 486.1;
+
+const VISIBLE_MIN: f64 = 400.;
+const VISIBLE_MAX: f64 = 700.;
+
+// Approximate color of a visible wavelength (Dan Bruton's piecewise linear fit)
+pub fn wavelength_to_rgb(nm: f64) -> Color {
+    if nm < 440. {
+        color!(((440. - nm) / 60.).min(1.), 0, 1)
+    } else if nm < 490. {
+        color!(0, (nm - 440.) / 50., 1)
+    } else if nm < 510. {
+        color!(0, 1, (510. - nm) / 20.)
+    } else if nm < 580. {
+        color!((nm - 510.) / 70., 1, 0)
+    } else if nm < 645. {
+        color!(1, (645. - nm) / 65., 0)
+    } else {
+        color!(1, 0, 0)
+    }
+}
+
+// Wavelengths evenly spread over the visible range, with weights that add up to white.
+// At least 3 samples are used so that every channel is covered
+pub fn spectral_samples(n: usize) -> Vec<(f64, Color)> {
+    let n = n.max(3);
+    let step = (VISIBLE_MAX - VISIBLE_MIN) / n as f64;
+    let samples = (0..n)
+        .map(|i| {
+            let nm = VISIBLE_MIN + step * (i as f64 + 0.5);
+            (nm, wavelength_to_rgb(nm))
+        })
+        .collect::<Vec<_>>();
+    let total = samples.iter().fold(color!(0, 0, 0), |acc, (_, c)| acc + *c);
+    samples
+        .into_iter()
+        .map(|(nm, c)| (nm, color!(c.r / total.r, c.g / total.g, c.b / total.b)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::{BLUE, RED, WHITE};
+
+    #[test]
+    fn visible_colors() {
+        assert_eq!(BLUE, wavelength_to_rgb(440.));
+        assert_eq!(color!(0, 1, 0), wavelength_to_rgb(510.));
+        assert_eq!(RED, wavelength_to_rgb(680.));
+    }
+
+    #[test]
+    fn sample_weights_add_up_to_white() {
+        for n in &[1, 3, 7, 16] {
+            let samples = spectral_samples(*n);
+            assert_eq!((*n).max(3), samples.len());
+            let sum = samples.iter().fold(color!(0, 0, 0), |acc, (_, c)| acc + *c);
+            assert_eq!(WHITE, sum);
+        }
+    }
+}
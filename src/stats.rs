@@ -0,0 +1,173 @@
+use core::ops::Add;
+use std::{cell::Cell, fmt};
+
+use crate::{canvas::Canvas, color, color::Color};
+
+// Counters are kept per thread, so the hot paths don't need any synchronization.
+// The camera takes them after each pixel and aggregates the results.
+thread_local! {
+    static STATS: Cell<RenderStats> = Cell::new(RenderStats::default());
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct RenderStats {
+    pub primary_rays: usize,
+    pub shadow_rays: usize,
+    pub reflection_rays: usize,
+    pub refraction_rays: usize,
+    pub intersection_tests: usize,
+    pub bounding_box_tests: usize,
+    pub max_depth: usize,
+}
+
+pub fn record(f: impl FnOnce(&mut RenderStats)) {
+    STATS.with(|cell| {
+        let mut stats = cell.get();
+        f(&mut stats);
+        cell.set(stats);
+    });
+}
+
+// Returns the counters of the current thread and resets them
+pub fn take() -> RenderStats {
+    STATS.with(|cell| cell.replace(RenderStats::default()))
+}
+
+impl RenderStats {
+    pub fn total_rays(&self) -> usize {
+        self.primary_rays + self.shadow_rays + self.reflection_rays + self.refraction_rays
+    }
+
+    // Used as the per-pixel cost in heatmaps
+    pub fn cost(&self) -> usize {
+        self.intersection_tests + self.bounding_box_tests
+    }
+}
+
+impl Add<RenderStats> for RenderStats {
+    type Output = RenderStats;
+
+    fn add(self, other: Self) -> Self {
+        RenderStats {
+            primary_rays: self.primary_rays + other.primary_rays,
+            shadow_rays: self.shadow_rays + other.shadow_rays,
+            reflection_rays: self.reflection_rays + other.reflection_rays,
+            refraction_rays: self.refraction_rays + other.refraction_rays,
+            intersection_tests: self.intersection_tests + other.intersection_tests,
+            bounding_box_tests: self.bounding_box_tests + other.bounding_box_tests,
+            max_depth: self.max_depth.max(other.max_depth),
+        }
+    }
+}
+
+impl fmt::Display for RenderStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Rays:")?;
+        writeln!(f, "  primary:            {}", self.primary_rays)?;
+        writeln!(f, "  shadow:             {}", self.shadow_rays)?;
+        writeln!(f, "  reflection:         {}", self.reflection_rays)?;
+        writeln!(f, "  refraction:         {}", self.refraction_rays)?;
+        writeln!(f, "  total:              {}", self.total_rays())?;
+        writeln!(f, "Tests:")?;
+        writeln!(f, "  shape intersection: {}", self.intersection_tests)?;
+        writeln!(f, "  bounding box:       {}", self.bounding_box_tests)?;
+        write!(f, "Max recursion depth:  {}", self.max_depth)
+    }
+}
+
+pub struct CostMap {
+    pub width: usize,
+    pub height: usize,
+    costs: Vec<usize>,
+}
+
+impl CostMap {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            costs: vec![0; width * height],
+        }
+    }
+
+    pub fn cost_at(&self, x: usize, y: usize) -> usize {
+        self.costs[y * self.width + x]
+    }
+
+    pub fn write_cost(&mut self, x: usize, y: usize, cost: usize) {
+        self.costs[y * self.width + x] = cost;
+    }
+
+    // False color image of the costs, from blue (cheapest) to red (most expensive)
+    pub fn to_heatmap(&self) -> Canvas {
+        let max = self.costs.iter().copied().max().unwrap_or(0).max(1) as f64;
+        let mut canvas = Canvas::new(self.width, self.height);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let c = heat_color(self.cost_at(x, y) as f64 / max);
+                canvas.write_pixel(x, y, c);
+            }
+        }
+        canvas
+    }
+}
+
+fn heat_color(v: f64) -> Color {
+    // blue -> cyan -> green -> yellow -> red
+    let v = v.clamp(0., 1.) * 4.;
+    match v as usize {
+        0 => color!(0, v, 1),
+        1 => color!(0, 1, 2. - v),
+        2 => color!(v - 2., 1, 0),
+        _ => color!(1, (4. - v).max(0.), 0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::{BLUE, RED};
+
+    #[test]
+    fn record_and_take() {
+        take();
+        record(|s| s.primary_rays += 1);
+        record(|s| s.shadow_rays += 2);
+        record(|s| s.max_depth = s.max_depth.max(3));
+        let stats = take();
+        assert_eq!(1, stats.primary_rays);
+        assert_eq!(2, stats.shadow_rays);
+        assert_eq!(3, stats.max_depth);
+        assert_eq!(RenderStats::default(), take());
+    }
+
+    #[test]
+    fn add_sums_counts_and_keeps_max_depth() {
+        let a = RenderStats {
+            primary_rays: 1,
+            intersection_tests: 5,
+            max_depth: 2,
+            ..RenderStats::default()
+        };
+        let b = RenderStats {
+            primary_rays: 2,
+            bounding_box_tests: 3,
+            max_depth: 1,
+            ..RenderStats::default()
+        };
+        let sum = a + b;
+        assert_eq!(3, sum.primary_rays);
+        assert_eq!(8, sum.cost());
+        assert_eq!(2, sum.max_depth);
+    }
+
+    #[test]
+    fn heatmap_is_normalized_to_the_max_cost() {
+        let mut costs = CostMap::new(2, 1);
+        costs.write_cost(0, 0, 0);
+        costs.write_cost(1, 0, 10);
+        let heatmap = costs.to_heatmap();
+        assert_eq!(BLUE, heatmap.pixel_at(0, 0));
+        assert_eq!(RED, heatmap.pixel_at(1, 0));
+    }
+}
@@ -0,0 +1,196 @@
+use crate::{
+    camera::Camera, canvas::Canvas, color::Color, matrix::Matrix, point, tuple::Tuple, vector,
+    world::World,
+};
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum StereoLayout {
+    SideBySide,
+    OverUnder,
+    // red channel from the left eye, green and blue from the right eye
+    Anaglyph,
+}
+
+pub struct StereoCamera {
+    pub hsize: usize,
+    pub vsize: usize,
+    pub field_of_view: f64,
+    pub interpupillary_distance: f64,
+    // distance from the cameras where both eyes converge (zero parallax);
+    // infinity keeps both eyes parallel
+    pub convergence_distance: f64,
+    from: Tuple,
+    to: Tuple,
+    up: Tuple,
+}
+
+impl StereoCamera {
+    pub fn new(hsize: usize, vsize: usize, field_of_view: f64) -> Self {
+        Self {
+            hsize,
+            vsize,
+            field_of_view,
+            interpupillary_distance: 0.064,
+            convergence_distance: f64::INFINITY,
+            from: point!(0, 0, 0),
+            to: point!(0, 0, -1),
+            up: vector!(0, 1, 0),
+        }
+    }
+
+    // Same parameters as Matrix::view_transform, for the point between both eyes
+    pub fn set_view(&mut self, from: Tuple, to: Tuple, up: Tuple) {
+        self.from = from;
+        self.to = to;
+        self.up = up;
+    }
+
+    pub fn eye_cameras(&self) -> (Camera, Camera) {
+        let forward = (self.to - self.from).normalize();
+        let left = forward.cross(&self.up.normalize()).normalize();
+        let half_ipd = self.interpupillary_distance / 2.;
+        let left_eye = self.from + left * half_ipd;
+        let right_eye = self.from - left * half_ipd;
+
+        let (left_target, right_target) = if self.convergence_distance.is_finite() {
+            let target = self.from + forward * self.convergence_distance;
+            (target, target)
+        } else {
+            (left_eye + forward, right_eye + forward)
+        };
+
+        let mut left_camera = Camera::new(self.hsize, self.vsize, self.field_of_view);
+        left_camera.set_transform(Matrix::view_transform(left_eye, left_target, self.up));
+        let mut right_camera = Camera::new(self.hsize, self.vsize, self.field_of_view);
+        right_camera.set_transform(Matrix::view_transform(right_eye, right_target, self.up));
+        (left_camera, right_camera)
+    }
+
+    pub fn render(&self, world: &World, antialiasing: bool, layout: StereoLayout) -> Canvas {
+        let (left_camera, right_camera) = self.eye_cameras();
+        let left = left_camera.render(world, antialiasing);
+        let right = right_camera.render(world, antialiasing);
+        compose(&left, &right, layout)
+    }
+}
+
+pub fn compose(left: &Canvas, right: &Canvas, layout: StereoLayout) -> Canvas {
+    assert_eq!(left.width, right.width);
+    assert_eq!(left.height, right.height);
+    let (width, height) = (left.width, left.height);
+    match layout {
+        StereoLayout::SideBySide => {
+            let mut image = Canvas::new(width * 2, height);
+            blit(&mut image, left, 0, 0);
+            blit(&mut image, right, width, 0);
+            image
+        }
+        StereoLayout::OverUnder => {
+            let mut image = Canvas::new(width, height * 2);
+            blit(&mut image, left, 0, 0);
+            blit(&mut image, right, 0, height);
+            image
+        }
+        StereoLayout::Anaglyph => {
+            let mut image = Canvas::new(width, height);
+            for y in 0..height {
+                for x in 0..width {
+                    let l = left.pixel_at(x, y);
+                    let r = right.pixel_at(x, y);
+                    image.write_pixel(x, y, Color::new(l.r, r.g, r.b));
+                }
+            }
+            image
+        }
+    }
+}
+
+fn blit(target: &mut Canvas, source: &Canvas, x0: usize, y0: usize) {
+    for y in 0..source.height {
+        for x in 0..source.width {
+            target.write_pixel(x0 + x, y0 + y, source.pixel_at(x, y));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::f64::consts::PI;
+
+    use crate::color::{BLUE, GREEN, RED, WHITE};
+
+    #[test]
+    fn eyes_are_offset_along_the_left_vector() {
+        let mut c = StereoCamera::new(11, 11, PI / 2.);
+        c.interpupillary_distance = 0.5;
+        c.set_view(point!(0, 0, -5), point!(0, 0, 0), vector!(0, 1, 0));
+        let (left, right) = c.eye_cameras();
+        assert_eq!(point!(-0.25, 0, -5), left.ray_for_pixel(5, 5).origin);
+        assert_eq!(point!(0.25, 0, -5), right.ray_for_pixel(5, 5).origin);
+        // parallel eyes by default
+        assert_eq!(vector!(0, 0, 1), left.ray_for_pixel(5, 5).direction);
+        assert_eq!(vector!(0, 0, 1), right.ray_for_pixel(5, 5).direction);
+    }
+
+    #[test]
+    fn eyes_converge_at_the_convergence_distance() {
+        let mut c = StereoCamera::new(11, 11, PI / 2.);
+        c.interpupillary_distance = 2.;
+        c.convergence_distance = 1.;
+        c.set_view(point!(0, 0, -5), point!(0, 0, 0), vector!(0, 1, 0));
+        let (left, right) = c.eye_cameras();
+        let d = 2f64.sqrt() / 2.;
+        assert_eq!(vector!(d, 0, d), left.ray_for_pixel(5, 5).direction);
+        assert_eq!(vector!(-d, 0, d), right.ray_for_pixel(5, 5).direction);
+    }
+
+    fn solid_canvas(color: Color) -> Canvas {
+        let mut c = Canvas::new(2, 3);
+        for y in 0..3 {
+            for x in 0..2 {
+                c.write_pixel(x, y, color);
+            }
+        }
+        c
+    }
+
+    #[test]
+    fn compose_side_by_side() {
+        let image = compose(
+            &solid_canvas(RED),
+            &solid_canvas(BLUE),
+            StereoLayout::SideBySide,
+        );
+        assert_eq!(4, image.width);
+        assert_eq!(3, image.height);
+        assert_eq!(RED, image.pixel_at(1, 2));
+        assert_eq!(BLUE, image.pixel_at(2, 0));
+    }
+
+    #[test]
+    fn compose_over_under() {
+        let image = compose(
+            &solid_canvas(RED),
+            &solid_canvas(BLUE),
+            StereoLayout::OverUnder,
+        );
+        assert_eq!(2, image.width);
+        assert_eq!(6, image.height);
+        assert_eq!(RED, image.pixel_at(1, 2));
+        assert_eq!(BLUE, image.pixel_at(0, 3));
+    }
+
+    #[test]
+    fn compose_anaglyph() {
+        let image = compose(
+            &solid_canvas(WHITE),
+            &solid_canvas(GREEN),
+            StereoLayout::Anaglyph,
+        );
+        assert_eq!(2, image.width);
+        assert_eq!(3, image.height);
+        assert_eq!(Color::new(1, 1, 0), image.pixel_at(0, 0));
+    }
+}
@@ -1,6 +1,8 @@
 use core::ops::{Add, Div, Mul, Neg, Sub};
 use std::fmt;
 
+use serde::{Deserialize, Serialize};
+
 use crate::{approx_eq, matrix::Matrix};
 
 #[macro_export]
@@ -22,7 +24,7 @@ macro_rules! vector {
     };
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Serialize, Deserialize)]
 pub struct Tuple {
     pub x: f64,
     pub y: f64,
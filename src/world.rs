@@ -1,30 +1,115 @@
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, f64::consts::PI, fs, io};
+
 use crate::{
     arena::Arena,
+    background::Background,
     color::{Color, BLACK, WHITE},
+    environment::EnvironmentLight,
+    integrator::{Integrator, TraceLimits},
     intersection::{Intersection, PreparedComputations},
-    light::PointLight,
-    material::MaterialBuilder,
+    light::{PointLight, SpotLight},
+    material::{Material, MaterialBuilder, ShadowMode},
+    material_library::MaterialLibrary,
     matrix::Matrix,
+    media::Fog,
+    occlusion::AmbientOcclusion,
+    photon::PhotonMap,
     point, ray,
     ray::Ray,
+    sampling,
     shapes::Shape,
-    solid, sphere,
+    solid, spectrum, sphere, stats,
     tuple::Tuple,
-    MAX_REFLECTION_RECURSION,
+    EPSILON,
 };
 
+// Recursion state of a Whitted ray
+#[derive(Debug, Copy, Clone)]
+struct Trace {
+    reflections_left: usize,
+    refractions_left: usize,
+    depth: usize,
+    // how much the ray adds to the pixel, relative to the camera ray
+    contribution: f64,
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct World {
     pub light: PointLight,
+    // additional lights, with their own shadow rays
+    pub spot_lights: Vec<SpotLight>,
     pub arena: Arena,
     pub object_ids: Vec<usize>,
+    pub integrator: Integrator,
+    pub limits: TraceLimits,
+    // point lights spread on each emissive shape for direct lighting
+    pub emissive_samples: usize,
+    // the shapes with an emissive material, kept up to date by the World methods that
+    // add shapes or change their materials. See refresh_emitters
+    emitter_ids: Vec<usize>,
+    // wavelengths traced when a ray hits a dispersive material
+    pub dispersion_samples: usize,
+    // rays traced for rough reflections and refractions at the first bounce
+    pub glossy_samples: usize,
+    // changed through set_library_material, load_materials and parse_materials,
+    // which keep the shapes that use an entry up to date
+    materials: MaterialLibrary,
+    // seen by rays that miss every object
+    pub background: Background,
+    // ambient light from the background, scaled by each material's ambient. 0 disables it
+    pub background_ambient: f64,
+    // image based lighting, see set_environment. The path tracer gathers the
+    // environment through the background instead
+    pub environment_light: Option<EnvironmentLight>,
+    // directions sampled on the environment light at each hit
+    pub environment_samples: usize,
+    // scales the ambient terms by how open the surface is around the hit
+    pub ambient_occlusion: Option<AmbientOcclusion>,
+    pub fog: Option<Fog>,
+    // caustics from build_caustic_map. While there is one, transparent shapes block
+    // shadow rays like the Hard shadow mode: the light through them comes from the photons
+    pub caustic_map: Option<PhotonMap>,
+    // arena ids of the shapes that use a library material, by name
+    material_bindings: HashMap<usize, String>,
+}
+
+// Result of a closest-hit query (picking, line-of-sight tools, etc.)
+#[derive(Debug)]
+pub struct RayHit<'a> {
+    pub object_id: usize,
+    pub object: &'a Shape,
+    pub t: f64,
+    pub point: Tuple,
+    // shading normal, perturbed by bump patterns
+    pub normalv: Tuple,
+    // true surface normal, for offsets and visibility tests
+    pub geometric_normalv: Tuple,
+    pub material: Material,
 }
 
 impl World {
     pub fn new(light: PointLight, objects: Vec<Shape>) -> Self {
         let mut w = Self {
             light,
+            spot_lights: Vec::new(),
             arena: Arena::new(),
             object_ids: Vec::new(),
+            integrator: Integrator::default(),
+            limits: TraceLimits::default(),
+            emissive_samples: 16,
+            emitter_ids: Vec::new(),
+            dispersion_samples: 7,
+            glossy_samples: 8,
+            materials: MaterialLibrary::with_presets(),
+            background: Background::default(),
+            background_ambient: 0.,
+            environment_light: None,
+            environment_samples: 16,
+            ambient_occlusion: None,
+            fog: None,
+            caustic_map: None,
+            material_bindings: HashMap::new(),
         };
         for object in objects {
             w.add_object(object);
@@ -42,36 +127,175 @@ impl World {
 
     pub fn add_object(&mut self, object: Shape) {
         self.object_ids.push(self.arena.add(object));
+        self.refresh_emitters();
     }
 
     pub fn add_object_with_id(&mut self, id: usize, object: Shape) {
         self.arena.add_with_id(id, object);
         self.object_ids.push(id);
+        self.refresh_emitters();
+    }
+
+    // Finds the emissive shapes again. Needed after changing materials on the arena directly
+    pub fn refresh_emitters(&mut self) {
+        self.emitter_ids = self
+            .arena
+            .objects
+            .iter()
+            .enumerate()
+            .filter_map(|(id, o)| o.as_ref().map(|o| (id, o)))
+            .filter(|(_, o)| !matches!(o, Shape::Group(_)) && o.material().is_emissive())
+            .map(|(id, _)| id)
+            .collect();
+    }
+
+    // Adds the shape with a library material and returns its id. Unknown names
+    // are an error and leave the world unchanged
+    pub fn add_object_with_material(
+        &mut self,
+        object: Shape,
+        material_name: &str,
+    ) -> Result<usize, String> {
+        self.library_material(material_name)?;
+        self.add_object(object);
+        let id = *self.object_ids.last().unwrap();
+        self.assign_material(id, material_name)?;
+        Ok(id)
+    }
+
+    // Gives the shape (or every shape inside a group) a library material and returns
+    // how many shapes got it. The shapes keep following the entry when the library changes
+    pub fn assign_material(&mut self, id: usize, material_name: &str) -> Result<usize, String> {
+        let material = self.library_material(material_name)?;
+        match self.arena.get(id) {
+            Shape::Group(g) => {
+                let mut count = 0;
+                for child_id in g.children_ids.clone() {
+                    count += self.assign_material(child_id, material_name)?;
+                }
+                Ok(count)
+            }
+            _ => {
+                self.arena.apply_changes(id, |s| s.set_material(material));
+                self.material_bindings.insert(id, material_name.to_string());
+                self.refresh_emitters();
+                Ok(1)
+            }
+        }
+    }
+
+    fn library_material(&self, material_name: &str) -> Result<Material, String> {
+        self.materials
+            .get(material_name)
+            .copied()
+            .ok_or_else(|| format!("Unknown material: {}", material_name))
+    }
+
+    pub fn materials(&self) -> &MaterialLibrary {
+        &self.materials
+    }
+
+    // Adds (or replaces) the entries of a material file
+    pub fn load_materials(&mut self, path: &str) -> io::Result<()> {
+        let text = fs::read_to_string(path)?;
+        self.parse_materials(&text)
+    }
+
+    pub fn parse_materials(&mut self, text: &str) -> io::Result<()> {
+        let result = self.materials.parse(text);
+        // entries before an error are kept, so the shapes follow them either way
+        self.sync_material_bindings();
+        result
+    }
+
+    fn sync_material_bindings(&mut self) {
+        for (id, name) in self.material_bindings.clone() {
+            if let Some(material) = self.materials.get(&name).copied() {
+                self.arena.apply_changes(id, |s| s.set_material(material));
+            }
+        }
+        self.refresh_emitters();
+    }
+
+    pub fn material_name(&self, id: usize) -> Option<&str> {
+        self.material_bindings.get(&id).map(|name| name.as_str())
+    }
+
+    // Adds or replaces a library entry and updates every shape using it
+    pub fn set_library_material(&mut self, material_name: &str, material: Material) {
+        self.materials.insert(material_name, material);
+        let ids = self
+            .material_bindings
+            .iter()
+            .filter(|(_, name)| name.as_str() == material_name)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in ids {
+            self.arena.apply_changes(id, |s| s.set_material(material));
+        }
+        self.refresh_emitters();
     }
 
     pub fn apply_changes_by_index(&mut self, index: usize, c: impl Fn(&mut Shape)) {
         let id = self.object_ids[index];
         self.arena.apply_changes(id, c);
+        self.refresh_emitters();
     }
 
     pub fn color_at(&self, r: &Ray) -> Color {
-        self.color_at_internal(r, MAX_REFLECTION_RECURSION)
+        match self.integrator {
+            Integrator::Whitted => self.color_at_internal(r, self.camera_trace()),
+            Integrator::PathTracing { samples, max_depth } => {
+                self.path_traced_color(r, samples, max_depth)
+            }
+        }
+    }
+
+    fn camera_trace(&self) -> Trace {
+        Trace {
+            reflections_left: self.limits.max_reflection_depth,
+            refractions_left: self.limits.max_refraction_depth,
+            depth: 0,
+            contribution: 1.,
+        }
     }
 
-    fn color_at_internal(&self, r: &Ray, remaining: usize) -> Color {
+    fn color_at_internal(&self, r: &Ray, trace: Trace) -> Color {
+        stats::record(|s| s.max_depth = s.max_depth.max(trace.depth));
+
         let xs = self.intersect(&r);
         let xs_refs = xs.iter().collect::<Vec<&Intersection>>();
 
-        match xs.iter().find(|i| i.t >= 0.) {
+        // volumes have no visible surface, they are marched by media_along
+        let (color, distance) = match xs
+            .iter()
+            .find(|i| i.t >= 0. && !i.object.material().is_volume())
+        {
+            Some(i) if r.wavelength.is_none() && i.object.material().is_dispersive() => {
+                return self.dispersed_color(r, trace);
+            }
             Some(i) => {
                 let comps = i.prepare_computations(&self.arena, &r, &xs_refs[..]);
-                self.shade_hit(&comps, remaining)
+                (self.shade_hit(&comps, trace) * comps.absorption(), i.t)
             }
-            None => BLACK,
-        }
+            None => (self.background.color_in(r.direction), f64::INFINITY),
+        };
+        let (light, transmittance) = self.media_along(r, &xs, distance);
+        color * transmittance + light
+    }
+
+    // Traces the ray once per sampled wavelength, so that every refraction
+    // along the way uses the refractive index for that wavelength
+    fn dispersed_color(&self, r: &Ray, trace: Trace) -> Color {
+        spectrum::spectral_samples(self.dispersion_samples)
+            .into_iter()
+            .fold(BLACK, |acc, (wavelength, weight)| {
+                let r = ray!(r.origin, r.direction).with_wavelength(Some(wavelength));
+                acc + self.color_at_internal(&r, trace) * weight
+            })
     }
 
-    fn intersect(&self, r: &Ray) -> Vec<Intersection> {
+    pub fn intersect(&self, r: &Ray) -> Vec<Intersection<'_>> {
         let mut result = vec![];
         for id in &self.object_ids {
             result.extend(self.arena.get(*id).intersect(&self.arena, r));
@@ -80,82 +304,398 @@ impl World {
         result
     }
 
-    fn shade_hit(&self, comps: &PreparedComputations, remaining: usize) -> Color {
-        let shadowed = self.is_shadowed(comps.over_point);
-        let surface = comps.object.material().lightning(
+    // Volumes have no surface and are skipped, like in the render
+    pub fn closest_hit(&self, r: &Ray) -> Option<RayHit<'_>> {
+        let xs = self.intersect(r);
+        let xs_refs = xs.iter().collect::<Vec<&Intersection>>();
+        let i = xs
+            .iter()
+            .find(|i| i.t >= 0. && !i.object.material().is_volume())?;
+        let comps = i.prepare_computations(&self.arena, r, &xs_refs[..]);
+        Some(RayHit {
+            object_id: self.arena.id_of(i.object).unwrap(),
+            object: i.object,
+            t: i.t,
+            point: comps.point,
+            normalv: comps.normalv,
+            geometric_normalv: comps.geometric_normalv,
+            material: *i.object.material(),
+        })
+    }
+
+    // true if any surface is hit in the [0, max_distance) interval, volumes don't count
+    pub fn any_hit(&self, r: &Ray, max_distance: f64) -> bool {
+        self.intersect(r)
+            .iter()
+            .any(|i| i.t >= 0. && i.t < max_distance && !i.object.material().is_volume())
+    }
+
+    // Line of sight between two points. When the points lie on surfaces,
+    // pass points offset from them (e.g. over_point) to avoid acne
+    pub fn is_visible(&self, from: Tuple, to: Tuple) -> bool {
+        let v = to - from;
+        let r = ray!(from, v.normalize());
+        !self.any_hit(&r, v.magnitude())
+    }
+
+    fn shade_hit(&self, comps: &PreparedComputations, trace: Trace) -> Color {
+        let transmittance = self.shadow_transmittance(comps.over_point);
+        let visibility = self.ambient_visibility_at(comps);
+        let lit_material = Material {
+            ambient: comps.object.material().ambient * visibility,
+            ..*comps.object.material()
+        };
+        let surface = lit_material.lightning_with_tangent(
             comps.object,
             &self.light,
             comps.over_point,
             comps.eyev,
             comps.normalv,
-            shadowed,
+            comps.tangentv,
+            transmittance,
         );
-        let reflected = self.reflected_color(comps, remaining);
-        let refracted = self.refracted_color(comps, remaining);
+        let surface = surface
+            + comps.object.material().emitted()
+            + self.caustic_lighting(comps)
+            + self.background_lighting(comps) * visibility;
+        // already blended with the layers, see direct_lighting
+        let other_lights = self.emissive_lighting(comps)
+            + self.spot_lighting(comps)
+            + self.sun_lighting(comps)
+            + self.environment_lighting(comps);
+        let reflected = self.reflected_color(comps, trace);
+        let refracted = self.refracted_color(comps, trace);
 
         let material = comps.object.material();
-        if material.reflective > 0. && material.transparency > 0. {
+        let base = if material.reflective > 0. && material.transparency > 0. {
             let reflectance = comps.schlick();
             surface + reflected * reflectance + refracted * (1. - reflectance)
         } else {
             surface + reflected + refracted
+        };
+
+        let layered = match comps.layer_reflectance() {
+            Some(layer) => {
+                let highlight = material.layer_highlight(
+                    &self.light,
+                    comps.over_point,
+                    comps.eyev,
+                    comps.normalv,
+                ) * transmittance;
+                base * (WHITE - layer)
+                    + (highlight + self.layer_reflection(comps, trace, layer)) * layer
+            }
+            None => base,
+        };
+        layered + other_lights
+    }
+
+    // Mirror reflection of the clearcoat and thin-film layers, before their reflectance
+    fn layer_reflection(&self, comps: &PreparedComputations, trace: Trace, layer: Color) -> Color {
+        let strength = layer.r.max(layer.g).max(layer.b);
+        if strength <= 0. {
+            return BLACK;
+        }
+        self.reflection(comps, trace, strength, 0.) / strength
+    }
+
+    fn ambient_visibility_at(&self, comps: &PreparedComputations) -> f64 {
+        match self.ambient_occlusion {
+            Some(occlusion) if comps.object.material().ambient > 0. => {
+                // the hemisphere of the true surface, bumped normals can tilt it into the shape
+                self.ambient_visibility(comps.over_point, comps.geometric_normalv, occlusion)
+            }
+            _ => 1.,
+        }
+    }
+
+    // The ambient term lit by the background instead of the light
+    fn background_lighting(&self, comps: &PreparedComputations) -> Color {
+        if self.background_ambient <= 0. {
+            return BLACK;
         }
+        let material = comps.object.material();
+        let color = material
+            .pattern
+            .color_at_object(comps.object, comps.over_point);
+        color
+            * self.background.ambient_light(comps.normalv)
+            * material.ambient
+            * self.background_ambient
+    }
+
+    pub fn is_shadowed(&self, point: Tuple) -> bool {
+        stats::record(|s| s.shadow_rays += 1);
+        !self.is_visible(point, self.light.position)
     }
 
-    fn is_shadowed(&self, point: Tuple) -> bool {
-        let v = self.light.position - point;
+    // Fraction of the light that reaches the point. Transparent objects let part
+    // of it through (see ShadowMode), absorbing ones attenuate it by the distance
+    // travelled inside them
+    pub fn shadow_transmittance(&self, point: Tuple) -> Color {
+        self.transmittance_between(point, self.light.position)
+    }
+
+    // Same as shadow_transmittance, toward any light position
+    pub fn transmittance_between(&self, point: Tuple, light_position: Tuple) -> Color {
+        stats::record(|s| s.shadow_rays += 1);
+        let v = light_position - point;
         let distance = v.magnitude();
-        let direction = v.normalize();
+        let r = ray!(point, v.normalize());
 
-        let r = ray!(point, direction);
-        let xs = self.intersect(&r);
-        match xs.iter().find(|i| i.t >= 0.) {
-            Some(i) => i.t < distance,
-            None => false,
+        let mut transmittance = WHITE;
+        // objects containing the current part of the shadow ray, with where it entered them
+        let mut inside: Vec<(&Shape, f64)> = vec![];
+        for i in self.intersect(&r) {
+            if i.t >= distance {
+                break;
+            }
+            if i.t >= 0. && !i.object.material().is_volume() {
+                let material = i.object.material();
+                if material.transparency == 0.
+                    || material.shadow == ShadowMode::Hard
+                    || self.caustic_map.is_some()
+                {
+                    return BLACK;
+                }
+                transmittance = transmittance * material.transparency;
+                if material.shadow == ShadowMode::Tinted {
+                    transmittance =
+                        transmittance * material.pattern.color_at_object(i.object, r.position(i.t));
+                }
+            }
+            match inside.iter().position(|(o, _)| *o == i.object) {
+                Some(pos) => {
+                    let (object, entered) = inside.remove(pos);
+                    if i.t > 0. {
+                        let travelled = i.t - entered;
+                        transmittance = transmittance * medium_transmittance(object, travelled);
+                    }
+                }
+                None => inside.push((i.object, i.t.max(0.))),
+            }
+        }
+        // the light is inside these
+        for (object, entered) in inside {
+            transmittance = transmittance * medium_transmittance(object, distance - entered);
+        }
+        transmittance
+    }
+
+    // Direct light from emissive shapes, each one acting as a set of point lights
+    // spread on its surface. Each stands for a patch of the surface, and lights the hit
+    // with the patch's emitted radiance times the solid angle it covers from there
+    fn emissive_lighting(&self, comps: &PreparedComputations) -> Color {
+        let mut color = BLACK;
+        for emitter in self.emitter_ids.iter().map(|id| self.arena.get(*id)) {
+            if std::ptr::eq(emitter, comps.object) {
+                continue;
+            }
+            let radiance = emitter.material().emitted();
+            for sample in emitter.surface_samples(&self.arena, self.emissive_samples) {
+                // keep the shadow rays from hitting the emitter itself
+                let position = sample.point + sample.normal * EPSILON;
+                let to_hit = comps.over_point - position;
+                let distance = to_hit.magnitude();
+                let cos = sample.normal.dot(&to_hit) / distance;
+                if distance <= 0. || cos <= 0. {
+                    continue;
+                }
+                // the shading models leave out the 1/PI of a diffuse surface
+                let intensity = radiance * (sample.area * cos / (PI * distance * distance));
+                let light = PointLight::new(position, intensity);
+                let transmittance = self.transmittance_between(comps.over_point, position);
+                color = color + self.direct_lighting(comps, &light, transmittance);
+            }
+        }
+        color
+    }
+
+    // Direct light from the spot lights, each one shaded as a point light with the
+    // intensity it sends toward the hit
+    pub(crate) fn spot_lighting(&self, comps: &PreparedComputations) -> Color {
+        let mut color = BLACK;
+        for spot in &self.spot_lights {
+            let intensity = spot.intensity_toward(comps.over_point);
+            if intensity == BLACK {
+                continue;
+            }
+            let light = PointLight::new(spot.position, intensity);
+            let transmittance = self.transmittance_between(comps.over_point, spot.position);
+            color = color + self.direct_lighting(comps, &light, transmittance);
+        }
+        color
+    }
+
+    // Direct light from the sun of a sky background
+    pub(crate) fn sun_lighting(&self, comps: &PreparedComputations) -> Color {
+        let sky = match &self.background {
+            Background::Sky(sky) if sky.elevation > 0. => sky,
+            _ => return BLACK,
+        };
+        let light = sky.sun_light(comps.over_point);
+        let transmittance = self.transmittance_between(comps.over_point, light.position);
+        self.direct_lighting(comps, &light, transmittance)
+    }
+
+    // Shades the hit with one light, leaving out the ambient term that the main light
+    // already adds. The clearcoat and thin-film layers let through what they don't
+    // reflect, and mirror the light
+    pub(crate) fn direct_lighting(
+        &self,
+        comps: &PreparedComputations,
+        light: &PointLight,
+        transmittance: Color,
+    ) -> Color {
+        let material = Material {
+            ambient: 0.,
+            ..*comps.object.material()
+        };
+        let direct = material.lightning_with_tangent(
+            comps.object,
+            light,
+            comps.over_point,
+            comps.eyev,
+            comps.normalv,
+            comps.tangentv,
+            transmittance,
+        );
+        match comps.layer_reflectance() {
+            Some(layer) => {
+                let highlight =
+                    material.layer_highlight(light, comps.over_point, comps.eyev, comps.normalv);
+                direct * (WHITE - layer) + highlight * transmittance * layer
+            }
+            None => direct,
         }
     }
 
-    fn reflected_color(&self, comps: &PreparedComputations, remaining: usize) -> Color {
-        if remaining <= 0 {
+    fn reflected_color(&self, comps: &PreparedComputations, trace: Trace) -> Color {
+        let material = comps.object.material();
+        self.reflection(
+            comps,
+            trace,
+            material.reflective,
+            material.reflection_roughness,
+        )
+    }
+
+    // Color seen in the reflected direction, scaled by `reflective`
+    fn reflection(
+        &self,
+        comps: &PreparedComputations,
+        trace: Trace,
+        reflective: f64,
+        roughness: f64,
+    ) -> Color {
+        if trace.reflections_left == 0 || trace.depth >= self.limits.max_depth {
             return BLACK;
         }
-        let reflective = comps.object.material().reflective;
         if reflective == 0.0 {
             return BLACK;
         }
+        let (weight, contribution) = match self.secondary_ray_weight(trace, reflective) {
+            Some(w) => w,
+            None => return BLACK,
+        };
+        let next = Trace {
+            reflections_left: trace.reflections_left - 1,
+            depth: trace.depth + 1,
+            contribution,
+            ..trace
+        };
 
-        let reflect_ray = ray!(comps.over_point, comps.reflectv);
-        let color = self.color_at_internal(&reflect_ray, remaining - 1);
-        color * reflective
+        let directions =
+            self.scattered_directions(comps.reflectv, roughness, comps.geometric_normalv, trace);
+        let samples = directions.len();
+        stats::record(|s| s.reflection_rays += samples);
+        let color = directions.into_iter().fold(BLACK, |acc, direction| {
+            let reflect_ray = ray!(comps.over_point, direction).with_wavelength(comps.wavelength);
+            acc + self.color_at_internal(&reflect_ray, next)
+        });
+        color / samples as f64 * reflective * weight
     }
 
-    fn refracted_color(&self, comps: &PreparedComputations, remaining: usize) -> Color {
-        if remaining <= 0 {
+    fn refracted_color(&self, comps: &PreparedComputations, trace: Trace) -> Color {
+        if trace.refractions_left == 0 || trace.depth >= self.limits.max_depth {
             return BLACK;
         }
         let transparency = comps.object.material().transparency;
         if transparency == 0. {
             return BLACK;
         }
+        let (weight, contribution) = match self.secondary_ray_weight(trace, transparency) {
+            Some(w) => w,
+            None => return BLACK,
+        };
+        let next = Trace {
+            refractions_left: trace.refractions_left - 1,
+            depth: trace.depth + 1,
+            contribution,
+            ..trace
+        };
 
-        // detect total internal reflection using Snell's Law
-        let n_ratio = comps.n1 / comps.n2;
-        // cos(theta_i) is the same as the dot product of the two vectors​
-        let cos_i = comps.eyev.dot(&comps.normalv);
-        // Find sin(theta_t)^2 via trigonometric identity​
-        let sin2_t = (n_ratio * n_ratio) * (1. - (cos_i * cos_i));
-        if sin2_t > 1. {
-            return BLACK;
-        }
+        let direction = match comps.refracted_direction() {
+            Some(direction) => direction,
+            // total internal reflection
+            None => return BLACK,
+        };
 
-        let cos_t = (1. - sin2_t).sqrt();
-        // Compute the direction of the refracted ray​
-        let direction = comps.normalv * (n_ratio * cos_i - cos_t) - comps.eyev * n_ratio;
+        let roughness = comps.object.material().refraction_roughness;
+        let directions =
+            self.scattered_directions(direction, roughness, -comps.geometric_normalv, trace);
+        let samples = directions.len();
+        stats::record(|s| s.refraction_rays += samples);
+        let color = directions.into_iter().fold(BLACK, |acc, direction| {
+            // Create the refracted ray​
+            let refracted_ray =
+                ray!(comps.under_point, direction).with_wavelength(comps.wavelength);
+            acc + self.color_at_internal(&refracted_ray, next)
+        });
+        color / samples as f64 * transparency * weight
+    }
 
-        // Create the refracted ray​
-        let refracted_ray = ray!(comps.under_point, direction);
+    // The perfect direction, or directions sampled around it for rough surfaces.
+    // Only the first bounce is split into several rays, deeper ones trace a single sample
+    fn scattered_directions(
+        &self,
+        direction: Tuple,
+        roughness: f64,
+        side: Tuple,
+        trace: Trace,
+    ) -> Vec<Tuple> {
+        if roughness <= 0. {
+            return vec![direction];
+        }
+        let samples = if trace.depth == 0 {
+            self.glossy_samples.max(1)
+        } else {
+            1
+        };
+        let mut rng = rand::thread_rng();
+        (0..samples)
+            .map(|_| sampling::rough_direction(direction, roughness, side, &mut rng))
+            .collect()
+    }
 
-        self.color_at_internal(&refracted_ray, remaining - 1) * transparency
+    // Applies the termination options to a secondary ray scaled by factor. Returns the weight
+    // for its color and its contribution, or None when it isn't worth tracing
+    fn secondary_ray_weight(&self, trace: Trace, factor: f64) -> Option<(f64, f64)> {
+        let contribution = trace.contribution * factor;
+        if contribution < self.limits.min_contribution {
+            return None;
+        }
+        match self.limits.russian_roulette_depth {
+            Some(start) if trace.depth >= start && contribution < 1. => {
+                if rand::random::<f64>() < contribution {
+                    // the surviving ray stands in for the terminated ones
+                    Some((1. / contribution, 1.))
+                } else {
+                    None
+                }
+            }
+            _ => Some((1., contribution)),
+        }
     }
 }
 
@@ -179,17 +719,91 @@ impl Default for World {
     }
 }
 
+// Absorption, and extinction for volumes, along a path inside the object
+fn medium_transmittance(object: &Shape, distance: f64) -> Color {
+    let material = object.material();
+    let extinction = material
+        .volume
+        .map_or(1., |volume| volume.transmittance(distance));
+    material.transmittance(distance) * extinction
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::f64::consts::PI;
+
     use crate::{
-        color,
+        approx_eq, color,
         color::RED,
-        material::Material,
+        cube, gradient_pattern,
+        material::{Clearcoat, Material},
+        media::Volume,
         patterns::{Pattern, TestPattern},
-        plane, ray, vector,
+        plane, ray,
+        shapes::group::Group,
+        sky::Sky,
+        stripe_pattern, vector,
     };
 
+    #[test]
+    fn shapes_follow_library_materials() {
+        let mut w = World::default();
+        let glass_id = w.add_object_with_material(sphere!(), "glass").unwrap();
+        w.add_object_with_material(plane!(), "glass").unwrap();
+        w.add_object_with_material(cube!(), "gold").unwrap();
+        assert_eq!(Some("glass"), w.material_name(glass_id));
+        assert_eq!(None, w.material_name(w.object_ids[0]));
+        assert_eq!(1.52, w.object_by_index(2).material().refractive_index);
+
+        let denser_glass = Material {
+            refractive_index: 1.7,
+            ..*w.materials().get("glass").unwrap()
+        };
+        w.set_library_material("glass", denser_glass);
+        assert_eq!(1.7, w.object_by_index(2).material().refractive_index);
+        assert_eq!(1.7, w.object_by_index(3).material().refractive_index);
+        assert_eq!(1., w.object_by_index(4).material().metallic);
+    }
+
+    #[test]
+    fn library_materials_on_groups_apply_to_children() {
+        let mut w = World::default();
+        let child_id = w.arena.add(sphere!());
+        let group_id = w.arena.next_id();
+        let mut group = Group::new(group_id);
+        group.add_child(child_id, &mut w.arena);
+        w.add_object_with_id(group_id, Shape::Group(group));
+        assert_eq!(Ok(1), w.assign_material(group_id, "water"));
+        assert_eq!(Some("water"), w.material_name(child_id));
+        assert_eq!(1.333, w.arena.get(child_id).material().refractive_index);
+    }
+
+    #[test]
+    fn assigning_an_unknown_material() {
+        let mut w = World::default();
+        let objects = w.object_ids.len();
+        assert_eq!(
+            Err("Unknown material: unobtainium".to_string()),
+            w.add_object_with_material(sphere!(), "unobtainium")
+        );
+        assert_eq!(objects, w.object_ids.len());
+        assert!(w.assign_material(w.object_ids[0], "unobtainium").is_err());
+    }
+
+    #[test]
+    fn material_files_update_bound_shapes() {
+        let mut w = World::default();
+        let id = w.add_object_with_material(sphere!(), "glass").unwrap();
+        w.parse_materials("[glass]\nrefractive_index = 1.6\n")
+            .unwrap();
+        assert_eq!(1.6, w.arena.get(id).material().refractive_index);
+        assert_eq!(1.6, w.materials().get("glass").unwrap().refractive_index);
+        assert!(w
+            .parse_materials("[glass]\nrefractive_index = soft\n")
+            .is_err());
+    }
+
     #[test]
     fn intersect_with_ray() {
         let w = World::default();
@@ -211,7 +825,7 @@ mod tests {
         let s = &w.object_by_index(0);
         let i = Intersection::new(4, s);
         let comps = i.prepare_computations(&w.arena, &r, &[&i]);
-        let c = w.shade_hit(&comps, MAX_REFLECTION_RECURSION);
+        let c = w.shade_hit(&comps, w.camera_trace());
         assert_eq!(color!(0.38066, 0.47583, 0.2855), c);
     }
 
@@ -272,6 +886,145 @@ mod tests {
         assert!(!w.is_shadowed(p));
     }
 
+    fn world_with_blocker(blocker: Shape) -> World {
+        World::new(PointLight::new(point!(0, 10, 0), WHITE), vec![blocker])
+    }
+
+    fn pane(material: Material) -> Shape {
+        let mut pane = plane!();
+        pane.set_transform(Matrix::translation(0, 5, 0));
+        pane.set_material(material);
+        pane
+    }
+
+    #[test]
+    fn opaque_objects_block_the_light() {
+        let w = world_with_blocker(pane(Material::default()));
+        assert_eq!(BLACK, w.shadow_transmittance(point!(0, 0, 0)));
+    }
+
+    #[test]
+    fn transparent_objects_cast_partial_shadows() {
+        let glass = MaterialBuilder::default()
+            .pattern(solid!(RED))
+            .transparency(0.6)
+            .build()
+            .unwrap();
+        let w = world_with_blocker(pane(glass));
+        assert_eq!(
+            color!(0.6, 0.6, 0.6),
+            w.shadow_transmittance(point!(0, 0, 0))
+        );
+        assert_eq!(WHITE, w.shadow_transmittance(point!(0, 6, 0)));
+
+        let tinted = Material {
+            shadow: ShadowMode::Tinted,
+            ..glass
+        };
+        let w = world_with_blocker(pane(tinted));
+        assert_eq!(color!(0.6, 0, 0), w.shadow_transmittance(point!(0, 0, 0)));
+
+        let hard = Material {
+            shadow: ShadowMode::Hard,
+            ..glass
+        };
+        let w = world_with_blocker(pane(hard));
+        assert_eq!(BLACK, w.shadow_transmittance(point!(0, 0, 0)));
+    }
+
+    #[test]
+    fn shadows_through_absorbing_media() {
+        let absorbing = MaterialBuilder::default()
+            .transparency(1)
+            .absorption(color!(1, 0.5, 0.5))
+            .density(0.5)
+            .build()
+            .unwrap();
+        let mut s = sphere!();
+        s.set_transform(Matrix::translation(0, 5, 0));
+        s.set_material(absorbing);
+        let w = world_with_blocker(s);
+        assert_eq!(color!(1, 0.5, 0.5), w.shadow_transmittance(point!(0, 0, 0)));
+        // from inside the sphere
+        assert_eq!(
+            color!(1, 0.5, 0.5).g.sqrt(),
+            w.shadow_transmittance(point!(0, 5, 0)).g
+        );
+
+        // light inside the sphere
+        let mut s = sphere!();
+        s.set_transform(Matrix::translation(0, 10, 0) * Matrix::scaling(2, 2, 2));
+        s.set_material(absorbing);
+        let w = world_with_blocker(s);
+        assert_eq!(color!(1, 0.5, 0.5), w.shadow_transmittance(point!(0, 0, 0)));
+    }
+
+    #[test]
+    fn shading_is_scaled_by_the_shadow_transmittance() {
+        let light = || PointLight::new(point!(0, 10, -10), WHITE);
+        let r = ray!(point!(0, 0, -5), vector!(0, 0, 1));
+        let shade = |w: &World| {
+            let i = Intersection::new(4, w.object_by_index(0));
+            let comps = i.prepare_computations(&w.arena, &r, &[&i]);
+            w.shade_hit(&comps, w.camera_trace())
+        };
+        let unshadowed = shade(&World::new(light(), vec![sphere!()]));
+
+        let glass = MaterialBuilder::default()
+            .transparency(0.5)
+            .build()
+            .unwrap();
+        let shadowed = shade(&World::new(light(), vec![sphere!(), pane(glass)]));
+
+        let ambient = color!(0.1, 0.1, 0.1);
+        assert_eq!(ambient + (unshadowed - ambient) * 0.5, shadowed);
+    }
+
+    #[test]
+    fn closest_hit_returns_id_and_surface_info() {
+        let w = World::default();
+        let r = ray!(point!(0, 0, -5), vector!(0, 0, 1));
+        let hit = w.closest_hit(&r).unwrap();
+        assert_eq!(w.object_ids[0], hit.object_id);
+        assert_eq!(4., hit.t);
+        assert_eq!(point!(0, 0, -1), hit.point);
+        assert_eq!(vector!(0, 0, -1), hit.normalv);
+        assert_eq!(vector!(0, 0, -1), hit.geometric_normalv);
+        assert_eq!(*w.object_by_index(0).material(), hit.material);
+    }
+
+    #[test]
+    fn closest_hit_from_inside_an_object() {
+        let w = World::default();
+        let r = ray!(point!(0, 0, 0), vector!(0, 0, 1));
+        let hit = w.closest_hit(&r).unwrap();
+        assert_eq!(w.object_ids[1], hit.object_id);
+        assert_eq!(0.5, hit.t);
+    }
+
+    #[test]
+    fn closest_hit_when_ray_misses() {
+        let w = World::default();
+        let r = ray!(point!(0, 0, -5), vector!(0, 1, 0));
+        assert!(w.closest_hit(&r).is_none());
+    }
+
+    #[test]
+    fn any_hit_respects_max_distance() {
+        let w = World::default();
+        let r = ray!(point!(0, 0, -5), vector!(0, 0, 1));
+        assert!(w.any_hit(&r, 10.));
+        assert!(!w.any_hit(&r, 3.));
+    }
+
+    #[test]
+    fn visibility_between_points() {
+        let w = World::default();
+        assert!(!w.is_visible(point!(0, 0, -5), point!(0, 0, 5)));
+        assert!(w.is_visible(point!(0, 2, -5), point!(0, 2, 5)));
+        assert!(w.is_visible(point!(0, 0, -5), point!(0, 0, -2)));
+    }
+
     #[test]
     fn shade_hit_intersection_in_shadow() {
         let light = PointLight::new(point!(0, 0, -10), WHITE);
@@ -284,7 +1037,7 @@ mod tests {
         let r = ray!(point!(0, 0, 5), vector!(0, 0, 1));
         let i = Intersection::new(4, &w.object_by_index(1));
         let comps = i.prepare_computations(&w.arena, &r, &[&i]);
-        let c = w.shade_hit(&comps, MAX_REFLECTION_RECURSION);
+        let c = w.shade_hit(&comps, w.camera_trace());
         assert_eq!(color!(0.1, 0.1, 0.1), c);
     }
 
@@ -302,7 +1055,7 @@ mod tests {
         let r = ray!(point!(0, 0, 0), vector!(0, 0, 1));
         let i = Intersection::new(1, &w.object_by_index(1));
         let comps = i.prepare_computations(&w.arena, &r, &[&i]);
-        let color = w.reflected_color(&comps, MAX_REFLECTION_RECURSION);
+        let color = w.reflected_color(&comps, w.camera_trace());
         assert_eq!(BLACK, color);
     }
 
@@ -322,10 +1075,126 @@ mod tests {
         );
         let i = Intersection::new(2f64.sqrt(), &w.last_object().unwrap());
         let comps = i.prepare_computations(&w.arena, &r, &[&i]);
-        let color = w.reflected_color(&comps, MAX_REFLECTION_RECURSION);
+        let color = w.reflected_color(&comps, w.camera_trace());
         assert_eq!(color!(0.19033, 0.23791, 0.14274), color);
     }
 
+    #[test]
+    fn rough_surfaces_scatter_several_rays_at_the_first_bounce() {
+        let w = World {
+            glossy_samples: 6,
+            ..World::default()
+        };
+        let direction = vector!(0, 1, 1).normalize();
+        let normal = vector!(0, 1, 0);
+        let trace = w.camera_trace();
+        assert_eq!(
+            vec![direction],
+            w.scattered_directions(direction, 0., normal, trace)
+        );
+        let directions = w.scattered_directions(direction, 0.8, normal, trace);
+        assert_eq!(6, directions.len());
+        assert!(directions.iter().all(|d| d.dot(&normal) >= 0.));
+        let deeper = Trace { depth: 1, ..trace };
+        assert_eq!(
+            1,
+            w.scattered_directions(direction, 0.8, normal, deeper).len()
+        );
+    }
+
+    #[test]
+    fn rough_reflection_averages_the_surroundings() {
+        // every direction sees the same enclosing sphere, so the blur keeps its color
+        let mut w = World::default();
+        w.object_ids.clear();
+        let mut sky = sphere!();
+        sky.set_transform(Matrix::scaling(100, 100, 100));
+        sky.set_material(Material {
+            pattern: solid!(0.2, 0.4, 0.6),
+            ambient: 1.,
+            diffuse: 0.,
+            specular: 0.,
+            ..Material::default()
+        });
+        w.add_object(sky);
+        let mut floor = plane!();
+        floor.set_material(Material {
+            reflective: 0.5,
+            reflection_roughness: 0.7,
+            ..Material::default()
+        });
+        w.add_object(floor);
+
+        let r = ray!(point!(0, 1, -1), vector!(0, -1, 1).normalize());
+        let i = Intersection::new(2f64.sqrt(), w.last_object().unwrap());
+        let comps = i.prepare_computations(&w.arena, &r, &[&i]);
+        let color = w.reflected_color(&comps, w.camera_trace());
+        assert_eq!(color!(0.1, 0.2, 0.3), color);
+    }
+
+    #[test]
+    fn frosted_refraction_stays_inside_the_surface() {
+        let w = World::default();
+        let normal = vector!(0, 0, -1);
+        let trace = w.camera_trace();
+        let directions = w.scattered_directions(vector!(0, 0, 1), 1., -normal, trace);
+        assert_eq!(w.glossy_samples, directions.len());
+        assert!(directions.iter().all(|d| d.dot(&normal) <= 0.));
+    }
+
+    fn half_mirror_reflection(limits: TraceLimits) -> Color {
+        let mut w = World {
+            limits,
+            ..World::default()
+        };
+        let mut shape = plane!();
+        shape.set_material(MaterialBuilder::default().reflective(0.5).build().unwrap());
+        shape.set_transform(Matrix::translation(0, -1, 0));
+        w.add_object(shape);
+
+        let r = ray!(
+            point!(0, 0, -3),
+            vector!(0, -2f64.sqrt() / 2., 2f64.sqrt() / 2.)
+        );
+        let i = Intersection::new(2f64.sqrt(), w.last_object().unwrap());
+        let comps = i.prepare_computations(&w.arena, &r, &[&i]);
+        w.reflected_color(&comps, w.camera_trace())
+    }
+
+    #[test]
+    fn reflections_below_the_contribution_threshold_are_skipped() {
+        let limits = |min_contribution| TraceLimits {
+            min_contribution,
+            ..TraceLimits::default()
+        };
+        assert_eq!(
+            color!(0.19033, 0.23791, 0.14274),
+            half_mirror_reflection(limits(0.4))
+        );
+        assert_eq!(BLACK, half_mirror_reflection(limits(0.6)));
+    }
+
+    #[test]
+    fn russian_roulette_keeps_the_expected_color() {
+        let limits = TraceLimits {
+            russian_roulette_depth: Some(0),
+            ..TraceLimits::default()
+        };
+        // the unscaled color seen in the mirror
+        let full = color!(0.38066, 0.47583, 0.2855);
+        let mut survived = 0;
+        for _ in 0..200 {
+            let c = half_mirror_reflection(limits);
+            if c == BLACK {
+                continue;
+            }
+            // survivors are weighted by 1 / contribution
+            assert_eq!(full, c);
+            survived += 1;
+        }
+        assert!(survived > 50 && survived < 150);
+    }
+
     #[test]
     fn shade_hit_reflective_material() {
         let mut w = World::default();
@@ -341,10 +1210,233 @@ mod tests {
         );
         let i = Intersection::new(2f64.sqrt(), &w.last_object().unwrap());
         let comps = i.prepare_computations(&w.arena, &r, &[&i]);
-        let c = w.shade_hit(&comps, MAX_REFLECTION_RECURSION);
+        let c = w.shade_hit(&comps, w.camera_trace());
         assert_eq!(color!(0.87676, 0.92434, 0.82917), c);
     }
 
+    #[test]
+    fn missed_rays_see_the_background() {
+        let mut w = World::default();
+        let r = ray!(point!(0, 0, -5), vector!(0, 1, 0));
+        assert_eq!(BLACK, w.color_at(&r));
+        w.background = Background::Gradient {
+            bottom: BLACK,
+            top: WHITE,
+        };
+        assert_eq!(WHITE, w.color_at(&r));
+    }
+
+    fn background_floor_world(floor_material: Material) -> World {
+        let mut w = World::new(PointLight::new(point!(0, 10, 0), WHITE), vec![]);
+        let mut floor = plane!();
+        floor.set_material(floor_material);
+        w.add_object(floor);
+        w.background = Background::Solid(color!(0.2, 0.4, 0.6));
+        w
+    }
+
+    #[test]
+    fn reflections_see_the_background() {
+        let w = background_floor_world(Material {
+            ambient: 0.,
+            diffuse: 0.,
+            specular: 0.,
+            reflective: 1.,
+            ..Material::default()
+        });
+        let r = ray!(point!(0, 1, -1), vector!(0, -1, 1).normalize());
+        assert_eq!(color!(0.2, 0.4, 0.6), w.color_at(&r));
+    }
+
+    #[test]
+    fn background_contributes_ambient_light() {
+        let mut w = background_floor_world(Material {
+            ambient: 0.5,
+            diffuse: 0.,
+            specular: 0.,
+            ..Material::default()
+        });
+        let r = ray!(point!(0, 1, 0), vector!(0, -1, 0));
+        assert_eq!(color!(0.5, 0.5, 0.5), w.color_at(&r));
+        w.background_ambient = 1.;
+        assert_eq!(color!(0.6, 0.7, 0.8), w.color_at(&r));
+    }
+
+    #[test]
+    fn ambient_occlusion_scales_the_ambient_term() {
+        let mut w = World::new(PointLight::new(point!(0, 10, 0), WHITE), vec![]);
+        let mut floor = plane!();
+        floor.set_material(Material {
+            ambient: 1.,
+            diffuse: 0.,
+            specular: 0.,
+            ..Material::default()
+        });
+        w.add_object(floor);
+        let mut ceiling = plane!();
+        ceiling.set_transform(Matrix::translation(0, 0.5, 0));
+        w.add_object(ceiling);
+
+        let r = ray!(point!(0, 0.25, 0), vector!(0, -1, 0));
+        assert_eq!(WHITE, w.color_at(&r));
+        w.ambient_occlusion = Some(AmbientOcclusion {
+            radius: 1000.,
+            samples: 16,
+        });
+        assert_eq!(BLACK, w.color_at(&r));
+        w.ambient_occlusion = Some(AmbientOcclusion {
+            radius: 0.25,
+            samples: 4,
+        });
+        assert_eq!(WHITE, w.color_at(&r));
+    }
+
+    #[test]
+    fn bumped_surfaces_dont_occlude_themselves() {
+        let mut w = World::new(PointLight::new(point!(0, 10, 0), WHITE), vec![]);
+        let mut ramp = gradient_pattern!(BLACK, WHITE);
+        ramp.set_transform(Matrix::scaling(0.1, 1, 1));
+        let mut floor = plane!();
+        floor.set_material(Material {
+            ambient: 1.,
+            diffuse: 0.,
+            specular: 0.,
+            bump: Some(ramp),
+            ..Material::default()
+        });
+        w.add_object(floor);
+        w.ambient_occlusion = Some(AmbientOcclusion {
+            radius: 1000.,
+            samples: 64,
+        });
+        assert_eq!(
+            WHITE,
+            w.color_at(&ray!(point!(0.05, 1, 0), vector!(0, -1, 0)))
+        );
+    }
+
+    #[test]
+    fn fog_blends_toward_its_color_with_distance() {
+        let mut w = World::default();
+        let fog_color = color!(0.5, 0.5, 0.5);
+        w.fog = Some(Fog::Exponential {
+            color: fog_color,
+            density: 0.1,
+        });
+        let miss = ray!(point!(0, 0, -5), vector!(0, 1, 0));
+        assert_eq!(fog_color, w.color_at(&miss));
+
+        let r = ray!(point!(0, 0, -5), vector!(0, 0, 1));
+        let transmittance = (-0.4f64).exp();
+        let expected =
+            color!(0.38066, 0.47583, 0.2855) * transmittance + fog_color * (1. - transmittance);
+        assert_eq!(expected, w.color_at(&r));
+    }
+
+    #[test]
+    fn volumes_are_invisible_but_cast_soft_shadows() {
+        let mut w = World::new(PointLight::new(point!(0, 10, 0), WHITE), vec![plane!()]);
+        let volume = Volume {
+            absorption: 0.2,
+            scattering: 0.3,
+            step: 0.25,
+        };
+        let mut smoke = cube!();
+        smoke.set_transform(Matrix::translation(0, 3, 0));
+        smoke.set_material(Material {
+            volume: Some(volume),
+            ..Material::default()
+        });
+        w.add_object(smoke);
+
+        assert!(approx_eq(
+            volume.transmittance(2.),
+            w.shadow_transmittance(point!(0, 0.001, 0)).r
+        ));
+        // the floor is seen through the smoke, dimmed by it
+        let r = ray!(point!(0, 5, 0), vector!(0, -1, 0));
+        let through = volume.transmittance(2.);
+        // ambient, then the diffuse and specular terms in the smoke's shadow
+        let floor = 0.1 + (0.9 + 0.9) * through;
+        // light scattered toward the eye at each step, lit through the smoke above it
+        // (plus its ambient) and dimmed by the smoke in front of it
+        let dt = 0.25;
+        let scattered = (1. - volume.transmittance(dt)) * 0.3 / volume.extinction();
+        let in_scattered: f64 = (0..8)
+            .map(|k| {
+                let lit = volume.transmittance((k as f64 + 0.5) * dt) + 0.1;
+                lit * scattered * volume.transmittance(k as f64 * dt)
+            })
+            .sum();
+        let seen = w.color_at(&r);
+        assert!((seen.r - (floor * through + in_scattered)).abs() < 1e-4);
+
+        // picking and visibility tests go through it too
+        assert_eq!(w.object_ids[0], w.closest_hit(&r).unwrap().object_id);
+        assert!(w.is_visible(point!(0, 5, 0), point!(0, 0.001, 0)));
+    }
+
+    fn coated_floor_world(base: Material) -> World {
+        let mut w = World::new(PointLight::new(point!(10, 1, 0), WHITE), vec![]);
+        let mut floor = plane!();
+        floor.set_material(Material {
+            clearcoat: Some(Clearcoat::default()),
+            ..base
+        });
+        w.add_object(floor);
+        w.background = Background::Solid(WHITE);
+        w
+    }
+
+    #[test]
+    fn clearcoat_reflects_over_the_base() {
+        let black = Material {
+            ambient: 0.,
+            diffuse: 0.,
+            specular: 0.,
+            ..Material::default()
+        };
+        let r = ray!(point!(0, 1, 0), vector!(0, -1, 0));
+        let w = coated_floor_world(black);
+        assert_eq!(color!(0.04, 0.04, 0.04), w.color_at(&r));
+
+        // the base is dimmed by what the coat reflects
+        let w = coated_floor_world(Material {
+            ambient: 1.,
+            ..black
+        });
+        assert_eq!(color!(1, 1, 1), w.color_at(&r));
+        let w = coated_floor_world(Material {
+            ambient: 0.5,
+            ..black
+        });
+        assert_eq!(color!(0.52, 0.52, 0.52), w.color_at(&r));
+    }
+
+    #[test]
+    fn clearcoat_mirrors_the_spot_lights() {
+        let black = Material {
+            ambient: 0.,
+            diffuse: 0.,
+            specular: 0.,
+            ..Material::default()
+        };
+        let mut w = coated_floor_world(black);
+        w.light = PointLight::new(point!(10, 1, 0), BLACK);
+        w.background = Background::Solid(BLACK);
+        w.spot_lights.push(SpotLight::new(
+            point!(1, 1, 0),
+            vector!(-1, -1, 0),
+            WHITE,
+            PI / 8.,
+            PI / 4.,
+        ));
+        // looking at the floor at the mirror angle of the spot light
+        let r = ray!(point!(-1, 1, 0), vector!(1, -1, 0).normalize());
+        let c = w.color_at(&r);
+        assert!(c.r > 0.04 && c.r < 1.);
+    }
+
     #[test]
     fn color_at_with_mutually_reflective_surfaces_doesnt_cause_infinite_recursion() {
         let light = PointLight::new(point!(0, 0, 0), WHITE);
@@ -362,6 +1454,7 @@ mod tests {
     #[test]
     fn reflected_color_at_max_recursive_depth() {
         let mut w = World::default();
+        w.limits.max_reflection_depth = 0;
         {
             let mut shape = plane!();
             shape.set_material(MaterialBuilder::default().reflective(0.5).build().unwrap());
@@ -375,10 +1468,25 @@ mod tests {
         );
         let i = Intersection::new(2f64.sqrt(), &w.last_object().unwrap());
         let comps = i.prepare_computations(&w.arena, &r, &[&i]);
-        let color = w.reflected_color(&comps, 0);
+        let color = w.reflected_color(&comps, w.camera_trace());
         assert_eq!(BLACK, color);
     }
 
+    #[test]
+    fn reflections_and_refractions_share_the_default_depth() {
+        let mut ball = sphere!();
+        ball.set_material(Material {
+            reflective: 0.9,
+            transparency: 0.9,
+            refractive_index: 1.5,
+            ..Material::default()
+        });
+        let w = World::new(PointLight::new(point!(-10, 10, -10), WHITE), vec![ball]);
+        stats::take();
+        w.color_at(&ray!(point!(0, 0, -5), vector!(0, 0, 1)));
+        assert_eq!(5, stats::take().max_depth);
+    }
+
     #[test]
     fn find_refracted_color_opaque_object() {
         let w = World::default();
@@ -387,13 +1495,14 @@ mod tests {
         let i1 = Intersection::new(4, s);
         let i2 = Intersection::new(6, s);
         let comps = i1.prepare_computations(&w.arena, &r, &[&i1, &i2]);
-        let c = w.refracted_color(&comps, MAX_REFLECTION_RECURSION);
+        let c = w.refracted_color(&comps, w.camera_trace());
         assert_eq!(BLACK, c);
     }
 
     #[test]
     fn refracted_color_max_recursion() {
         let mut w = World::default();
+        w.limits.max_refraction_depth = 0;
         w.apply_changes_by_index(0, |shape| {
             let material = Material {
                 transparency: 1.,
@@ -407,7 +1516,7 @@ mod tests {
         let i1 = Intersection::new(4, s);
         let i2 = Intersection::new(6, s);
         let comps = i1.prepare_computations(&w.arena, &r, &[&i1, &i2]);
-        let c = w.refracted_color(&comps, 0);
+        let c = w.refracted_color(&comps, w.camera_trace());
         assert_eq!(BLACK, c);
     }
 
@@ -429,7 +1538,7 @@ mod tests {
         // NOTE: this time you're inside the sphere, so you need​
         // to look at the second intersection
         let comps = i2.prepare_computations(&w.arena, &r, &[&i1, &i2]);
-        let c = w.refracted_color(&comps, MAX_REFLECTION_RECURSION);
+        let c = w.refracted_color(&comps, w.camera_trace());
         assert_eq!(BLACK, c);
     }
 
@@ -460,7 +1569,7 @@ mod tests {
         let i3 = Intersection::new(0.4899, &w.object_by_index(1));
         let i4 = Intersection::new(0.9899, &w.object_by_index(0));
         let comps = i3.prepare_computations(&w.arena, &r, &[&i1, &i2, &i3, &i4]);
-        let c = w.refracted_color(&comps, MAX_REFLECTION_RECURSION);
+        let c = w.refracted_color(&comps, w.camera_trace());
         assert_eq!(color!(0, 0.99887, 0.04722), c);
     }
 
@@ -474,6 +1583,8 @@ mod tests {
             MaterialBuilder::default()
                 .transparency(0.5)
                 .refractive_index(1.5)
+                // the expected color assumes the floor blocks the light
+                .shadow(ShadowMode::Hard)
                 .build()
                 .unwrap(),
         );
@@ -499,10 +1610,237 @@ mod tests {
             &w.object_by_index(w.object_ids.len() - 2), /* floor */
         );
         let comps = i.prepare_computations(&w.arena, &r, &[&i]);
-        let c = w.shade_hit(&comps, MAX_REFLECTION_RECURSION);
+        let c = w.shade_hit(&comps, w.camera_trace());
         assert_eq!(color!(0.93642, 0.68642, 0.68642), c);
     }
 
+    fn absorbing_glass_world(radius: f64) -> World {
+        let mut w = World::new(PointLight::new(point!(-10, 10, -10), WHITE), vec![]);
+        let mut glass = sphere!();
+        glass.set_transform(Matrix::scaling(radius, radius, radius));
+        glass.set_material(
+            MaterialBuilder::default()
+                .ambient(0)
+                .diffuse(0)
+                .specular(0)
+                .transparency(1)
+                .absorption(color!(1, 0.5, 0.5))
+                .density(0.5)
+                .build()
+                .unwrap(),
+        );
+        w.add_object(glass);
+        let mut wall = plane!();
+        wall.set_transform(Matrix::translation(0, 0, 10) * Matrix::rotation_x(PI / 2.));
+        wall.set_material(
+            MaterialBuilder::default()
+                .ambient(1)
+                .diffuse(0)
+                .specular(0)
+                .build()
+                .unwrap(),
+        );
+        w.add_object(wall);
+        w
+    }
+
+    #[test]
+    fn thick_glass_absorbs_more_than_thin_glass() {
+        let r = ray!(point!(0, 0, -5), vector!(0, 0, 1));
+        assert_eq!(color!(1, 0.5, 0.5), absorbing_glass_world(1.).color_at(&r));
+        assert_eq!(
+            color!(1, 0.25, 0.25),
+            absorbing_glass_world(2.).color_at(&r)
+        );
+    }
+
+    fn neon_world() -> World {
+        // the main light doesn't contribute
+        let mut w = World::new(PointLight::new(point!(0, 10, 0), BLACK), vec![]);
+        let mut floor = plane!();
+        floor.set_material(
+            MaterialBuilder::default()
+                .ambient(0)
+                .specular(0)
+                .build()
+                .unwrap(),
+        );
+        w.add_object(floor);
+        let mut neon = sphere!();
+        neon.set_transform(Matrix::translation(0, 3, 0) * Matrix::scaling(0.5, 0.5, 0.5));
+        neon.set_material(
+            MaterialBuilder::default()
+                .ambient(0)
+                .diffuse(0)
+                .specular(0)
+                .emission(RED)
+                .emission_strength(2.)
+                .build()
+                .unwrap(),
+        );
+        w.add_object(neon);
+        w
+    }
+
+    #[test]
+    fn emissive_surfaces_are_visible_without_lights() {
+        let w = neon_world();
+        let r = ray!(point!(0, 3, -5), vector!(0, 0, 1));
+        assert_eq!(color!(2, 0, 0), w.color_at(&r));
+    }
+
+    #[test]
+    fn emissive_shapes_light_other_objects() {
+        let mut w = neon_world();
+        w.emissive_samples = 256;
+        let r = ray!(point!(0, 1, -1), vector!(0, -1, 1).normalize());
+        let lit = w.color_at(&r);
+        // a sphere of radiance L and radius R at distance d lights the point below it
+        // as L * (R / d)^2, times the diffuse reflectance
+        let expected = 0.9 * 2. * (0.5f64 / 3.).powi(2);
+        assert!((lit.r - expected).abs() < expected * 0.05);
+        assert_eq!(0., lit.g);
+
+        // a larger lamp gives more light
+        let id = *w.object_ids.last().unwrap();
+        w.arena
+            .apply_changes(id, |s| s.set_transform(Matrix::translation(0, 3, 0)));
+        assert!(w.color_at(&r).r > lit.r * 3.);
+
+        // same shadow test as the main light
+        let mut blocker = cube!();
+        blocker.set_transform(Matrix::translation(0, 1.5, 1) * Matrix::scaling(3, 0.1, 3));
+        w.add_object(blocker);
+        assert_eq!(BLACK, w.color_at(&r));
+    }
+
+    #[test]
+    fn emitters_follow_material_changes() {
+        let mut w = neon_world();
+        let r = ray!(point!(0, 1, -1), vector!(0, -1, 1).normalize());
+        assert!(w.color_at(&r).r > 0.);
+        w.apply_changes_by_index(1, |s| {
+            let material = Material {
+                emission_strength: 0.,
+                ..*s.material()
+            };
+            s.set_material(material)
+        });
+        assert_eq!(BLACK, w.color_at(&r));
+    }
+
+    #[test]
+    fn sky_lights_the_scene_with_its_sun() {
+        let mut w = background_floor_world(Material {
+            ambient: 0.,
+            specular: 0.,
+            ..Material::default()
+        });
+        w.light.intensity = BLACK;
+        let sky = Sky::new(PI / 4., 0., 3.);
+        w.background = Background::Sky(sky);
+        let miss = ray!(point!(0, 1, 0), vector!(0, 1, 0));
+        assert_eq!(sky.color_in(vector!(0, 1, 0)), w.color_at(&miss));
+
+        let down = ray!(point!(0, 1, 0), vector!(0, -1, 0));
+        let expected = sky.sun_color() * 0.9 * (PI / 4.).cos();
+        assert_eq!(expected, w.color_at(&down));
+
+        // same shadow test as the main light, toward the sun
+        let mut blocker = cube!();
+        blocker.set_transform(Matrix::translation(0, 3, 3) * Matrix::scaling(2, 0.1, 2));
+        w.add_object(blocker);
+        assert_eq!(
+            BLACK,
+            w.color_at(&ray!(point!(0, 0.5, 0), vector!(0, -1, 0)))
+        );
+
+        // nothing once the sun has set
+        w.background = Background::Sky(Sky::new(-0.1, 0., 3.));
+        assert_eq!(BLACK, w.color_at(&down));
+    }
+
+    #[test]
+    fn spot_lights_light_their_cone() {
+        let mut w = background_floor_world(Material {
+            ambient: 0.,
+            specular: 0.,
+            ..Material::default()
+        });
+        w.light.intensity = BLACK;
+        w.spot_lights.push(SpotLight::new(
+            point!(0, 2, 0),
+            vector!(0, -1, 0),
+            WHITE,
+            PI / 8.,
+            PI / 6.,
+        ));
+        let down = |x: f64| ray!(point!(x, 1, 0), vector!(0, -1, 0));
+        assert!(approx_eq(0.9, w.color_at(&down(0.)).r));
+        assert_eq!(BLACK, w.color_at(&down(2.)));
+
+        // same shadow test as the main light
+        let mut blocker = sphere!();
+        blocker.set_transform(Matrix::translation(0, 1.5, 0) * Matrix::scaling(0.1, 0.1, 0.1));
+        w.add_object(blocker);
+        assert_eq!(
+            BLACK,
+            w.color_at(&ray!(point!(0.01, 0.5, 0), vector!(0, -1, 0)))
+        );
+    }
+
+    fn prism_world(backdrop: Pattern) -> World {
+        let mut w = World::new(PointLight::new(point!(-10, 10, -10), WHITE), vec![]);
+        let mut prism = sphere!();
+        prism.set_material(
+            MaterialBuilder::default()
+                .ambient(0)
+                .diffuse(0)
+                .specular(0)
+                .transparency(1)
+                .refractive_index(1.7)
+                .abbe_number(5.)
+                .build()
+                .unwrap(),
+        );
+        w.add_object(prism);
+        let mut wall = plane!();
+        wall.set_transform(Matrix::translation(0, 0, 3) * Matrix::rotation_x(PI / 2.));
+        wall.set_material(
+            MaterialBuilder::default()
+                .pattern(backdrop)
+                .ambient(1)
+                .diffuse(0)
+                .specular(0)
+                .build()
+                .unwrap(),
+        );
+        w.add_object(wall);
+        w
+    }
+
+    #[test]
+    fn dispersion_keeps_white_light_white() {
+        let w = prism_world(solid!(WHITE));
+        let r = ray!(point!(0.25, 0, -5), vector!(0, 0, 1));
+        assert_eq!(WHITE, w.color_at(&r));
+    }
+
+    #[test]
+    fn dispersion_separates_colors() {
+        // off center, wavelengths are bent differently and land on different stripes
+        let w = prism_world(stripe_pattern!(WHITE, BLACK));
+        let mut fringes = 0;
+        for i in 0..20 {
+            let x = 0.3 + i as f64 * 0.03;
+            let c = w.color_at(&ray!(point!(x, 0, -5), vector!(0, 0, 1)));
+            if c.r != c.b || c.g != c.b {
+                fringes += 1;
+            }
+        }
+        assert!(fringes > 0);
+    }
+
     #[test]
     fn shade_hit_with_reflective_transparent_material() {
         let mut w = World::default();
@@ -514,6 +1852,8 @@ mod tests {
                 .reflective(0.5)
                 .transparency(0.5)
                 .refractive_index(1.5)
+                // the expected color assumes the floor blocks the light
+                .shadow(ShadowMode::Hard)
                 .build()
                 .unwrap(),
         );
@@ -539,7 +1879,7 @@ mod tests {
             &w.object_by_index(w.object_ids.len() - 2), /* floor */
         );
         let comps = i.prepare_computations(&w.arena, &r, &[&i]);
-        let c = w.shade_hit(&comps, MAX_REFLECTION_RECURSION);
+        let c = w.shade_hit(&comps, w.camera_trace());
         assert_eq!(color!(0.93391, 0.69643, 0.69243), c);
     }
 }
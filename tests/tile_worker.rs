@@ -0,0 +1,29 @@
+use std::{f64::consts::PI, process::Command};
+
+use rust_tracer::{
+    camera::Camera,
+    distributed::{Connection, Coordinator},
+    point, vector,
+    world::World,
+};
+
+// the worker binary, served over its stdin/stdout
+#[test]
+fn render_with_spawned_workers() {
+    let world = World::default();
+    let mut camera = Camera::new(11, 9, PI / 2.);
+    camera.look_at(point!(0, 0, -5), point!(0, 0, 0), vector!(0, 1, 0));
+
+    let mut coordinator = Coordinator::new(4);
+    for _ in 0..2 {
+        coordinator
+            .add_worker(|| Connection::spawn(&mut Command::new(env!("CARGO_BIN_EXE_tile_worker"))));
+    }
+    let image = coordinator.render(&world, &camera, false).unwrap();
+    let expected = camera.render(&world, false);
+    for y in 0..expected.height {
+        for x in 0..expected.width {
+            assert_eq!(expected.pixel_at(x, y), image.pixel_at(x, y));
+        }
+    }
+}